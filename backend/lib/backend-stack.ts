@@ -20,6 +20,17 @@ export class AutoPosterStack extends Stack {
   constructor(app: App, id: string) {
     super(app, id);
     const openAiApiKey = process.env.OPEN_AI_API_KEY || 'NO_API_KEY';
+    const llmBaseUrl = process.env.LLM_BASE_URL || 'https://api.openai.com/v1';
+    // Has to support structured outputs unless LLM_RESPONSE_FORMAT says otherwise
+    const llmModel = process.env.LLM_MODEL || 'gpt-4o-2024-08-06';
+    const quoteCheck = process.env.QUOTE_CHECK || 'drop';
+    const moderationClassifier = process.env.MODERATION_CLASSIFIER || 'off';
+    const imageGeneration = process.env.IMAGE_GENERATION || 'off';
+    const articleLinkMode = process.env.ARTICLE_LINK_MODE || 'off';
+    const utmTagging = process.env.UTM_TAGGING || 'on';
+    const utmCampaign = process.env.UTM_CAMPAIGN || 'autoposter';
+    const shortener = process.env.SHORTENER || 'off';
+    const shortenerUrl = process.env.SHORTENER_URL || 'https://is.gd/create.php?format=simple';
     const desoUser = process.env.DESO_USER || "No Deso User";
     const desoPrivateKey = process.env.DESO_PRIVATE_KEY || "No Deso Private Key";
     const consumerKey = process.env.CONSUMER_KEY || 'NO Twitter Consumer Key';
@@ -27,6 +38,20 @@ export class AutoPosterStack extends Stack {
     const accessToken = process.env.ACCESS_TOKEN || 'NO Twitter Access Key';
     const accessTokenSecret = process.env.ACCESS_TOKEN_SECRET || 'NO Twitter Access Key Secret';
     const scheduledPosts = "ScheduledPosts";
+    const postHistory = "PostHistory";
+    const generationBatches = "GenerationBatches";
+    const idempotencyKeys = "IdempotencyKeys";
+    const apiKeys = "ApiKeys";
+    // Bearer tokens are checked against this JWKS when it's set, API keys always work
+    const jwksUrl = process.env.JWKS_URL || '';
+    const jwtIssuer = process.env.JWT_ISSUER || '';
+    const jwtAudience = process.env.JWT_AUDIENCE || '';
+    // Comma separated origins the webapp is served from. There's no default,
+    // browsers only get CORS headers for origins listed here ('*' allows any)
+    const corsAllowedOrigins = (process.env.CORS_ALLOWED_ORIGINS || '').trim();
+    if (!corsAllowedOrigins) {
+      throw new Error('CORS_ALLOWED_ORIGINS must list the origins the webapp is served from');
+    }
 
     // Setup our dynamo db table
     const dynamoTable = new Table(this, 'Posts', {
@@ -46,6 +71,21 @@ export class AutoPosterStack extends Stack {
       removalPolicy: RemovalPolicy.RETAIN, // NOT recommended for production code
     });
 
+    // getPosts reads one status at a time in created_at order
+    dynamoTable.addGlobalSecondaryIndex({
+      indexName: 'status-created_at',
+      partitionKey: {
+        name: 'status',
+        type: AttributeType.STRING
+      },
+      sortKey: {
+        name: 'created_at',
+        type: AttributeType.NUMBER
+      },
+      readCapacity: 1,
+      writeCapacity: 1,
+    });
+
     const scheduledTable = new Table(this, 'ScheduledPosts', {
       partitionKey: {
         name: 'uuid',
@@ -57,6 +97,73 @@ export class AutoPosterStack extends Stack {
       removalPolicy: RemovalPolicy.RETAIN, // NOT recommended for production code
     });
 
+    // Every published post, used to catch new posts that repeat recent ones
+    const historyTable = new Table(this, 'PostHistory', {
+      partitionKey: {
+        name: 'uuid',
+        type: AttributeType.STRING
+      },
+      readCapacity: 1,
+      writeCapacity: 1,
+      tableName: postHistory,
+      // Published posts are only compared for HISTORY_DAYS, sendPosts sets expires_at to match
+      timeToLiveAttribute: 'expires_at',
+      removalPolicy: RemovalPolicy.RETAIN, // NOT recommended for production code
+    });
+
+    // Images generated for posts, keyed images/<uuid>.png
+    const imageBucket = new s3.Bucket(this, 'PostImages', {
+      blockPublicAccess: BlockPublicAccess.BLOCK_ALL,
+      removalPolicy: RemovalPolicy.RETAIN,
+    });
+
+    // One row per generatePosts run: the source article, prompt version and posts it added
+    const batchTable = new Table(this, 'GenerationBatches', {
+      partitionKey: {
+        name: 'uuid',
+        type: AttributeType.STRING
+      },
+      readCapacity: 1,
+      writeCapacity: 1,
+      tableName: generationBatches,
+      removalPolicy: RemovalPolicy.RETAIN, // NOT recommended for production code
+    });
+
+    // Idempotency-Key headers sent to /add and /addSchedule, with the response to replay
+    const idempotencyTable = new Table(this, 'IdempotencyKeys', {
+      partitionKey: {
+        name: 'key',
+        type: AttributeType.STRING
+      },
+      readCapacity: 1,
+      writeCapacity: 1,
+      tableName: idempotencyKeys,
+      timeToLiveAttribute: 'expires_at',
+      removalPolicy: RemovalPolicy.RETAIN, // NOT recommended for production code
+    });
+
+    // API keys by the SHA-256 of the key, with a name and the scopes they grant
+    const apiKeysTable = new Table(this, 'ApiKeys', {
+      partitionKey: {
+        name: 'key_hash',
+        type: AttributeType.STRING
+      },
+      readCapacity: 1,
+      writeCapacity: 1,
+      tableName: apiKeys,
+      removalPolicy: RemovalPolicy.RETAIN, // NOT recommended for production code
+    });
+
+    // Every API lambda checks callers the same way
+    const authEnvironment = {
+      API_KEYS_TABLE_NAME: apiKeys,
+      JWKS_URL: jwksUrl,
+      JWT_ISSUER: jwtIssuer,
+      JWT_AUDIENCE: jwtAudience,
+      CORS_ALLOWED_ORIGINS: corsAllowedOrigins
+    };
+    const allowOrigins = corsAllowedOrigins === '*' ? Cors.ALL_ORIGINS : corsAllowedOrigins.split(',').map(o => o.trim());
+
     // Create an IAM role for the Lambda function
     const lambdaRole = new iam.Role(this, 'LambdaRole', {
       assumedBy: new iam.ServicePrincipal('lambda.amazonaws.com'),
@@ -99,15 +206,15 @@ export class AutoPosterStack extends Stack {
     const api = new RestApi(this, 'PostAPI', {
       restApiName: 'Post API',
       defaultCorsPreflightOptions: {
-        allowOrigins: Cors.ALL_ORIGINS,
+        allowOrigins: allowOrigins,
         allowMethods: Cors.ALL_METHODS,
-        allowHeaders: Cors.DEFAULT_HEADERS,
+        allowHeaders: [...Cors.DEFAULT_HEADERS, 'Idempotency-Key'],
       }
     });
     const generateAPI = new RestApi(this, 'GenerateAPI', {
       restApiName: 'Generate API',
       defaultCorsPreflightOptions: {
-        allowOrigins: Cors.ALL_ORIGINS,
+        allowOrigins: allowOrigins,
         allowMethods: Cors.ALL_METHODS,
         allowHeaders: Cors.DEFAULT_HEADERS,
       }
@@ -123,7 +230,12 @@ export class AutoPosterStack extends Stack {
         RUST_BACKTRACE: '1',
         TABLE_NAME: 'Posts',
         SCHEDULED_TABLE_NAME: scheduledPosts,
-        SNS_ARN: postTopic.topicArn
+        HISTORY_TABLE_NAME: postHistory,
+        SNS_ARN: postTopic.topicArn,
+        MODERATION_CLASSIFIER: moderationClassifier,
+        OPEN_AI_API_KEY: openAiApiKey,
+        LLM_BASE_URL: llmBaseUrl,
+        LLM_MODEL: llmModel
       },
       logRetention: RetentionDays.ONE_WEEK,
       role: lambdaRole
@@ -132,90 +244,83 @@ export class AutoPosterStack extends Stack {
     postTopic.grantPublish(sendPosts);
     dynamoTable.grantReadWriteData(sendPosts);
     scheduledTable.grantReadWriteData(sendPosts);
+    historyTable.grantWriteData(sendPosts);
     const postEvent = new Rule(this, 'postEvent', {
       schedule: Schedule.expression('rate(1 hour)'),
     });
     postEvent.addTarget(new LambdaFunction(sendPosts));
 
-    // Get Posts
-    const getPosts = new Function(this, 'getPosts', {
-      description: "Get all posts in DB",
-      code: Code.fromAsset('lib/lambdas/getPosts/target/x86_64-unknown-linux-musl/release/lambda'),
-      runtime: Runtime.PROVIDED_AL2,
-      handler: 'not.required',
-      environment: {
-        RUST_BACKTRACE: '1',
-        TABLE_NAME: 'Posts',
-      },
-      logRetention: RetentionDays.ONE_WEEK,
-      role: lambdaRole
-    });
-    dynamoTable.grantReadWriteData(getPosts);
-
-    // Edit Posts
-    const editPost = new Function(this, 'editPost', {
-      description: "Edit posts to the DB",
-      code: Code.fromAsset('lib/lambdas/editPost/target/x86_64-unknown-linux-musl/release/lambda'),
-      runtime: Runtime.PROVIDED_AL2,
-      handler: 'not.required',
-      environment: {
-        RUST_BACKTRACE: '1',
-        TABLE_NAME: 'Posts'
-      },
-      logRetention: RetentionDays.ONE_WEEK,
-      role: lambdaRole
-    });
-    dynamoTable.grantWriteData(editPost);
-
-    // 1 Lambda function API
-    const addPost = new Function(this, 'addPost', {
-      description: "Add new posts to the DB",
-      code: Code.fromAsset('lib/lambdas/addPost/target/x86_64-unknown-linux-musl/release/lambda'),
+    // Every endpoint of both APIs, routed by method and path
+    const apiFunction = new Function(this, 'api', {
+      description: "Adds, lists, edits, reviews and deletes posts and scheduled posts, and runs generation",
+      code: Code.fromAsset('lib/lambdas/api/target/x86_64-unknown-linux-musl/release/lambda'),
       runtime: Runtime.PROVIDED_AL2,
       handler: 'not.required',
       timeout: Duration.minutes(5),
       environment: {
         RUST_BACKTRACE: '1',
-        TABLE_NAME: 'Posts'
-      },
-      logRetention: RetentionDays.ONE_WEEK,
-      role: lambdaRole
-    });
-
-    dynamoTable.grantWriteData(addPost);
-
-    const addScheduledPost = new Function(this, 'addScheduledPost', {
-      description: "Add new scheduled posts to the DB",
-      code: Code.fromAsset('lib/lambdas/addScheduledPost/target/x86_64-unknown-linux-musl/release/lambda'),
-      runtime: Runtime.PROVIDED_AL2,
-      handler: 'not.required',
-      environment: {
-        RUST_BACKTRACE: '1',
-        TABLE_NAME: scheduledPosts
+        ...authEnvironment,
+        TABLE_NAME: 'Posts',
+        SCHEDULED_TABLE_NAME: scheduledPosts,
+        HISTORY_TABLE_NAME: postHistory,
+        BATCH_TABLE_NAME: generationBatches,
+        IDEMPOTENCY_TABLE_NAME: idempotencyKeys,
+        DUPLICATE_CHECK: 'drop',
+        MODERATION_CLASSIFIER: moderationClassifier,
+        OPEN_AI_API_KEY: openAiApiKey,
+        LLM_BASE_URL: llmBaseUrl,
+        LLM_MODEL: llmModel,
+        QUOTE_CHECK: quoteCheck,
+        IMAGE_GENERATION: imageGeneration,
+        IMAGE_BUCKET: imageBucket.bucketName
       },
       logRetention: RetentionDays.ONE_WEEK,
       role: lambdaRole
     });
 
-    scheduledTable.grantWriteData(addScheduledPost);
+    apiKeysTable.grantReadData(apiFunction);
+    dynamoTable.grantReadWriteData(apiFunction);
+    scheduledTable.grantReadWriteData(apiFunction);
+    historyTable.grantReadData(apiFunction);
+    batchTable.grantWriteData(apiFunction);
+    idempotencyTable.grantReadWriteData(apiFunction);
+    imageBucket.grantPut(apiFunction);
 
     // Integrate lambda functions with an API gateway
-    const addPostAPI = new LambdaIntegration(addPost);
-    const addScheduledPostAPI = new LambdaIntegration(addScheduledPost);
-    const getPostsAPI = new LambdaIntegration(getPosts);
-    const editPostAPI = new LambdaIntegration(editPost);
+    // The paths stay as they were, they all go to the one lambda
+    const apiIntegration = new LambdaIntegration(apiFunction);
 
     const add = api.root.addResource('add');
-    add.addMethod('POST', addPostAPI);
+    add.addMethod('POST', apiIntegration);
 
     const addSchedule = api.root.addResource('addSchedule');
-    addSchedule.addMethod('POST', addScheduledPostAPI);
+    addSchedule.addMethod('POST', apiIntegration);
+
+    const editSchedule = api.root.addResource('editSchedule');
+    editSchedule.addMethod('POST', apiIntegration);
 
     const get = api.root.addResource('getPosts');
-    get.addMethod('GET', getPostsAPI);
+    get.addMethod('GET', apiIntegration);
+
+    const getScheduled = api.root.addResource('getScheduledPosts');
+    getScheduled.addMethod('GET', apiIntegration);
+
+    const deletePostsResource = api.root.addResource('deletePosts');
+    deletePostsResource.addMethod('DELETE', apiIntegration);
+    deletePostsResource.addResource('{uuid}').addMethod('DELETE', apiIntegration);
+
+    const deleteScheduledResource = api.root.addResource('deleteScheduledPosts');
+    deleteScheduledResource.addMethod('DELETE', apiIntegration);
+    deleteScheduledResource.addResource('{uuid}').addMethod('DELETE', apiIntegration);
 
     const edit = api.root.addResource('editPosts');
-    edit.addMethod('POST', editPostAPI);
+    edit.addMethod('POST', apiIntegration);
+
+    const approve = api.root.addResource('approvePosts');
+    approve.addMethod('POST', apiIntegration);
+
+    const reject = api.root.addResource('rejectPosts');
+    reject.addMethod('POST', apiIntegration);
 
     lambdaRole.addToPolicy(new iam.PolicyStatement({
       actions: ['execute-api:Invoke'],
@@ -223,7 +328,7 @@ export class AutoPosterStack extends Stack {
     }));
     // Event triggered Lambdas: generatePosts and sendPosts
     const generatePosts = new Function(this, 'generatePosts', {
-      description: "Generates new posts and calls addPost API",
+      description: "Generates new posts and adds them to the DB as drafts",
       code: Code.fromAsset('lib/lambdas/generatePosts/target/x86_64-unknown-linux-musl/release/lambda'),
       runtime: Runtime.PROVIDED_AL2,
       handler: 'not.required',
@@ -231,7 +336,16 @@ export class AutoPosterStack extends Stack {
       environment: {
         RUST_BACKTRACE: '1',
         OPEN_AI_API_KEY: openAiApiKey,
-        ADD_TO_DB_API: api.url
+        LLM_BASE_URL: llmBaseUrl,
+        LLM_MODEL: llmModel,
+        QUOTE_CHECK: quoteCheck,
+        TABLE_NAME: 'Posts',
+        BATCH_TABLE_NAME: generationBatches,
+        HISTORY_TABLE_NAME: postHistory,
+        DUPLICATE_CHECK: 'drop',
+        MODERATION_CLASSIFIER: moderationClassifier,
+        IMAGE_GENERATION: imageGeneration,
+        IMAGE_BUCKET: imageBucket.bucketName
       },
       logRetention: RetentionDays.ONE_WEEK,
       role: lambdaRole
@@ -240,11 +354,14 @@ export class AutoPosterStack extends Stack {
       schedule: Schedule.rate(Duration.days(1)),
     });
     generateEvent.addTarget(new LambdaFunction(generatePosts));
+    dynamoTable.grantReadWriteData(generatePosts);
+    batchTable.grantWriteData(generatePosts);
+    historyTable.grantReadData(generatePosts);
+    imageBucket.grantPut(generatePosts);
 
     // Add api endpoint for generation
-    const generateAPIIntegration = new LambdaIntegration(generatePosts);
     const generate = generateAPI.root.addResource('generate');
-    generate.addMethod('POST', generateAPIIntegration);
+    generate.addMethod('POST', apiIntegration);
 
     // 2 Lambda function subscribers
 
@@ -261,7 +378,12 @@ export class AutoPosterStack extends Stack {
         CONSUMER_KEY: consumerKey,
         CONSUMER_SECRET: consumerSecret,
         ACCESS_TOKEN: accessToken,
-        ACCESS_TOKEN_SECRET: accessTokenSecret
+        ACCESS_TOKEN_SECRET: accessTokenSecret,
+        ARTICLE_LINK_MODE: articleLinkMode,
+        UTM_TAGGING: utmTagging,
+        UTM_CAMPAIGN: utmCampaign,
+        SHORTENER: shortener,
+        SHORTENER_URL: shortenerUrl
       },
       logRetention: RetentionDays.ONE_WEEK,
       role: lambdaRole
@@ -278,7 +400,12 @@ export class AutoPosterStack extends Stack {
         RUST_BACKTRACE: '1',
         SNS_ARN: postTopic.topicArn,
         DESO_USER: desoUser,
-        DESO_PRIVATE_KEY: desoPrivateKey
+        DESO_PRIVATE_KEY: desoPrivateKey,
+        ARTICLE_LINK_MODE: articleLinkMode,
+        UTM_TAGGING: utmTagging,
+        UTM_CAMPAIGN: utmCampaign,
+        SHORTENER: shortener,
+        SHORTENER_URL: shortenerUrl
       },
       logRetention: RetentionDays.ONE_WEEK,
       role: lambdaRole
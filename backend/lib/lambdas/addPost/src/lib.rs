@@ -0,0 +1,331 @@
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use lambda_runtime::{LambdaEvent};
+use std::collections::HashMap;
+use aws_sdk_dynamodb::operation::get_item::GetItemInput;
+use aws_sdk_dynamodb::operation::put_item::PutItem;
+use std::env;
+use aws_config::{meta::region::RegionProviderChain, SdkConfig};
+use aws_sdk_dynamodb::{config::Region, meta::PKG_VERSION};
+use aws_sdk_dynamodb::Client as DbClient;
+use std::iter::Iterator;
+use lambda_http::{Response, Body, Error, Request};
+use serde_json::json;
+use shared::duplicates::{filter_duplicates, load_known_posts, DuplicateConfig};
+use shared::hashtags::HashtagPolicy;
+use shared::auth::{require_scope, Scope};
+use shared::http::{check_post_count, check_post_text, parse_body, respond, ApiError};
+use shared::idempotency::{start, Start};
+use shared::moderation::Moderator;
+use shared::storage::{batch_put, BatchWriteResult, PostRecord, STATUSES};
+
+// Posts added by hand have already been read by a person, when that
+// person can publish. Anyone else's posts wait for review.
+const DEFAULT_STATUS: &str = "approved";
+const UNREVIEWED_STATUS: &str = "draft";
+
+
+#[derive(Debug)]
+pub struct Opt {
+    /// The AWS Region.
+    pub region: Option<String>,
+    /// Whether to display additional information.
+    pub verbose: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Post {
+    pub post: String,
+    /// draft, approved or rejected, defaults to approved
+    #[serde(default)]
+    pub status: Option<String>,
+    /// POST or THREAD, posts are single posts unless told otherwise
+    #[serde(default)]
+    pub content_type: Option<String>,
+    /// A thread's posts in order
+    #[serde(default)]
+    pub segments: Option<Vec<String>>,
+    /// Which prompt template generated the post, if any
+    #[serde(default)]
+    pub prompt_version: Option<String>,
+    /// Quotes that couldn't be found in the source article
+    #[serde(default)]
+    pub unverified_quotes: Option<Vec<String>>,
+    /// The source the post is for, it has to carry that source's required hashtags
+    #[serde(default)]
+    pub source: Option<String>
+}
+
+impl Post {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        check_post_text("post", &self.post)?;
+        if let Some(status) = &self.status {
+            if !STATUSES.contains(&status.as_str()) {
+                return Err(ApiError::invalid("status", &format!("Unknown status: {}", status)));
+            }
+        }
+        if let Some(content_type) = &self.content_type {
+            if content_type != "POST" && content_type != "THREAD" {
+                return Err(ApiError::invalid("content_type", &format!("Unknown content type: {}", content_type)));
+            }
+        }
+        for (i, segment) in self.segments.iter().flatten().enumerate() {
+            check_post_text(&format!("segments[{}]", i), segment)?;
+        }
+        Ok(())
+    }
+
+    /// Everything about the post's hashtags that breaks the policy
+    pub fn hashtag_violations(&self, policy: &HashtagPolicy) -> Vec<String> {
+        let violations = match &self.segments {
+            Some(segments) => policy.check_segments(segments, self.source.as_deref()),
+            None => policy.check(&self.post, self.source.as_deref()),
+        };
+        violations.into_iter().map(|v| format!("{:?}: {}", self.post, v)).collect()
+    }
+
+    pub fn into_record(self) -> PostRecord {
+        let mut record = PostRecord::new(self.post, &self.status.unwrap_or(String::from(DEFAULT_STATUS)));
+        record.content_type = self.content_type;
+        record.segments = self.segments;
+        record.prompt_version = self.prompt_version;
+        record.unverified_quotes = self.unverified_quotes;
+        record.source = self.source;
+        record
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Posts {
+    pub posts: Vec<Post>
+}
+
+// Define an iterator type for Posts
+pub struct PostsIterator {
+    inner: std::vec::IntoIter<Post>,
+}
+
+impl Iterator for PostsIterator {
+    type Item = Post;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+// Implement IntoIterator for Posts
+impl IntoIterator for Posts {
+    type Item = Post;
+    type IntoIter = PostsIterator;
+
+    fn into_iter(self) -> Self::IntoIter {
+        PostsIterator {
+            inner: self.posts.into_iter(),
+        }
+    }
+}
+
+async fn get_table_name() -> Option<String> {
+    env::var("TABLE_NAME").ok()
+}
+
+async fn get_history_table_name() -> Option<String> {
+    env::var("HISTORY_TABLE_NAME").ok()
+}
+
+pub async fn make_config(opt: Opt) -> Result<SdkConfig, Error> {
+    let region_provider = make_region_provider(opt.region);
+
+    println!();
+    if opt.verbose {
+        println!("DynamoDB client version: {}", PKG_VERSION);
+        println!(
+            "Region:                  {}",
+            region_provider.region().await.unwrap().as_ref()
+        );
+        println!();
+    }
+
+    Ok(aws_config::from_env().region(region_provider).load().await)
+}
+
+pub fn make_region_provider(region: Option<String>) -> RegionProviderChain {
+    RegionProviderChain::first_try(region.map(Region::new))
+        .or_default_provider()
+        .or_else(Region::new("us-east-1"))
+}
+
+
+/**
+ * Writes the posts, retrying anything DynamoDB leaves unprocessed, and
+ * reports which were written.
+ */
+pub async fn add_to_db(client: &DbClient, posts: Vec<PostRecord>, table: String) -> BatchWriteResult {
+    let items = posts.iter().map(PostRecord::to_item).collect();
+    let result = batch_put(client, &table, items).await;
+    println!("Written: {} Failed: {}", result.written.len(), result.failed.len());
+    result
+}
+
+
+pub async fn handler(request: Request, db_client: &DbClient) -> Result<Response<String>, Error> {
+    // 1. Get table name from env
+    let table_name = match get_table_name().await {
+        Some(t) => t,
+        None => return ApiError::config("TABLE_NAME not set").into_response(),
+    };
+    let mut posts: Posts = match parse_body(request.body()) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    println!("Posts: {:?}", posts);
+    if let Err(e) = check_post_count(posts.posts.len()) {
+        return e.into_response();
+    }
+    for (i, post) in posts.posts.iter().enumerate() {
+        if let Err(e) = post.validate() {
+            return e.within(&format!("posts[{}]", i)).into_response();
+        }
+    }
+    if require_scope(&request, Scope::Publish).is_err() {
+        for post in posts.posts.iter_mut().filter(|p| p.status.is_none()) {
+            post.status = Some(String::from(UNREVIEWED_STATUS));
+        }
+    }
+    // Approving a post decides it goes out, which takes the publish scope
+    if posts.posts.iter().any(|p| p.status.as_deref().unwrap_or(DEFAULT_STATUS) == "approved") {
+        if let Err(e) = require_scope(&request, Scope::Publish) {
+            return e.into_response();
+        }
+    }
+    let hashtag_policy = match HashtagPolicy::load() {
+        Ok(p) => p,
+        Err(e) => return ApiError::config(&e).into_response(),
+    };
+    for (i, post) in posts.posts.iter().enumerate() {
+        let violations = post.hashtag_violations(&hashtag_policy);
+        if !violations.is_empty() {
+            return ApiError::invalid("post", &format!("Hashtag policy: {}", violations.join("; ")))
+                .within(&format!("posts[{}]", i))
+                .into_response();
+        }
+    }
+
+    let moderator = match Moderator::from_env() {
+        Ok(m) => m,
+        Err(e) => return ApiError::config(&e).into_response(),
+    };
+
+    // 2. A retry with the same Idempotency-Key gets the first response back
+    let pending = match start(db_client, &request, "add").await {
+        Ok(Start::Proceed(p)) => p,
+        Ok(Start::Replay(replay)) => return replay.into_response(),
+        Err(e) => return e.into_response(),
+    };
+
+    // 3. Check for near duplicates of queued and recently published posts
+    let duplicate_config = DuplicateConfig::from_env();
+    let history_table = get_history_table_name().await;
+    let known = load_known_posts(db_client, &table_name, history_table.as_deref(), duplicate_config).await;
+    let records: Vec<PostRecord> = posts.into_iter().map(Post::into_record).collect();
+    let (mut records, duplicates) = filter_duplicates(records, known, duplicate_config);
+
+    // 4. Quarantine anything that fails moderation instead of queueing it
+    let quarantined = moderator.moderate_records(&mut records).await;
+
+    let result = add_to_db(db_client, records, table_name).await;
+    // Some posts getting in is a success, the response says which didn't
+    let (status, body) = if result.written.is_empty() && !result.failed.is_empty() {
+        let error = ApiError::internal(&format!("None of the posts could be written: {}", result.failed.join(", ")));
+        (error.status, error.to_body())
+    } else {
+        (200, json!({
+            "added": result.written.len(),
+            "created": result.written,
+            "failed": result.failed,
+            "duplicates": duplicates,
+            "quarantined": quarantined
+        }).to_string())
+    };
+    if let Some(pending) = pending {
+        pending.finish(db_client, status, &body).await;
+    }
+    respond(status, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    #[test]
+    fn test_add_to_db() {
+        let post = Post {
+            post: String::from("Test Post 1"),
+            status: None,
+            content_type: None,
+            segments: None,
+            prompt_version: None,
+            unverified_quotes: None,
+            source: None
+        };
+        let post2 = Post {
+            post: String::from("Test Post 2"),
+            status: Some(String::from("draft")),
+            content_type: None,
+            segments: None,
+            prompt_version: Some(String::from("tweets@v1")),
+            unverified_quotes: Some(vec![String::from("Run with joy every single day")]),
+            source: None
+        };
+        let thread = Post {
+            post: String::from("Test Thread 1\n\nTest Thread 2\n\nTest Thread 3"),
+            status: Some(String::from("draft")),
+            content_type: Some(String::from("THREAD")),
+            segments: Some(vec![
+                String::from("Test Thread 1"),
+                String::from("Test Thread 2"),
+                String::from("Test Thread 3")
+            ]),
+            prompt_version: Some(String::from("thread@v1")),
+            unverified_quotes: None,
+            source: None
+        };
+        let posts: Vec<PostRecord> = vec![post, post2, thread].into_iter()
+            .map(Post::into_record)
+            .collect();
+        let opt = Opt {
+            region: Some("us-east-1".to_string()),
+            verbose: true,
+        };
+        let config = aw!(make_config(opt)).unwrap();
+        println!("{:?}", config);
+        let db_client = DbClient::new(&config);
+        let table_name = String::from("Posts");
+        let result = aw!(add_to_db(&db_client, posts, table_name));
+        println!("Result: {:?}", result);
+    }
+
+    #[test]
+    fn test_hashtag_violations() {
+        let policy = HashtagPolicy::load().unwrap();
+        let mut post = Post {
+            post: String::from("Rest days are training days too. #HybridAthlete"),
+            status: None,
+            content_type: None,
+            segments: None,
+            prompt_version: None,
+            unverified_quotes: None,
+            source: None
+        };
+        assert!(post.hashtag_violations(&policy).is_empty());
+        post.post = String::from("Rest days are #training days too. #FitnessLife");
+        assert_eq!(post.hashtag_violations(&policy).len(), 2);
+    }
+}
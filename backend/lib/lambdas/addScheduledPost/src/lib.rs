@@ -0,0 +1,236 @@
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use lambda_runtime::{LambdaEvent};
+use std::collections::HashMap;
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::operation::get_item::GetItemInput;
+use aws_sdk_dynamodb::operation::put_item::PutItem;
+use std::env;
+use aws_config::{meta::region::RegionProviderChain, SdkConfig};
+use aws_sdk_dynamodb::{config::Region, meta::PKG_VERSION};
+use aws_sdk_dynamodb::Client as DbClient;
+use std::iter::Iterator;
+use uuid::Uuid;
+use lambda_http::{Response, Body, Error, Request};
+use serde_json::json;
+use chrono::{Local, NaiveTime, DateTime, Timelike};
+use shared::http::{check_post_count, check_post_text, parse_body, respond, ApiError};
+use shared::idempotency::{start, Start};
+use shared::schedule::validate_schedule;
+use shared::storage::{batch_put, BatchWriteResult};
+
+
+#[derive(Debug)]
+pub struct Opt {
+    /// The AWS Region.
+    pub region: Option<String>,
+    /// Whether to display additional information.
+    pub verbose: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Post {
+    pub post: String,
+    pub time: String,
+    pub recurring: bool,
+    /// An IANA name like America/New_York, UTC when unset
+    #[serde(default)]
+    pub timezone: Option<String>,
+}
+
+impl Post {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        check_post_text("post", &self.post)?;
+        validate_schedule(Some(&self.time), self.timezone.as_deref())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Posts {
+    pub posts: Vec<Post>
+}
+
+// Define an iterator type for Posts
+pub struct PostsIterator {
+    inner: std::vec::IntoIter<Post>,
+}
+
+impl Iterator for PostsIterator {
+    type Item = Post;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+// Implement IntoIterator for Posts
+impl IntoIterator for Posts {
+    type Item = Post;
+    type IntoIter = PostsIterator;
+
+    fn into_iter(self) -> Self::IntoIter {
+        PostsIterator {
+            inner: self.posts.into_iter(),
+        }
+    }
+}
+
+// The API lambda serves posts too, so it names the scheduled table separately
+async fn get_table_name() -> Option<String> {
+    env::var("SCHEDULED_TABLE_NAME").ok()
+}
+
+async fn generate_uuid() -> String {
+    Uuid::new_v4().to_string()
+}
+
+pub async fn make_config(opt: Opt) -> Result<SdkConfig, Error> {
+    let region_provider = make_region_provider(opt.region);
+
+    println!();
+    if opt.verbose {
+        println!("DynamoDB client version: {}", PKG_VERSION);
+        println!(
+            "Region:                  {}",
+            region_provider.region().await.unwrap().as_ref()
+        );
+        println!();
+    }
+
+    Ok(aws_config::from_env().region(region_provider).load().await)
+}
+
+pub fn make_region_provider(region: Option<String>) -> RegionProviderChain {
+    RegionProviderChain::first_try(region.map(Region::new))
+        .or_default_provider()
+        .or_else(Region::new("us-east-1"))
+}
+
+
+/**
+ * Writes the scheduled posts, retrying anything DynamoDB leaves
+ * unprocessed, and reports which were written.
+ */
+pub async fn add_to_db(client: &DbClient, posts: Posts, table: String) -> BatchWriteResult {
+    let mut items = Vec::new();
+    for post in posts {
+        let uuid = AttributeValue::S(generate_uuid().await);
+        let mut item = HashMap::new();
+        item.insert("uuid".to_string(), uuid);
+        item.insert("post".to_string(), AttributeValue::S(post.post));
+        item.insert("time".to_string(), AttributeValue::S(post.time));
+        item.insert("recurring".to_string(), AttributeValue::Bool(post.recurring));
+        if let Some(timezone) = post.timezone {
+            item.insert("timezone".to_string(), AttributeValue::S(timezone));
+        }
+        items.push(item);
+    }
+    let result = batch_put(client, &table, items).await;
+    println!("Written: {} Failed: {}", result.written.len(), result.failed.len());
+    result
+}
+
+
+pub async fn handler(request: Request, db_client: &DbClient) -> Result<Response<String>, Error> {
+    // 1. Get table name from env
+    let table_name = match get_table_name().await {
+        Some(t) => t,
+        None => return ApiError::config("SCHEDULED_TABLE_NAME not set").into_response(),
+    };
+    let posts: Posts = match parse_body(request.body()) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    println!("Posts: {:?}", posts);
+    if let Err(e) = check_post_count(posts.posts.len()) {
+        return e.into_response();
+    }
+    // Check every post first so a bad one doesn't leave the rest half added
+    for (i, post) in posts.posts.iter().enumerate() {
+        if let Err(e) = post.validate() {
+            return e.within(&format!("posts[{}]", i)).into_response();
+        }
+    }
+    // A retry with the same Idempotency-Key gets the first response back
+    let pending = match start(db_client, &request, "addSchedule").await {
+        Ok(Start::Proceed(p)) => p,
+        Ok(Start::Replay(replay)) => return replay.into_response(),
+        Err(e) => return e.into_response(),
+    };
+    let result = add_to_db(db_client, posts, table_name).await;
+    let (status, body) = if result.written.is_empty() && !result.failed.is_empty() {
+        let error = ApiError::internal("None of the scheduled posts could be written");
+        (error.status, error.to_body())
+    } else {
+        (200, json!({
+            "created": result.written,
+            "failed": result.failed
+        }).to_string())
+    };
+    if let Some(pending) = pending {
+        pending.finish(db_client, status, &body).await;
+    }
+    respond(status, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    #[test]
+    fn test_add_to_db() {
+        let post = Post {
+            post: String::from("I'm scheduled for 4pm and will be deleted"),
+            time: NaiveTime::from_hms_opt(16, 0, 0).unwrap().to_string(),
+            recurring: false,
+            timezone: None
+        };
+        let post2 = Post {
+            post: String::from("I am scheduled for 8am and will recur"),
+            time: NaiveTime::from_hms_opt(8, 0, 0).unwrap().to_string(),
+            recurring: true,
+            timezone: Some(String::from("America/New_York"))
+        };
+        let posts: Posts = Posts {
+            posts: vec![post, post2]
+        };
+        let opt = Opt {
+            region: Some("us-east-1".to_string()),
+            verbose: true,
+        };
+        let config = aw!(make_config(opt)).unwrap();
+        println!("{:?}", config);
+        let db_client = DbClient::new(&config);
+        let table_name = String::from("ScheduledPosts");
+        let result = aw!(add_to_db(&db_client, posts, table_name));
+        println!("Result: {:?}", result);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut post = Post {
+            post: String::from("Morning run!"),
+            time: String::from("07:30:00"),
+            recurring: true,
+            timezone: Some(String::from("Europe/London"))
+        };
+        assert!(post.validate().is_ok());
+        post.timezone = Some(String::from("London"));
+        assert!(post.validate().is_err());
+        post.timezone = None;
+        post.time = String::from("7:30pm");
+        assert_eq!(post.validate().unwrap_err().field, Some(String::from("time")));
+        post.time = String::from("07:30:00");
+        post.post = String::new();
+        assert_eq!(post.validate().unwrap_err().field, Some(String::from("post")));
+    }
+}
@@ -0,0 +1,111 @@
+use aws_config::meta::region::RegionProviderChain;
+use aws_sdk_dynamodb::config::Region;
+use aws_sdk_dynamodb::Client as DbClient;
+use aws_sdk_s3::Client as S3Client;
+use lambda_http::http::header::ORIGIN;
+use lambda_http::{service_fn, Response, Error, Request};
+use shared::auth::{apply_cors, guard, AuthConfig};
+use shared::http::ApiError;
+use std::time::Instant;
+
+mod routes;
+use routes::{find_route, Endpoint};
+
+/**
+ * The AWS clients every handler uses. They're made once when the lambda
+ * starts, so requests don't each load the SDK config again.
+ */
+pub struct Clients {
+    pub db: DbClient,
+    pub s3: S3Client,
+}
+
+impl Clients {
+    pub async fn load() -> Clients {
+        let region_provider = RegionProviderChain::first_try(Region::new("us-east-1"));
+        let config = aws_config::from_env().region(region_provider).load().await;
+        Clients {
+            db: DbClient::new(&config),
+            s3: S3Client::new(&config),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let clients = Clients::load().await;
+    let func = service_fn(|request| handler(request, &clients));
+    lambda_http::run(func).await?;
+
+    Ok(())
+}
+
+async fn call(endpoint: Endpoint, request: Request, clients: &Clients) -> Result<Response<String>, Error> {
+    match endpoint {
+        Endpoint::AddPost => add_post::handler(request, &clients.db).await,
+        Endpoint::AddScheduledPost => add_scheduled_post::handler(request, &clients.db).await,
+        Endpoint::EditPost => edit_post::handler(request, &clients.db).await,
+        Endpoint::EditScheduledPost => edit_scheduled_post::handler(request, &clients.db).await,
+        Endpoint::ReviewPost => review_post::handler(request, &clients.db).await,
+        Endpoint::GetPosts => get_posts::handler(request, &clients.db).await,
+        Endpoint::GetScheduledPosts => get_scheduled_posts::handler(request, &clients.db).await,
+        Endpoint::DeletePosts => delete_posts::handler(request, &clients.db).await,
+        Endpoint::GeneratePosts => generate_posts::http_handler(request, &clients.db, &clients.s3).await,
+    }
+}
+
+/**
+ * Sends a request to the handler for its method and path, behind that
+ * route's scope. Every response gets the same CORS headers and error body,
+ * including a handler's unexpected error, which would otherwise reach the
+ * caller as the runtime's bare 500.
+ */
+pub async fn handler(request: Request, clients: &Clients) -> Result<Response<String>, Error> {
+    let started = Instant::now();
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let (method, path) = (&method, &path);
+    let response = match find_route(method, path) {
+        Ok(route) => guard(request, route.scope, |request| async move {
+            match call(route.endpoint, request, clients).await {
+                Ok(response) => Ok(response),
+                Err(e) => {
+                    println!("{} {} failed: {:?}", method, path, e);
+                    ApiError::internal(&format!("Failed: {}", e)).into_response()
+                }
+            }
+        }).await?,
+        Err(e) => {
+            let origin = request.headers().get(ORIGIN).and_then(|o| o.to_str().ok());
+            let mut response = e.into_response()?;
+            apply_cors(&mut response, &AuthConfig::from_env(), origin);
+            response
+        }
+    };
+    println!("{} {} {} in {}ms", method, path, response.status().as_u16(), started.elapsed().as_millis());
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    #[test]
+    fn test_handler_unknown_path() {
+        let request = lambda_http::http::Request::builder()
+            .method("POST")
+            .uri("/prod/publishEverything")
+            .body(lambda_http::Body::Empty)
+            .unwrap();
+        let clients = aw!(Clients::load());
+        let response = aw!(handler(request, &clients)).unwrap();
+        assert_eq!(response.status().as_u16(), 404);
+        assert!(response.body().contains("not_found"));
+    }
+}
@@ -0,0 +1,102 @@
+use shared::auth::Scope;
+use shared::http::ApiError;
+
+/// The handlers a request can be sent to, one for each lambda the API used to call
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Endpoint {
+    AddPost,
+    AddScheduledPost,
+    EditPost,
+    EditScheduledPost,
+    ReviewPost,
+    GetPosts,
+    GetScheduledPosts,
+    DeletePosts,
+    GeneratePosts,
+}
+
+/**
+ * A method and path the API answers. Path segments in braces, like {uuid},
+ * match any one segment.
+ */
+#[derive(Debug, PartialEq)]
+pub struct Route {
+    pub method: &'static str,
+    pub path: &'static str,
+    pub endpoint: Endpoint,
+    pub scope: Scope,
+}
+
+pub const ROUTES: &[Route] = &[
+    Route { method: "POST", path: "add", endpoint: Endpoint::AddPost, scope: Scope::Write },
+    // Scheduled posts go out without a review, so setting one up is publishing
+    Route { method: "POST", path: "addSchedule", endpoint: Endpoint::AddScheduledPost, scope: Scope::Publish },
+    Route { method: "POST", path: "editPosts", endpoint: Endpoint::EditPost, scope: Scope::Write },
+    Route { method: "POST", path: "editSchedule", endpoint: Endpoint::EditScheduledPost, scope: Scope::Publish },
+    Route { method: "POST", path: "approvePosts", endpoint: Endpoint::ReviewPost, scope: Scope::Publish },
+    Route { method: "POST", path: "rejectPosts", endpoint: Endpoint::ReviewPost, scope: Scope::Publish },
+    Route { method: "GET", path: "getPosts", endpoint: Endpoint::GetPosts, scope: Scope::Read },
+    Route { method: "GET", path: "getScheduledPosts", endpoint: Endpoint::GetScheduledPosts, scope: Scope::Read },
+    Route { method: "DELETE", path: "deletePosts", endpoint: Endpoint::DeletePosts, scope: Scope::Write },
+    Route { method: "DELETE", path: "deletePosts/{uuid}", endpoint: Endpoint::DeletePosts, scope: Scope::Write },
+    Route { method: "DELETE", path: "deleteScheduledPosts", endpoint: Endpoint::DeletePosts, scope: Scope::Write },
+    Route { method: "DELETE", path: "deleteScheduledPosts/{uuid}", endpoint: Endpoint::DeletePosts, scope: Scope::Write },
+    Route { method: "POST", path: "generate", endpoint: Endpoint::GeneratePosts, scope: Scope::Generate },
+];
+
+/**
+ * Whether a request path ends with the pattern. Only the end has to match
+ * so the stage, /prod, or a custom domain's base path in front is fine.
+ */
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('/').collect();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    if segments.len() < parts.len() {
+        return false;
+    }
+    segments[segments.len() - parts.len()..].iter()
+        .zip(&parts)
+        .all(|(segment, part)| (part.starts_with('{') && part.ends_with('}')) || segment == part)
+}
+
+/// The route for a request, 404 if nothing is at the path and 405 if something is but not for this method.
+pub fn find_route(method: &str, path: &str) -> Result<&'static Route, ApiError> {
+    let at_path: Vec<&'static Route> = ROUTES.iter().filter(|r| matches(r.path, path)).collect();
+    if at_path.is_empty() {
+        return Err(ApiError::not_found(&format!("Nothing at {}", path)));
+    }
+    match at_path.iter().find(|r| r.method.eq_ignore_ascii_case(method)) {
+        Some(route) => Ok(route),
+        None => {
+            let mut allowed: Vec<&str> = at_path.iter().map(|r| r.method).collect();
+            allowed.dedup();
+            Err(ApiError::new(405, "method_not_allowed", &format!("{} isn't allowed on {}, use {}", method, path, allowed.join(", "))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_route() {
+        assert_eq!(find_route("POST", "/add").unwrap().endpoint, Endpoint::AddPost);
+        assert_eq!(find_route("POST", "/prod/addSchedule").unwrap().endpoint, Endpoint::AddScheduledPost);
+        assert_eq!(find_route("GET", "/prod/getPosts/").unwrap().scope, Scope::Read);
+        assert_eq!(find_route("POST", "/prod/rejectPosts").unwrap().scope, Scope::Publish);
+        assert_eq!(find_route("POST", "/prod/addSchedule").unwrap().scope, Scope::Publish);
+        assert_eq!(find_route("POST", "/prod/editSchedule").unwrap().scope, Scope::Publish);
+        assert_eq!(find_route("POST", "/prod/generate").unwrap().endpoint, Endpoint::GeneratePosts);
+
+        let single = find_route("DELETE", "/prod/deleteScheduledPosts/df6381e1-1cd1-4c5e-8442-11745c43c7d7").unwrap();
+        assert_eq!(single.path, "deleteScheduledPosts/{uuid}");
+        assert_eq!(find_route("DELETE", "/deletePosts").unwrap().path, "deletePosts");
+
+        assert_eq!(find_route("GET", "/prod/getPost").unwrap_err().status, 404);
+        assert_eq!(find_route("GET", "/").unwrap_err().status, 404);
+        let error = find_route("GET", "/prod/add").unwrap_err();
+        assert_eq!(error.status, 405);
+        assert_eq!(error.message, "GET isn't allowed on /prod/add, use POST");
+    }
+}
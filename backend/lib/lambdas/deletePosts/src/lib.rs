@@ -0,0 +1,212 @@
+use serde::Deserialize;
+use serde::Serialize;
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
+use std::env;
+use aws_config::{meta::region::RegionProviderChain, SdkConfig};
+use aws_sdk_dynamodb::{config::Region, meta::PKG_VERSION};
+use aws_sdk_dynamodb::Client as DbClient;
+use lambda_http::{Response, Error, Request};
+use shared::http::{check_post_count, check_uuid, parse_body, respond_json, ApiError};
+
+
+#[derive(Debug)]
+pub struct Opt {
+    /// The AWS Region.
+    pub region: Option<String>,
+    /// Whether to display additional information.
+    pub verbose: bool,
+}
+
+/// Which table a delete endpoint removes posts from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Posts,
+    ScheduledPosts
+}
+
+impl Target {
+    /**
+     * The table and, for a single delete, the post's id from the request
+     * path. /deletePosts and /deleteScheduledPosts delete the ids in the
+     * body, /deletePosts/{uuid} and /deleteScheduledPosts/{uuid} delete one.
+     */
+    pub fn from_path(path: &str) -> Option<(Target, Option<String>)> {
+        let mut segments = path.trim_end_matches('/').rsplit('/');
+        let last = segments.next()?;
+        match last {
+            "deletePosts" => return Some((Target::Posts, None)),
+            "deleteScheduledPosts" => return Some((Target::ScheduledPosts, None)),
+            _ => {}
+        };
+        let uuid = Some(last.to_string());
+        match segments.next()? {
+            "deletePosts" => Some((Target::Posts, uuid)),
+            "deleteScheduledPosts" => Some((Target::ScheduledPosts, uuid)),
+            _ => None
+        }
+    }
+
+    fn table_env(&self) -> &'static str {
+        match self {
+            Target::Posts => "TABLE_NAME",
+            Target::ScheduledPosts => "SCHEDULED_TABLE_NAME",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostId {
+    pub uuid: String
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Posts {
+    pub posts: Vec<PostId>
+}
+
+/// What happened to each id, `missing` ones weren't in the table
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct DeleteResult {
+    pub deleted: Vec<String>,
+    pub missing: Vec<String>,
+    pub failed: Vec<String>
+}
+
+pub async fn make_config(opt: Opt) -> Result<SdkConfig, Error> {
+    let region_provider = make_region_provider(opt.region);
+
+    println!();
+    if opt.verbose {
+        println!("DynamoDB client version: {}", PKG_VERSION);
+        println!(
+            "Region:                  {}",
+            region_provider.region().await.unwrap().as_ref()
+        );
+        println!();
+    }
+
+    Ok(aws_config::from_env().region(region_provider).load().await)
+}
+
+pub fn make_region_provider(region: Option<String>) -> RegionProviderChain {
+    RegionProviderChain::first_try(region.map(Region::new))
+        .or_default_provider()
+        .or_else(Region::new("us-east-1"))
+}
+
+/**
+ * Deletes one post. Returns whether it was there to delete, DynamoDB
+ * doesn't fail deleting an item that doesn't exist.
+ */
+pub async fn delete_post(client: &DbClient, table: &str, uuid: &str) -> Result<bool, Error> {
+    let response = client.delete_item()
+        .table_name(table)
+        .key("uuid", AttributeValue::S(uuid.to_string()))
+        .return_values(ReturnValue::AllOld)
+        .send().await?;
+
+    Ok(response.attributes.map(|a| !a.is_empty()).unwrap_or(false))
+}
+
+pub async fn delete_posts(client: &DbClient, table: &str, uuids: Vec<String>) -> DeleteResult {
+    let mut result = DeleteResult::default();
+    for uuid in uuids {
+        match delete_post(client, table, &uuid).await {
+            Ok(true) => result.deleted.push(uuid),
+            Ok(false) => result.missing.push(uuid),
+            Err(e) => {
+                println!("Failed deleting {} {:?}", uuid, e);
+                result.failed.push(uuid);
+            }
+        };
+    }
+    result
+}
+
+/// The ids in a bulk delete body, each once and in order.
+pub fn bulk_ids(body: &[u8]) -> Result<Vec<String>, ApiError> {
+    let posts: Posts = parse_body(body)?;
+    let mut uuids: Vec<String> = Vec::new();
+    for (i, post) in posts.posts.into_iter().enumerate() {
+        check_uuid("uuid", &post.uuid).map_err(|e| e.within(&format!("posts[{}]", i)))?;
+        if !uuids.contains(&post.uuid) {
+            uuids.push(post.uuid);
+        }
+    }
+    // Keeps a bulk delete inside the lambda's timeout
+    check_post_count(uuids.len())?;
+    Ok(uuids)
+}
+
+pub async fn handler(request: Request, db_client: &DbClient) -> Result<Response<String>, Error> {
+    // 1. Work out which table and which posts
+    let (target, single) = match Target::from_path(request.uri().path()) {
+        Some(t) => t,
+        None => return ApiError::not_found(&format!("Unknown delete path: {}", request.uri().path())).into_response(),
+    };
+    let uuids = match &single {
+        Some(uuid) => vec![uuid.clone()],
+        None => match bulk_ids(request.body()) {
+            Ok(u) => u,
+            Err(e) => return e.into_response(),
+        }
+    };
+
+    // 2. Get table name from env
+    let table_name = match env::var(target.table_env()) {
+        Ok(t) => t,
+        Err(_) => return ApiError::config(&format!("{} not set", target.table_env())).into_response(),
+    };
+    println!("Deleting {:?} from {}", uuids, table_name);
+
+    // 3. Delete each post, keeping track of what was actually there
+    let result = delete_posts(db_client, &table_name, uuids).await;
+    // A bulk delete reports each id in the body, a single one uses the status too
+    let status = match &single {
+        Some(_) if !result.failed.is_empty() => 500,
+        Some(_) if !result.missing.is_empty() => 404,
+        _ => 200
+    };
+    respond_json(status, &result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    #[test]
+    fn test_target_from_path() {
+        assert_eq!(Target::from_path("/prod/deletePosts"), Some((Target::Posts, None)));
+        assert_eq!(Target::from_path("/deleteScheduledPosts/"), Some((Target::ScheduledPosts, None)));
+        assert_eq!(Target::from_path("/prod/deletePosts/abc-123"), Some((Target::Posts, Some(String::from("abc-123")))));
+        assert_eq!(Target::from_path("/prod/deleteScheduledPosts/abc-123"), Some((Target::ScheduledPosts, Some(String::from("abc-123")))));
+        assert_eq!(Target::from_path("/prod/editPosts/abc-123"), None);
+        assert_eq!(Target::from_path("/"), None);
+    }
+
+    #[test]
+    fn test_bulk_ids() {
+        let body = br#"{"posts": [{"uuid": "a"}, {"uuid": "b"}, {"uuid": "a"}]}"#;
+        assert_eq!(bulk_ids(body).unwrap(), vec!["a", "b"]);
+        assert!(bulk_ids(b"[]").is_err());
+        assert_eq!(bulk_ids(br#"{"posts": []}"#).unwrap_err().field, Some(String::from("posts")));
+    }
+
+    #[test]
+    fn test_delete_posts() {
+        let opt = Opt {
+            region: Some("us-east-1".to_string()),
+            verbose: true,
+        };
+        let config = aw!(make_config(opt)).unwrap();
+        let db_client = DbClient::new(&config);
+        let resp = aw!(delete_posts(&db_client, "Posts", vec![String::from("not-a-real-post")]));
+        println!("Response: {:?}", resp);
+    }
+}
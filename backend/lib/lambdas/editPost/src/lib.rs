@@ -0,0 +1,526 @@
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use lambda_runtime::{LambdaEvent};
+use std::collections::HashMap;
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
+use aws_sdk_dynamodb::operation::get_item::GetItemInput;
+use aws_sdk_dynamodb::operation::put_item::PutItem;
+use std::env;
+use aws_config::{meta::region::RegionProviderChain, SdkConfig};
+use aws_sdk_dynamodb::{config::Region, meta::PKG_VERSION};
+use aws_sdk_dynamodb::Client as DbClient;
+use std::iter::Iterator;
+use uuid::Uuid;
+use lambda_http::{Response, Body, Error, Request};
+use serde_json::json;
+use shared::hashtags::HashtagPolicy;
+use shared::auth::{require_scope, Scope};
+use shared::http::{check_post_count, check_post_text, check_uuid, parse_body, respond_json, ApiError};
+use shared::moderation::{Moderator, QUARANTINED};
+use shared::storage::{now, STATUSES};
+
+
+// A thread segment is one tweet, so it has to fit X's limit
+pub const MAX_SEGMENT_CHARS: usize = 280;
+
+#[derive(Debug)]
+pub struct Opt {
+    /// The AWS Region.
+    pub region: Option<String>,
+    /// Whether to display additional information.
+    pub verbose: bool,
+}
+
+/**
+ * An edit to one post. Only the fields that are set are changed. New text
+ * without a status sends the post back to draft to be reviewed again. A
+ * thread's text is edited through its segments, which its post is rebuilt
+ * from, since the segments are what gets published.
+ */
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Post {
+    pub uuid: String,
+    #[serde(default)]
+    pub post: Option<String>,
+    #[serde(default)]
+    pub segments: Option<Vec<String>>,
+    #[serde(default)]
+    pub status: Option<String>,
+    /// The source the post is for when it doesn't have one stored, it has to
+    /// carry that source's required hashtags
+    #[serde(default)]
+    pub source: Option<String>,
+    /// The version the edit was made from. The edit is refused if the post
+    /// has changed since, posts from before versions are version 0.
+    #[serde(default)]
+    pub version: Option<u64>,
+    /// Set when the edit fails moderation
+    #[serde(skip)]
+    pub moderation_reasons: Option<Vec<String>>
+}
+
+impl Post {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        check_uuid("uuid", &self.uuid)?;
+        if self.post.is_none() && self.segments.is_none() && self.status.is_none() {
+            return Err(ApiError::new(400, "nothing_to_change", &format!("Nothing to change on {}", self.uuid)));
+        }
+        if let Some(text) = &self.post {
+            check_post_text("post", text)?;
+        }
+        if let Some(segments) = &self.segments {
+            if self.post.is_some() {
+                return Err(ApiError::invalid("post", "A thread's post is made from its segments, send one or the other"));
+            }
+            if segments.is_empty() {
+                return Err(ApiError::invalid("segments", "A thread needs at least one segment"));
+            }
+            for (i, segment) in segments.iter().enumerate() {
+                let field = format!("segments[{}]", i);
+                check_post_text(&field, segment)?;
+                if segment.chars().count() > MAX_SEGMENT_CHARS {
+                    return Err(ApiError::invalid(&field, &format!("Segments can be at most {} characters", MAX_SEGMENT_CHARS)));
+                }
+            }
+        }
+        if let Some(status) = &self.status {
+            if !STATUSES.contains(&status.as_str()) {
+                return Err(ApiError::invalid("status", &format!("Unknown status: {}", status)));
+            }
+            // New text goes back for review, approving it is a separate step
+            if status == "approved" && self.text().is_some() {
+                return Err(ApiError::invalid("status", "Edited text can't be approved in the same edit, approve it once it's been reviewed"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Everything about the edited text's hashtags that breaks the policy, a thread segment by segment
+    pub fn hashtag_violations(&self, policy: &HashtagPolicy, source: Option<&str>) -> Vec<String> {
+        match (&self.segments, &self.post) {
+            (Some(segments), _) => policy.check_segments(segments, source),
+            (None, Some(text)) => policy.check(text, source),
+            (None, None) => Vec::new()
+        }
+    }
+
+    /// The status the edit leaves the post in, new text without one is a draft again
+    pub fn new_status(&self) -> Option<&str> {
+        match (&self.status, self.text()) {
+            // Never approve text in the edit that changes it
+            (Some(status), Some(_)) if status == "approved" => Some("draft"),
+            (Some(status), _) => Some(status.as_str()),
+            (None, Some(_)) => Some("draft"),
+            (None, None) => None
+        }
+    }
+
+    /// The edited text, a thread's segments joined the way generated threads are
+    pub fn text(&self) -> Option<String> {
+        match &self.segments {
+            Some(segments) => Some(segments.join("\n\n")),
+            None => self.post.clone(),
+        }
+    }
+}
+
+/// The UpdateItem for an edit
+#[derive(Debug, PartialEq)]
+pub struct Update {
+    pub expression: String,
+    pub condition: String,
+    pub names: HashMap<String, String>,
+    pub values: HashMap<String, AttributeValue>
+}
+
+/**
+ * Sets the edited fields and bumps the version. The post has to exist, so
+ * an edit never brings back a post that was published or deleted, and has
+ * to still be at the version the edit was made from. Plain text can't
+ * replace a thread, its segments would still go out.
+ */
+pub fn build_update(post: &Post, edited_at: u64) -> Update {
+    let mut sets = Vec::new();
+    let mut removes = Vec::new();
+    let mut names = HashMap::new();
+    let mut values = HashMap::new();
+    names.insert(String::from("#uuid"), String::from("uuid"));
+    let text = post.text();
+    if let Some(text) = &text {
+        sets.push("#post = :post");
+        names.insert(String::from("#post"), String::from("post"));
+        values.insert(String::from(":post"), AttributeValue::S(text.clone()));
+    }
+    if let Some(segments) = &post.segments {
+        sets.push("segments = :segments");
+        values.insert(String::from(":segments"), AttributeValue::L(segments.iter().cloned().map(AttributeValue::S).collect()));
+    }
+    if let Some(status) = post.new_status() {
+        sets.push("#status = :status");
+        names.insert(String::from("#status"), String::from("status"));
+        values.insert(String::from(":status"), AttributeValue::S(String::from(status)));
+    }
+    match &post.moderation_reasons {
+        Some(reasons) => {
+            sets.push("moderation_reasons = :reasons");
+            values.insert(String::from(":reasons"), AttributeValue::L(reasons.iter().cloned().map(AttributeValue::S).collect()));
+        },
+        // New text that passed moderation clears the old reasons
+        None if text.is_some() => removes.push("moderation_reasons"),
+        None => {}
+    };
+    sets.push("edited_at = :edited_at");
+    values.insert(String::from(":edited_at"), AttributeValue::N(edited_at.to_string()));
+    sets.push("version = if_not_exists(version, :zero) + :one");
+    values.insert(String::from(":zero"), AttributeValue::N(String::from("0")));
+    values.insert(String::from(":one"), AttributeValue::N(String::from("1")));
+
+    let mut expression = format!("SET {}", sets.join(", "));
+    if !removes.is_empty() {
+        expression = format!("{} REMOVE {}", expression, removes.join(", "));
+    }
+    let mut condition = match post.version {
+        Some(0) => String::from("attribute_exists(#uuid) AND attribute_not_exists(version)"),
+        Some(version) => {
+            values.insert(String::from(":version"), AttributeValue::N(version.to_string()));
+            String::from("attribute_exists(#uuid) AND version = :version")
+        },
+        None => String::from("attribute_exists(#uuid)")
+    };
+    if post.post.is_some() {
+        condition.push_str(" AND attribute_not_exists(segments)");
+    }
+    Update {
+        expression,
+        condition,
+        names,
+        values
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EditOutcome {
+    /// The post's new version
+    Updated(u64),
+    Missing,
+    Conflict,
+    /// Plain text sent for a thread, which has to be edited through its segments
+    Thread
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Posts {
+    pub posts: Vec<Post>
+}
+
+// Define an iterator type for Posts
+pub struct PostsIterator {
+    inner: std::vec::IntoIter<Post>,
+}
+
+impl Iterator for PostsIterator {
+    type Item = Post;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+// Implement IntoIterator for Posts
+impl IntoIterator for Posts {
+    type Item = Post;
+    type IntoIter = PostsIterator;
+
+    fn into_iter(self) -> Self::IntoIter {
+        PostsIterator {
+            inner: self.posts.into_iter(),
+        }
+    }
+}
+
+async fn get_table_name() -> Option<String> {
+    env::var("TABLE_NAME").ok()
+}
+
+pub async fn make_config(opt: Opt) -> Result<SdkConfig, Error> {
+    let region_provider = make_region_provider(opt.region);
+
+    println!();
+    if opt.verbose {
+        println!("DynamoDB client version: {}", PKG_VERSION);
+        println!(
+            "Region:                  {}",
+            region_provider.region().await.unwrap().as_ref()
+        );
+        println!();
+    }
+
+    Ok(aws_config::from_env().region(region_provider).load().await)
+}
+
+pub fn make_region_provider(region: Option<String>) -> RegionProviderChain {
+    RegionProviderChain::first_try(region.map(Region::new))
+        .or_default_provider()
+        .or_else(Region::new("us-east-1"))
+}
+
+
+/// The source stored on the post, None if it has none or is gone
+pub async fn stored_source(client: &DbClient, table: &str, uuid: &str) -> Result<Option<String>, Error> {
+    let output = client.get_item()
+        .table_name(table)
+        .key("uuid", AttributeValue::S(String::from(uuid)))
+        .projection_expression("#source")
+        .expression_attribute_names("#source", "source")
+        .send().await?;
+    Ok(output.item.and_then(|i| i.get("source").and_then(|s| s.as_s().ok()).cloned()))
+}
+
+pub async fn update_post(client: &DbClient, table: &str, post: &Post) -> Result<EditOutcome, Error> {
+    let update = build_update(post, now());
+    let result = client.update_item()
+        .table_name(table)
+        .key("uuid", AttributeValue::S(post.uuid.clone()))
+        .update_expression(update.expression)
+        .condition_expression(update.condition)
+        .set_expression_attribute_names(Some(update.names))
+        .set_expression_attribute_values(Some(update.values))
+        .return_values(ReturnValue::UpdatedNew)
+        .send().await;
+    match result {
+        Ok(output) => {
+            let version = output.attributes
+                .and_then(|a| a.get("version").and_then(|v| v.as_n().ok()).and_then(|v| v.parse().ok()))
+                .unwrap_or(0);
+            Ok(EditOutcome::Updated(version))
+        },
+        Err(e) if e.as_service_error().map(|s| s.is_conditional_check_failed_exception()).unwrap_or(false) => {
+            // The condition doesn't say which part failed, so look
+            let existing = client.get_item()
+                .table_name(table)
+                .key("uuid", AttributeValue::S(post.uuid.clone()))
+                .projection_expression("#uuid, segments")
+                .expression_attribute_names("#uuid", "uuid")
+                .send().await?;
+            match existing.item {
+                Some(item) if post.post.is_some() && item.contains_key("segments") => Ok(EditOutcome::Thread),
+                Some(_) => Ok(EditOutcome::Conflict),
+                None => Ok(EditOutcome::Missing)
+            }
+        },
+        Err(e) => Err(e.into())
+    }
+}
+
+
+pub async fn handler(request: Request, db_client: &DbClient) -> Result<Response<String>, Error> {
+    // 1. Get table name from env
+    let table_name = match get_table_name().await {
+        Some(t) => t,
+        None => return ApiError::config("TABLE_NAME not set").into_response(),
+    };
+    let mut posts: Posts = match parse_body(request.body()) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    println!("Posts: {:?}", posts);
+    if let Err(e) = check_post_count(posts.posts.len()) {
+        return e.into_response();
+    }
+    for (i, post) in posts.posts.iter().enumerate() {
+        if let Err(e) = post.validate() {
+            return e.within(&format!("posts[{}]", i)).into_response();
+        }
+    }
+    // Approving a post decides it goes out, which takes the publish scope
+    if posts.posts.iter().any(|p| p.status.as_deref() == Some("approved")) {
+        if let Err(e) = require_scope(&request, Scope::Publish) {
+            return e.into_response();
+        }
+    }
+    let hashtag_policy = match HashtagPolicy::load() {
+        Ok(p) => p,
+        Err(e) => return ApiError::config(&e).into_response(),
+    };
+    for (i, post) in posts.posts.iter().enumerate() {
+        if post.text().is_none() {
+            continue;
+        }
+        // The stored source decides the required tags, so an edit can't drop them by leaving it out
+        let source = match stored_source(db_client, &table_name, &post.uuid).await {
+            Ok(s) => s.or(post.source.clone()),
+            Err(e) => return ApiError::internal(&format!("Failed reading post {}: {}", post.uuid, e)).into_response(),
+        };
+        let violations = post.hashtag_violations(&hashtag_policy, source.as_deref());
+        if !violations.is_empty() {
+            return ApiError::invalid("post", &format!("Hashtag policy: {}", violations.join("; ")))
+                .within(&format!("posts[{}]", i))
+                .into_response();
+        }
+    }
+    // Edited text is checked again, and quarantined if it fails
+    let moderator = match Moderator::from_env() {
+        Ok(m) => m,
+        Err(e) => return ApiError::config(&e).into_response(),
+    };
+    for post in posts.posts.iter_mut() {
+        let text = match post.text() {
+            Some(t) => t,
+            None => continue
+        };
+        let reasons = match moderator.moderate(&text).await {
+            Ok(r) => r,
+            Err(e) => vec![e],
+        };
+        if !reasons.is_empty() {
+            println!("Quarantining {:?}: {:?}", text, reasons);
+            post.status = Some(String::from(QUARANTINED));
+            post.moderation_reasons = Some(reasons);
+        }
+    }
+
+    // Each post is updated on its own so one conflict doesn't stop the rest
+    let mut updated = Vec::new();
+    let mut missing = Vec::new();
+    let mut conflicts = Vec::new();
+    let mut threads = Vec::new();
+    let mut failed = Vec::new();
+    for post in &posts.posts {
+        match update_post(db_client, &table_name, post).await {
+            Ok(EditOutcome::Updated(version)) => updated.push(json!({
+                "uuid": post.uuid,
+                "version": version,
+                "status": post.new_status()
+            })),
+            Ok(EditOutcome::Missing) => missing.push(post.uuid.clone()),
+            Ok(EditOutcome::Conflict) => conflicts.push(post.uuid.clone()),
+            Ok(EditOutcome::Thread) => threads.push(post.uuid.clone()),
+            Err(e) => {
+                println!("Failed updating {} {:?}", post.uuid, e);
+                failed.push(post.uuid.clone());
+            }
+        };
+    }
+    let status = if !threads.is_empty() {
+        400
+    } else if !conflicts.is_empty() {
+        409
+    } else if !missing.is_empty() {
+        404
+    } else if !failed.is_empty() {
+        500
+    } else {
+        200
+    };
+    respond_json(status, &json!({
+        "updated": updated,
+        "missing": missing,
+        "conflicts": conflicts,
+        "threads": threads,
+        "failed": failed
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    fn edit(post: Option<&str>, status: Option<&str>, version: Option<u64>) -> Post {
+        Post {
+            uuid: String::from("df6381e1-1cd1-4c5e-8442-11745c43c7d7"),
+            post: post.map(String::from),
+            segments: None,
+            status: status.map(String::from),
+            source: None,
+            version,
+            moderation_reasons: None
+        }
+    }
+
+    #[test]
+    fn test_build_update() {
+        let update = build_update(&edit(Some("Run with joy! #RunWithJoy"), None, Some(3)), 1718670000);
+        assert_eq!(update.expression, "SET #post = :post, #status = :status, edited_at = :edited_at, version = if_not_exists(version, :zero) + :one REMOVE moderation_reasons");
+        assert_eq!(update.condition, "attribute_exists(#uuid) AND version = :version AND attribute_not_exists(segments)");
+        assert_eq!(update.values.get(":status"), Some(&AttributeValue::S(String::from("draft"))));
+        assert_eq!(update.values.get(":version"), Some(&AttributeValue::N(String::from("3"))));
+
+        let update = build_update(&edit(None, Some("approved"), Some(0)), 1718670000);
+        assert_eq!(update.expression, "SET #status = :status, edited_at = :edited_at, version = if_not_exists(version, :zero) + :one");
+        assert_eq!(update.condition, "attribute_exists(#uuid) AND attribute_not_exists(version)");
+        assert!(!update.names.contains_key("#post"));
+
+        assert!(edit(None, None, None).validate().is_err());
+        assert_eq!(edit(None, Some("published"), None).validate().unwrap_err().field, Some(String::from("status")));
+        assert_eq!(edit(Some(""), None, None).validate().unwrap_err().field, Some(String::from("post")));
+
+        // Changed text isn't approved until someone reviews it
+        let approved_text = edit(Some("Run with joy!"), Some("approved"), Some(3));
+        assert_eq!(approved_text.validate().unwrap_err().field, Some(String::from("status")));
+        let update = build_update(&approved_text, 1718670000);
+        assert_eq!(update.values.get(":status"), Some(&AttributeValue::S(String::from("draft"))));
+        assert_eq!(edit(Some("Run with joy!"), Some("rejected"), Some(3)).new_status(), Some("rejected"));
+
+        let thread = Post {
+            segments: Some(vec![String::from("1. Run with joy."), String::from("2. Rest too.")]),
+            ..edit(None, None, Some(2))
+        };
+        assert!(thread.validate().is_ok());
+        let update = build_update(&thread, 1718670000);
+        assert_eq!(update.expression, "SET #post = :post, segments = :segments, #status = :status, edited_at = :edited_at, version = if_not_exists(version, :zero) + :one REMOVE moderation_reasons");
+        assert_eq!(update.condition, "attribute_exists(#uuid) AND version = :version");
+        assert_eq!(update.values.get(":post"), Some(&AttributeValue::S(String::from("1. Run with joy.\n\n2. Rest too."))));
+        let too_long = Post {
+            segments: Some(vec![String::from("Fine"), "a".repeat(MAX_SEGMENT_CHARS + 1)]),
+            ..edit(None, None, None)
+        };
+        assert_eq!(too_long.validate().unwrap_err().field, Some(String::from("segments[1]")));
+        let both = Post {
+            segments: Some(vec![String::from("One")]),
+            ..edit(Some("One"), None, None)
+        };
+        assert!(both.validate().is_err());
+    }
+
+    #[test]
+    fn test_hashtag_violations() {
+        let mut policy = HashtagPolicy::load().unwrap();
+        policy.required.insert(String::from("substack"), vec![String::from("#RunWithJoy")]);
+        let thread = Post {
+            segments: Some(vec![String::from("1. Run with joy. #Running"), String::from("2. Rest too. #RunWithJoy")]),
+            ..edit(None, None, None)
+        };
+        assert!(thread.hashtag_violations(&policy, Some("substack")).is_empty());
+        // Joined, the first segment's tag would sit in the middle of the text
+        assert!(!policy.check(&thread.text().unwrap(), Some("substack")).is_empty());
+        let stripped = edit(Some("Run with joy!"), None, None);
+        assert_eq!(stripped.hashtag_violations(&policy, Some("substack")), vec![String::from("Missing required hashtag #RunWithJoy")]);
+        assert!(edit(None, Some("approved"), None).hashtag_violations(&policy, Some("substack")).is_empty());
+    }
+
+    #[test]
+    fn test_update_post() {
+        let post = edit(
+            Some("Working toward a goal like a marathon? Balance is still important and might be crucial for achieving your goals. #MarathonTraining #RunWithJoy"),
+            Some("approved"),
+            None
+        );
+        let opt = Opt {
+            region: Some("us-east-1".to_string()),
+            verbose: true,
+        };
+        let config = aw!(make_config(opt)).unwrap();
+        println!("{:?}", config);
+        let db_client = DbClient::new(&config);
+        let table_name = String::from("Posts");
+        let resp = aw!(update_post(&db_client, &table_name, &post));
+        println!("Response: {:?}", resp);
+    }
+}
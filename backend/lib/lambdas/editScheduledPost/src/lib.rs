@@ -0,0 +1,278 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use aws_sdk_dynamodb::types::AttributeValue;
+use std::env;
+use aws_config::{meta::region::RegionProviderChain, SdkConfig};
+use aws_sdk_dynamodb::{config::Region, meta::PKG_VERSION};
+use aws_sdk_dynamodb::Client as DbClient;
+use lambda_http::{Response, Error, Request};
+use serde_json::json;
+use shared::http::{check_post_count, check_post_text, check_uuid, parse_body, respond_json, ApiError};
+use shared::schedule::validate_schedule;
+use shared::storage::now;
+
+
+#[derive(Debug)]
+pub struct Opt {
+    /// The AWS Region.
+    pub region: Option<String>,
+    /// Whether to display additional information.
+    pub verbose: bool,
+}
+
+/**
+ * An edit to one scheduled post. Only the fields that are set are changed,
+ * setting enabled to false pauses the post without deleting it.
+ */
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Post {
+    pub uuid: String,
+    #[serde(default)]
+    pub post: Option<String>,
+    #[serde(default)]
+    pub time: Option<String>,
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub recurring: Option<bool>,
+    #[serde(default)]
+    pub enabled: Option<bool>,
+}
+
+impl Post {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        check_uuid("uuid", &self.uuid)?;
+        if self.post.is_none() && self.time.is_none() && self.timezone.is_none()
+            && self.recurring.is_none() && self.enabled.is_none() {
+            return Err(ApiError::new(400, "nothing_to_change", &format!("Nothing to change on {}", self.uuid)));
+        }
+        if let Some(text) = &self.post {
+            check_post_text("post", text)?;
+        }
+        validate_schedule(self.time.as_deref(), self.timezone.as_deref())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Posts {
+    pub posts: Vec<Post>
+}
+
+/// The UpdateItem for an edit
+#[derive(Debug, PartialEq)]
+pub struct Update {
+    pub expression: String,
+    pub names: HashMap<String, String>,
+    pub values: HashMap<String, AttributeValue>
+}
+
+/**
+ * Sets the edited fields. New text takes the post out of quarantine,
+ * SendPost checks it again before it goes out.
+ */
+pub fn build_update(post: &Post, edited_at: u64) -> Update {
+    let mut sets = Vec::new();
+    let mut removes = Vec::new();
+    let mut names = HashMap::new();
+    let mut values = HashMap::new();
+    names.insert(String::from("#uuid"), String::from("uuid"));
+    if let Some(text) = &post.post {
+        sets.push("#post = :post");
+        names.insert(String::from("#post"), String::from("post"));
+        values.insert(String::from(":post"), AttributeValue::S(text.clone()));
+        removes.push("#status");
+        removes.push("moderation_reasons");
+        names.insert(String::from("#status"), String::from("status"));
+    }
+    if let Some(time) = &post.time {
+        sets.push("#time = :time");
+        names.insert(String::from("#time"), String::from("time"));
+        values.insert(String::from(":time"), AttributeValue::S(time.clone()));
+    }
+    if let Some(timezone) = &post.timezone {
+        sets.push("timezone = :timezone");
+        values.insert(String::from(":timezone"), AttributeValue::S(timezone.clone()));
+    }
+    if let Some(recurring) = post.recurring {
+        sets.push("recurring = :recurring");
+        values.insert(String::from(":recurring"), AttributeValue::Bool(recurring));
+    }
+    if let Some(enabled) = post.enabled {
+        sets.push("enabled = :enabled");
+        values.insert(String::from(":enabled"), AttributeValue::Bool(enabled));
+    }
+    sets.push("edited_at = :edited_at");
+    values.insert(String::from(":edited_at"), AttributeValue::N(edited_at.to_string()));
+
+    let mut expression = format!("SET {}", sets.join(", "));
+    if !removes.is_empty() {
+        expression = format!("{} REMOVE {}", expression, removes.join(", "));
+    }
+    Update {
+        expression,
+        names,
+        values
+    }
+}
+
+// The API lambda serves posts too, so it names the scheduled table separately
+async fn get_table_name() -> Option<String> {
+    env::var("SCHEDULED_TABLE_NAME").ok()
+}
+
+pub async fn make_config(opt: Opt) -> Result<SdkConfig, Error> {
+    let region_provider = make_region_provider(opt.region);
+
+    println!();
+    if opt.verbose {
+        println!("DynamoDB client version: {}", PKG_VERSION);
+        println!(
+            "Region:                  {}",
+            region_provider.region().await.unwrap().as_ref()
+        );
+        println!();
+    }
+
+    Ok(aws_config::from_env().region(region_provider).load().await)
+}
+
+pub fn make_region_provider(region: Option<String>) -> RegionProviderChain {
+    RegionProviderChain::first_try(region.map(Region::new))
+        .or_default_provider()
+        .or_else(Region::new("us-east-1"))
+}
+
+/**
+ * Updates one scheduled post. Returns false if it isn't in the table, a
+ * one off post may have been sent already.
+ */
+pub async fn update_scheduled_post(client: &DbClient, table: &str, post: &Post) -> Result<bool, Error> {
+    let update = build_update(post, now());
+    let result = client.update_item()
+        .table_name(table)
+        .key("uuid", AttributeValue::S(post.uuid.clone()))
+        .update_expression(update.expression)
+        .condition_expression("attribute_exists(#uuid)")
+        .set_expression_attribute_names(Some(update.names))
+        .set_expression_attribute_values(Some(update.values))
+        .send().await;
+    match result {
+        Ok(_) => Ok(true),
+        Err(e) if e.as_service_error().map(|s| s.is_conditional_check_failed_exception()).unwrap_or(false) => Ok(false),
+        Err(e) => Err(e.into())
+    }
+}
+
+pub async fn handler(request: Request, db_client: &DbClient) -> Result<Response<String>, Error> {
+    // 1. Get table name from env
+    let table_name = match get_table_name().await {
+        Some(t) => t,
+        None => return ApiError::config("SCHEDULED_TABLE_NAME not set").into_response(),
+    };
+    let posts: Posts = match parse_body(request.body()) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    println!("Posts: {:?}", posts);
+    if let Err(e) = check_post_count(posts.posts.len()) {
+        return e.into_response();
+    }
+    // Check every post first so a bad one doesn't leave the rest half edited
+    for (i, post) in posts.posts.iter().enumerate() {
+        if let Err(e) = post.validate() {
+            return e.within(&format!("posts[{}]", i)).into_response();
+        }
+    }
+
+    // 2. Update each post, keeping track of the ones that weren't there
+    let mut updated = Vec::new();
+    let mut missing = Vec::new();
+    let mut failed = Vec::new();
+    for post in &posts.posts {
+        match update_scheduled_post(db_client, &table_name, post).await {
+            Ok(true) => updated.push(post.uuid.clone()),
+            Ok(false) => missing.push(post.uuid.clone()),
+            Err(e) => {
+                println!("Failed updating {} {:?}", post.uuid, e);
+                failed.push(post.uuid.clone());
+            }
+        };
+    }
+    let status = if !missing.is_empty() {
+        404
+    } else if !failed.is_empty() {
+        500
+    } else {
+        200
+    };
+    respond_json(status, &json!({
+        "updated": updated,
+        "missing": missing,
+        "failed": failed
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    fn edit() -> Post {
+        Post {
+            uuid: String::from("df6381e1-1cd1-4c5e-8442-11745c43c7d7"),
+            post: None,
+            time: None,
+            timezone: None,
+            recurring: None,
+            enabled: None
+        }
+    }
+
+    #[test]
+    fn test_build_update() {
+        let post = Post {
+            time: Some(String::from("09:00:00")),
+            timezone: Some(String::from("America/Chicago")),
+            enabled: Some(false),
+            ..edit()
+        };
+        assert!(post.validate().is_ok());
+        let update = build_update(&post, 1718670000);
+        assert_eq!(update.expression, "SET #time = :time, timezone = :timezone, enabled = :enabled, edited_at = :edited_at");
+        assert_eq!(update.values.get(":enabled"), Some(&AttributeValue::Bool(false)));
+
+        let post = Post {
+            post: Some(String::from("Long run Sunday!")),
+            ..edit()
+        };
+        let update = build_update(&post, 1718670000);
+        assert_eq!(update.expression, "SET #post = :post, edited_at = :edited_at REMOVE #status, moderation_reasons");
+
+        assert!(edit().validate().is_err());
+        assert!(Post { timezone: Some(String::from("Chicago")), ..edit() }.validate().is_err());
+        let error = Post { time: Some(String::from("9am")), ..edit() }.validate().unwrap_err();
+        assert_eq!(error.field, Some(String::from("time")));
+    }
+
+    #[test]
+    fn test_update_scheduled_post() {
+        let post = Post {
+            enabled: Some(true),
+            ..edit()
+        };
+        let opt = Opt {
+            region: Some("us-east-1".to_string()),
+            verbose: true,
+        };
+        let config = aw!(make_config(opt)).unwrap();
+        let db_client = DbClient::new(&config);
+        let resp = aw!(update_scheduled_post(&db_client, "ScheduledPosts", &post));
+        println!("Response: {:?}", resp);
+    }
+}
@@ -0,0 +1,18 @@
+use aws_sdk_dynamodb::Client as DbClient;
+use lambda_http::{service_fn, Error};
+use shared::auth::{guard, Scope};
+use edit_scheduled_post::{handler, make_config, Opt};
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let opt = Opt {
+        region: Some("us-east-1".to_string()),
+        verbose: true,
+    };
+    // One client for every request the lambda serves
+    let db_client = DbClient::new(&make_config(opt).await?);
+    let func = service_fn(|request| guard(request, Scope::Publish, |request| handler(request, &db_client)));
+    lambda_http::run(func).await?;
+
+    Ok(())
+}
@@ -0,0 +1,342 @@
+use std::collections::VecDeque;
+use tiktoken_rs::CoreBPE;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkConfig {
+    /// The most tokens a single chunk may contain.
+    pub max_tokens: usize,
+    /// How many tokens of trailing sentences to repeat at the start of the next chunk.
+    pub overlap_tokens: usize,
+}
+
+// A contiguous slice of the content. Concatenating every unit in order
+// gives back the original content.
+#[derive(Debug, Clone)]
+struct Unit {
+    text: String,
+    tokens: usize,
+    ends_paragraph: bool,
+}
+
+fn count_tokens(bpe: &CoreBPE, text: &str) -> usize {
+    bpe.encode_with_special_tokens(text).len()
+}
+
+fn join_units(units: &[Unit]) -> String {
+    units.iter().map(|u| u.text.as_str()).collect::<String>().trim().to_string()
+}
+
+/**
+ * Splits a paragraph after each run of sentence terminators (and any closing
+ * quotes or brackets) that is followed by whitespace. The whitespace stays
+ * with the sentence before it.
+ */
+fn split_sentences(paragraph: &str) -> Vec<&str> {
+    let chars: Vec<(usize, char)> = paragraph.char_indices().collect();
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+
+    while i < chars.len() {
+        if !matches!(chars[i].1, '.' | '!' | '?') {
+            i += 1;
+            continue;
+        }
+        let mut j = i + 1;
+        while j < chars.len() && matches!(chars[j].1, '.' | '!' | '?' | '"' | '\'' | '”' | '’' | ')' | ']') {
+            j += 1;
+        }
+        if j < chars.len() && chars[j].1.is_whitespace() {
+            while j < chars.len() && chars[j].1.is_whitespace() {
+                j += 1;
+            }
+            let end = if j < chars.len() { chars[j].0 } else { paragraph.len() };
+            sentences.push(&paragraph[start..end]);
+            start = end;
+        }
+        i = j;
+    }
+    if start < paragraph.len() {
+        sentences.push(&paragraph[start..]);
+    }
+    sentences
+}
+
+/**
+ * Breaks a sentence that is over budget on its own into words, and any word
+ * that is still over budget into runs of characters.
+ */
+fn split_oversized(bpe: &CoreBPE, sentence: &str, max_tokens: usize) -> Vec<(String, usize)> {
+    let mut pieces = Vec::new();
+    for word in sentence.split_inclusive(char::is_whitespace) {
+        let tokens = count_tokens(bpe, word);
+        if tokens <= max_tokens {
+            pieces.push((word.to_string(), tokens));
+            continue;
+        }
+        let chars: Vec<char> = word.chars().collect();
+        let mut start = 0;
+        let mut step = max_tokens.max(1);
+        while start < chars.len() {
+            let end = (start + step).min(chars.len());
+            let piece: String = chars[start..end].iter().collect();
+            let tokens = count_tokens(bpe, &piece);
+            if tokens > max_tokens && step > 1 {
+                step /= 2;
+                continue;
+            }
+            pieces.push((piece, tokens));
+            start = end;
+        }
+    }
+    pieces
+}
+
+fn split_units(bpe: &CoreBPE, content: &str, max_tokens: usize) -> Vec<Unit> {
+    let mut units = Vec::new();
+    for paragraph in content.split_inclusive("\n\n") {
+        let sentences = split_sentences(paragraph);
+        let last = sentences.len().saturating_sub(1);
+        for (i, sentence) in sentences.into_iter().enumerate() {
+            let tokens = count_tokens(bpe, sentence);
+            if tokens <= max_tokens {
+                units.push(Unit {
+                    text: sentence.to_string(),
+                    tokens,
+                    ends_paragraph: i == last,
+                });
+                continue;
+            }
+            let pieces = split_oversized(bpe, sentence, max_tokens);
+            let last_piece = pieces.len().saturating_sub(1);
+            for (j, (text, tokens)) in pieces.into_iter().enumerate() {
+                units.push(Unit {
+                    text,
+                    tokens,
+                    ends_paragraph: i == last && j == last_piece,
+                });
+            }
+        }
+    }
+    units
+}
+
+/**
+ * Finds where to end the current chunk. We cut after the last paragraph
+ * that leaves the chunk at least half full, otherwise we keep every
+ * sentence that fit.
+ */
+fn paragraph_cut(current: &[Unit], overlap_len: usize, max_tokens: usize) -> usize {
+    let mut tokens = 0;
+    let mut cut = None;
+    for (i, unit) in current.iter().enumerate() {
+        tokens += unit.tokens;
+        if i >= overlap_len && unit.ends_paragraph && tokens >= max_tokens / 2 {
+            cut = Some(i + 1);
+        }
+    }
+    cut.unwrap_or(current.len())
+}
+
+/**
+ * Turns the current units into a chunk, handing units back to the queue
+ * until the joined text is within budget. Token counts of the parts are
+ * only an estimate of the count of the whole, so this check is what
+ * actually guarantees the budget.
+ */
+fn close_chunk(
+    bpe: &CoreBPE,
+    current: &mut Vec<Unit>,
+    overlap_len: &mut usize,
+    queue: &mut VecDeque<Unit>,
+    max_tokens: usize,
+) -> String {
+    loop {
+        if count_tokens(bpe, &join_units(current)) <= max_tokens {
+            break;
+        }
+        if current.len() > *overlap_len + 1 {
+            queue.push_front(current.pop().unwrap());
+        } else if *overlap_len > 0 {
+            current.drain(..*overlap_len);
+            *overlap_len = 0;
+        } else {
+            break;
+        }
+    }
+    join_units(current)
+}
+
+fn tail_overlap(closed: &[Unit], overlap_tokens: usize) -> Vec<Unit> {
+    let mut tokens = 0;
+    let mut start = closed.len();
+    while start > 0 && tokens + closed[start - 1].tokens <= overlap_tokens {
+        tokens += closed[start - 1].tokens;
+        start -= 1;
+    }
+    closed[start..].to_vec()
+}
+
+/**
+ * Splits content into chunks of at most `max_tokens` cl100k_base tokens.
+ * Chunks end on paragraph boundaries where possible and on sentence
+ * boundaries otherwise; only a sentence that is over budget by itself gets
+ * broken up. Each chunk starts with up to `overlap_tokens` worth of the
+ * previous chunk's closing sentences so context isn't lost at the seams.
+ */
+pub fn chunk_content(bpe: &CoreBPE, content: &str, config: ChunkConfig) -> Vec<String> {
+    let max_tokens = config.max_tokens;
+    if content.trim().is_empty() {
+        return Vec::new();
+    }
+    if count_tokens(bpe, content.trim()) <= max_tokens {
+        return vec![content.trim().to_string()];
+    }
+    // Overlap has to leave room for new content in every chunk
+    let overlap_tokens = config.overlap_tokens.min(max_tokens / 2);
+
+    let mut queue: VecDeque<Unit> = split_units(bpe, content, max_tokens).into();
+    let mut chunks = Vec::new();
+    let mut current: Vec<Unit> = Vec::new();
+    let mut current_tokens = 0;
+    // Number of units at the start of `current` carried over from the previous chunk
+    let mut overlap_len = 0;
+
+    while let Some(unit) = queue.pop_front() {
+        if current_tokens + unit.tokens <= max_tokens {
+            current_tokens += unit.tokens;
+            current.push(unit);
+            continue;
+        }
+        if current.len() == overlap_len {
+            // Nothing new fits alongside the overlap, so drop it
+            current.clear();
+            overlap_len = 0;
+            current_tokens = unit.tokens;
+            current.push(unit);
+            continue;
+        }
+        queue.push_front(unit);
+        let cut = paragraph_cut(&current, overlap_len, max_tokens);
+        for unit in current.split_off(cut).into_iter().rev() {
+            queue.push_front(unit);
+        }
+        chunks.push(close_chunk(bpe, &mut current, &mut overlap_len, &mut queue, max_tokens));
+
+        current = tail_overlap(&current, overlap_tokens);
+        current_tokens = current.iter().map(|u| u.tokens).sum();
+        overlap_len = current.len();
+    }
+    if current.len() > overlap_len {
+        chunks.push(close_chunk(bpe, &mut current, &mut overlap_len, &mut queue, max_tokens));
+        // Anything handed back while closing still needs a home
+        while !queue.is_empty() {
+            current = queue.drain(..).collect();
+            overlap_len = 0;
+            chunks.push(close_chunk(bpe, &mut current, &mut overlap_len, &mut queue, max_tokens));
+        }
+    }
+
+    chunks.into_iter().filter(|c| !c.is_empty()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tiktoken_rs::cl100k_base;
+
+    fn article(paragraphs: usize) -> String {
+        (0..paragraphs)
+            .map(|p| {
+                (0..6)
+                    .map(|s| format!("Paragraph {} sentence {} talks about running, \"strength\" and recovery.", p, s))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            })
+            .collect::<Vec<String>>()
+            .join("\n\n")
+    }
+
+    #[test]
+    fn test_short_content_is_one_chunk() {
+        let bpe = cl100k_base().unwrap();
+        let config = ChunkConfig { max_tokens: 500, overlap_tokens: 50 };
+        let chunks = chunk_content(&bpe, "  Run with joy. Every day!  ", config);
+        assert_eq!(chunks, vec!["Run with joy. Every day!".to_string()]);
+        assert!(chunk_content(&bpe, "   ", config).is_empty());
+    }
+
+    #[test]
+    fn test_every_chunk_is_under_budget() {
+        let bpe = cl100k_base().unwrap();
+        let content = article(40);
+        for max_tokens in [60, 150, 400] {
+            let config = ChunkConfig { max_tokens, overlap_tokens: 40 };
+            let chunks = chunk_content(&bpe, &content, config);
+            assert!(chunks.len() > 1);
+            for chunk in &chunks {
+                assert!(count_tokens(&bpe, chunk) <= max_tokens, "chunk over {} tokens: {}", max_tokens, chunk);
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunks_end_on_sentence_boundaries() {
+        let bpe = cl100k_base().unwrap();
+        let content = article(20);
+        let config = ChunkConfig { max_tokens: 150, overlap_tokens: 0 };
+        for chunk in chunk_content(&bpe, &content, config) {
+            assert!(chunk.ends_with("recovery."), "chunk cut mid-sentence: {}", chunk);
+            assert_eq!(chunk.matches('"').count() % 2, 0, "chunk cut mid-quote: {}", chunk);
+        }
+    }
+
+    #[test]
+    fn test_chunks_prefer_paragraph_boundaries() {
+        let bpe = cl100k_base().unwrap();
+        let content = article(20);
+        let paragraph_tokens = count_tokens(&bpe, content.split("\n\n").next().unwrap());
+        let config = ChunkConfig { max_tokens: paragraph_tokens * 3, overlap_tokens: 0 };
+        for chunk in chunk_content(&bpe, &content, config) {
+            assert!(chunk.ends_with("sentence 5 talks about running, \"strength\" and recovery."), "chunk cut mid-paragraph: {}", chunk);
+        }
+    }
+
+    #[test]
+    fn test_chunks_overlap() {
+        let bpe = cl100k_base().unwrap();
+        let content = article(20);
+        let config = ChunkConfig { max_tokens: 150, overlap_tokens: 40 };
+        let chunks = chunk_content(&bpe, &content, config);
+        for pair in chunks.windows(2) {
+            let last_sentence = split_sentences(&pair[0]).last().unwrap().trim().to_string();
+            let first_sentence = split_sentences(&pair[1]).first().unwrap().trim().to_string();
+            assert!(pair[1].contains(&last_sentence), "no overlap between {:?} and {:?}", pair[0], pair[1]);
+            assert!(pair[0].contains(&first_sentence), "no overlap between {:?} and {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn test_no_content_is_lost() {
+        let bpe = cl100k_base().unwrap();
+        let content = article(20);
+        let config = ChunkConfig { max_tokens: 100, overlap_tokens: 30 };
+        let chunks = chunk_content(&bpe, &content, config);
+        for sentence in split_sentences(&content) {
+            assert!(chunks.iter().any(|c| c.contains(sentence.trim())), "missing sentence: {}", sentence);
+        }
+    }
+
+    #[test]
+    fn test_oversized_word_is_split() {
+        let bpe = cl100k_base().unwrap();
+        let url = format!("https://example.com/{}", "abcdefghij".repeat(200));
+        let content = format!("Read more here: {} and run with joy.", url);
+        let config = ChunkConfig { max_tokens: 50, overlap_tokens: 10 };
+        let chunks = chunk_content(&bpe, &content, config);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(count_tokens(&bpe, chunk) <= 50);
+        }
+    }
+}
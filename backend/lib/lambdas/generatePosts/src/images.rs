@@ -0,0 +1,213 @@
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client as S3Client;
+use serde::Deserialize;
+use serde_json::json;
+use std::env;
+
+use super::provider::{ChatMessage, LlmProvider, ResponseFormat};
+use super::{extract_json, FailureResponse};
+use shared::moderation::QUARANTINED;
+use shared::storage::{GenerationBatch, PostRecord};
+
+const IMAGE_PLAN_PROMPT: &str = "Write a prompt for an illustration to go with this {subject}. \
+The illustration should be warm and encouraging, suit a running and fitness account, and contain \
+no text, logos or real people. Also write alt text that describes the illustration in one or two \
+sentences for someone who can't see it. Respond in JSON format like this: \
+{\"prompt\": \"...\", \"alt_text\": \"...\"}\n\n{text}";
+
+// Only the start of the article is needed to illustrate it
+const MAX_ARTICLE_CHARS: usize = 2000;
+
+/**
+ * Which images to make. Post draws one for every post, Article draws one
+ * for the article and gives it to every post from it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageMode {
+    Off,
+    Post,
+    Article,
+}
+
+impl std::str::FromStr for ImageMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ImageMode, String> {
+        match s {
+            "off" | "none" => Ok(ImageMode::Off),
+            "post" => Ok(ImageMode::Post),
+            "article" => Ok(ImageMode::Article),
+            _ => Err(format!("Unknown image mode {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageConfig {
+    pub mode: ImageMode,
+    pub bucket: Option<String>,
+}
+
+impl ImageConfig {
+    /**
+     * Configuration:
+     * IMAGE_GENERATION: off (default), post or article
+     * IMAGE_BUCKET: where images are stored, images are off without it
+     */
+    pub fn from_env() -> ImageConfig {
+        ImageConfig {
+            mode: env::var("IMAGE_GENERATION").ok()
+                .and_then(|m| m.parse().ok())
+                .unwrap_or(ImageMode::Off),
+            bucket: env::var("IMAGE_BUCKET").ok(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ImagePlan {
+    pub prompt: String,
+    pub alt_text: String,
+}
+
+/// A drawn image and its alt text
+#[derive(Debug, Clone, PartialEq)]
+pub struct Illustration {
+    pub image: Vec<u8>,
+    pub alt_text: String,
+}
+
+fn image_plan_format() -> ResponseFormat {
+    ResponseFormat {
+        name: String::from("image"),
+        schema: json!({
+            "type": "object",
+            "properties": {
+                "prompt": { "type": "string" },
+                "alt_text": { "type": "string" }
+            },
+            "required": ["prompt", "alt_text"],
+            "additionalProperties": false
+        }),
+    }
+}
+
+/**
+ * Asks the model what to draw and how to describe it, then draws it.
+ */
+pub async fn illustrate(provider: &dyn LlmProvider, subject: &str, text: &str) -> Result<Illustration, FailureResponse> {
+    let prompt = IMAGE_PLAN_PROMPT
+        .replace("{subject}", subject)
+        .replace("{text}", text);
+    let reply = provider.complete(vec![ChatMessage::user(prompt)], Some(&image_plan_format())).await?;
+    let plan: ImagePlan = match extract_json(&reply).and_then(|j| serde_json::from_str(&j).ok()) {
+        Some(p) => p,
+        None => return Err(FailureResponse {
+            body: format!("Could not parse image plan: {}", reply)
+        }),
+    };
+    println!("Image prompt: {}", plan.prompt);
+    let image = provider.generate_image(&plan.prompt).await?;
+    Ok(Illustration {
+        image,
+        alt_text: plan.alt_text,
+    })
+}
+
+pub async fn store_image(client: &S3Client, bucket: &str, key: &str, image: Vec<u8>) -> Result<(), FailureResponse> {
+    match client.put_object()
+        .bucket(bucket)
+        .key(key)
+        .content_type("image/png")
+        .body(ByteStream::from(image))
+        .send().await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(FailureResponse {
+                body: format!("Failed storing image {}: {}", key, e)
+            }),
+        }
+}
+
+async fn illustrate_and_store(provider: &dyn LlmProvider, client: &S3Client, bucket: &str, key: &str, subject: &str, text: &str) -> Result<String, FailureResponse> {
+    let illustration = illustrate(provider, subject, text).await?;
+    store_image(client, bucket, key, illustration.image).await?;
+    Ok(illustration.alt_text)
+}
+
+/**
+ * Draws and stores the images for a batch and attaches them to its posts.
+ * Images are nice to have, so a post whose image fails is kept without
+ * one. Quarantined posts aren't illustrated. Returns how many posts got
+ * an image.
+ */
+pub async fn add_images(provider: &dyn LlmProvider, client: &S3Client, config: &ImageConfig, batch: &GenerationBatch, article_content: &str, records: &mut [PostRecord]) -> usize {
+    let bucket = match (&config.mode, &config.bucket) {
+        (ImageMode::Off, _) => return 0,
+        (_, Some(b)) => b,
+        (_, None) => {
+            println!("IMAGE_BUCKET not set, skipping images");
+            return 0;
+        }
+    };
+    let mut illustrated = 0;
+    match config.mode {
+        ImageMode::Article => {
+            let key = format!("images/{}.png", batch.uuid);
+            let text: String = format!("{}\n\n{}", batch.article_title, article_content)
+                .chars()
+                .take(MAX_ARTICLE_CHARS)
+                .collect();
+            let alt_text = match illustrate_and_store(provider, client, bucket, &key, "article", &text).await {
+                Ok(a) => a,
+                Err(e) => {
+                    println!("{}", e);
+                    return 0;
+                }
+            };
+            for record in records.iter_mut().filter(|r| r.status != QUARANTINED) {
+                record.image_key = Some(key.clone());
+                record.image_alt = Some(alt_text.clone());
+                illustrated += 1;
+            }
+        },
+        _ => {
+            for record in records.iter_mut().filter(|r| r.status != QUARANTINED) {
+                let key = format!("images/{}.png", record.uuid);
+                match illustrate_and_store(provider, client, bucket, &key, "post", &record.post).await {
+                    Ok(alt_text) => {
+                        record.image_key = Some(key);
+                        record.image_alt = Some(alt_text);
+                        illustrated += 1;
+                    },
+                    Err(e) => println!("{}", e),
+                }
+            }
+        },
+    }
+    illustrated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::provider::FakeProvider;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    #[test]
+    fn test_illustrate() {
+        let plan = r#"{"prompt": "A runner at sunrise, watercolor", "alt_text": "A lone runner on a trail at sunrise."}"#;
+        let provider = FakeProvider::new(vec![plan]);
+        let illustration = aw!(illustrate(&provider, "post", "Run with joy! #RunWithJoy")).unwrap();
+        assert_eq!(illustration.image, b"A runner at sunrise, watercolor".to_vec());
+        assert_eq!(illustration.alt_text, "A lone runner on a trail at sunrise.");
+        assert!(provider.requests.lock().unwrap()[0][0].content.ends_with("Run with joy! #RunWithJoy"));
+
+        let provider = FakeProvider::new(vec!["I can't draw that"]);
+        assert!(aw!(illustrate(&provider, "post", "Run with joy!")).is_err());
+    }
+}
@@ -0,0 +1,973 @@
+use lambda_http::Context;
+use lambda_runtime::LambdaEvent;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use futures_util::future::join_all;
+use reqwest::get;
+use select::document::Document;
+use select::predicate::Name;
+use uuid::Uuid;
+use std::env;
+use scraper::{Html, Selector};
+use lambda_http::{Response, Body, Error, Request};
+use tokio::fs::File;
+use tokio::time::Duration;
+use tokio::fs::File as AsyncFile;
+use tokio_util::codec::{BytesCodec, FramedRead};
+use std::path::Path;
+use std::io::prelude::*;
+use base64;
+use tokio::io::AsyncWriteExt;
+use dotenv::dotenv;
+use std::any::Any;
+use std::str::FromStr;
+use xml::reader::{EventReader, XmlEvent};
+use regex::Regex;
+use reqwest;
+use tiktoken_rs::cl100k_base;
+use aws_config::{meta::region::RegionProviderChain, SdkConfig};
+use aws_sdk_dynamodb::{config::Region, meta::PKG_VERSION};
+use aws_sdk_dynamodb::Client as DbClient;
+use aws_sdk_s3::Client as S3Client;
+use shared::duplicates::{filter_duplicates, load_known_posts, DuplicateConfig};
+use shared::hashtags::HashtagPolicy;
+use shared::http::{respond, ApiError};
+use shared::moderation::Moderator;
+use shared::storage::{write_generation, GenerationBatch, PostRecord};
+
+mod chunking;
+mod images;
+mod prompts;
+mod provider;
+mod quotes;
+mod validation;
+use chunking::{chunk_content, ChunkConfig};
+use images::{add_images, ImageConfig, ImageMode};
+use prompts::{GenerateRequest, Prompt, PromptConfig};
+use provider::{ChatMessage, LlmProvider, OpenAiCompatibleProvider, ResponseFormat};
+use quotes::{check_quotes, QuoteCheckConfig};
+use validation::{describe_rejections, parse_posts, parse_thread, platform_limit, posts_response_format, selection_schema, thread_response_format, validate_post, validate_posts, validate_thread, Rejection, MAX_THREAD_SEGMENTS, MIN_THREAD_SEGMENTS};
+
+
+
+const REASK_PROMPT: &str = "Some of those Tweets can't be used:
+
+{problems}
+
+Write replacements for them that are under {max_length} characters and contain no placeholder 
+text. Respond with only the replacement Tweets in the same JSON format.";
+
+const THREAD_REASK_PROMPT: &str = "That thread can't be used:
+
+{problems}
+
+Rewrite the whole thread with {min_segments} to {max_segments} Tweets, each under {max_length} 
+characters and with no placeholder text. Respond with the thread in the same JSON format.";
+
+const SUMMARY_PROMPT: &str = "Summarize this part of an article in a few short paragraphs. 
+Keep its most quotable lines word for word.";
+
+const REDUCE_PROMPT: &str = "Here are candidate Tweets written from different parts of 
+the same article, one per line with its number. Choose the {count} most powerful Tweets that 
+will inspire conversation, avoiding Tweets that make the same point. Respond with the numbers 
+of the Tweets you chose in JSON format like this: {\"selected\": [0, 3, 5]}
+
+{posts}";
+
+const MAX_TOKENS: usize = 7500;
+
+const CHUNK_OVERLAP_TOKENS: usize = 200;
+
+// How many times we ask the model for a chunk's posts before giving up on the invalid ones
+const MAX_GENERATION_ATTEMPTS: usize = 3;
+
+#[derive(Debug)]
+pub struct Opt {
+    /// The AWS Region.
+    pub region: Option<String>,
+    /// Whether to display additional information.
+    pub verbose: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SuccessResponse {
+    pub body: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FailureResponse {
+    pub body: String,
+}
+
+type WorkerResponse = Result<SuccessResponse, FailureResponse>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ContentType {
+    POST,
+    THREAD
+}
+
+impl Default for ContentType {
+    fn default() -> ContentType {
+        ContentType::POST
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Post {
+    pub post: String,
+    #[serde(default)]
+    pub content_type: ContentType,
+    /// The thread's posts in order, only set for threads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub segments: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_version: Option<String>,
+    /// Quotes we couldn't find in the article, set when they're flagged rather than dropped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unverified_quotes: Option<Vec<String>>
+}
+
+impl Post {
+    pub fn new(post: String) -> Post {
+        Post {
+            post,
+            content_type: ContentType::POST,
+            segments: None,
+            prompt_version: None,
+            unverified_quotes: None
+        }
+    }
+
+    /// A thread is stored as one post whose text is every segment in order
+    pub fn thread(segments: Vec<String>) -> Post {
+        Post {
+            post: segments.join("\n\n"),
+            content_type: ContentType::THREAD,
+            segments: Some(segments),
+            prompt_version: None,
+            unverified_quotes: None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Posts {
+    pub posts: Vec<Post>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Selection {
+    pub selected: Vec<usize>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Article {
+    pub title: String,
+    pub url: String,
+    pub content: String
+}
+
+// The posts we kept plus every post we threw away and why
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Generation {
+    pub posts: Posts,
+    pub rejected: Vec<Rejection>
+}
+
+// Implement Display for the Failure response so that we can then implement Error.
+impl std::fmt::Display for FailureResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.body)
+    }
+}
+
+// Implement Error for the FailureResponse so that we can `?` (try) the Response
+// returned by `lambda_runtime::run(func).await` in `fn main`.
+impl std::error::Error for FailureResponse {}
+
+async fn cleanup(content: String) -> Result<String, FailureResponse> {
+    // Keep paragraph breaks so the content can be chunked on them
+    let block_re = Regex::new(r"(?i)</(p|h[1-6]|li|blockquote|div)>|<br\s*/?>").unwrap();
+    let blocks = block_re.replace_all(content.as_str(), "\n\n");
+
+    // Remove xml tags
+    let re = Regex::new(r"<[^>]*>").unwrap();
+    let cleanup_string = re.replace_all(&blocks, "");
+
+    let bpe = cl100k_base().unwrap();
+    let tokens = bpe.encode_with_special_tokens(&cleanup_string);
+    println!("Token length after cleanup: {}", tokens.len());
+    Ok(cleanup_string.to_string())
+}
+
+/**
+ * Pulls the latest article out of an RSS feed. The content is every CDATA
+ * section in the first item, the title and link come from their own tags.
+ */
+fn parse_newsletter(xml_content: &str) -> Article {
+    let tag_name = "item";
+    let parser = EventReader::new(xml_content.as_bytes());
+    let mut inside_tag = false;
+    let mut current_tag = String::new();
+    let mut article = Article {
+        title: String::new(),
+        url: String::new(),
+        content: String::new()
+    };
+
+    for event in parser {
+        match event {
+            Ok(XmlEvent::StartElement { name, .. }) if name.local_name == tag_name => {
+                inside_tag = true;
+            }
+            Ok(XmlEvent::EndElement { name }) if name.local_name == tag_name => {
+                break;
+            }
+            Ok(XmlEvent::StartElement { name, .. }) if inside_tag => {
+                current_tag = name.local_name;
+            }
+            Ok(XmlEvent::EndElement { .. }) if inside_tag => {
+                current_tag.clear();
+            }
+            Ok(XmlEvent::CData(text)) if inside_tag => {
+                if current_tag == "title" {
+                    article.title.push_str(&text);
+                }
+                article.content.push_str(&text);
+            }
+            Ok(XmlEvent::Characters(text)) if inside_tag => {
+                match current_tag.as_str() {
+                    "title" => article.title.push_str(&text),
+                    "link" => article.url.push_str(&text),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    article.title = article.title.trim().to_string();
+    article.url = article.url.trim().to_string();
+    article
+}
+
+async fn get_current_newsletter_content(url: &str) -> Result<Article, FailureResponse> {
+    // Send a GET request to the URL
+    let response = match get(url).await {
+        Ok(r) => r,
+        Err(e) => {
+            println!("Error reading URL: {:?} {:?}", url, e);
+            return Err(FailureResponse {
+                body: format!("Error reading URL: {}", e)
+            });
+        }
+    };
+
+    // Read the response body into a string
+    let xml_content = match response.text().await {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Error reading URL contents: {:?}", e);
+            return Err(FailureResponse {
+                body: format!("Error reading URL contents: {}", e)
+            });
+        }
+    };
+
+    // Parse XML content
+    let article = parse_newsletter(&xml_content);
+    let bpe = cl100k_base().unwrap();
+    let tokens = bpe.encode_with_special_tokens(&article.content);
+    println!("Article: {} {}", article.title, article.url);
+    println!("Token length before cleanup: {}", tokens.len());
+
+    return Ok(article);
+}
+
+async fn get_table_name() -> Option<String> {
+    env::var("TABLE_NAME").ok()
+}
+
+async fn get_batch_table_name() -> Option<String> {
+    env::var("BATCH_TABLE_NAME").ok()
+}
+
+async fn get_history_table_name() -> Option<String> {
+    env::var("HISTORY_TABLE_NAME").ok()
+}
+
+pub async fn make_config(opt: Opt) -> Result<SdkConfig, Error> {
+    let region_provider = make_region_provider(opt.region);
+
+    println!();
+    if opt.verbose {
+        println!("DynamoDB client version: {}", PKG_VERSION);
+        println!(
+            "Region:                  {}",
+            region_provider.region().await.unwrap().as_ref()
+        );
+        println!();
+    }
+
+    Ok(aws_config::from_env().region(region_provider).load().await)
+}
+
+pub fn make_region_provider(region: Option<String>) -> RegionProviderChain {
+    RegionProviderChain::first_try(region.map(Region::new))
+        .or_default_provider()
+        .or_else(Region::new("us-east-1"))
+}
+
+async fn get_chunk_overlap_tokens() -> usize {
+    env::var("CHUNK_OVERLAP_TOKENS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(CHUNK_OVERLAP_TOKENS)
+}
+
+async fn request_json(provider: &dyn LlmProvider, prompt: String, format: &ResponseFormat) -> Result<String, FailureResponse> {
+    let generated_content = provider.complete(vec![ChatMessage::user(prompt)], Some(format)).await?;
+    match extract_json(&generated_content) {
+        Some(s) => Ok(s),
+        None => {
+            println!("Error parsing JSON contents!");
+            Err(FailureResponse {
+                body: format!("Error parsing JSON contents!")
+            })
+        },
+    }
+}
+
+/**
+ * Map step: asks for candidate posts from a single chunk of the article.
+ * Invalid posts are reported back to the model so it can replace them,
+ * up to MAX_GENERATION_ATTEMPTS times.
+ */
+async fn generate_chunk_posts(provider: &dyn LlmProvider, prompt: &str, content_chunk: String) -> Result<Generation, FailureResponse> {
+    let format = posts_response_format();
+    let mut messages = vec![ChatMessage::user(format!("{} {}", prompt, content_chunk))];
+    let mut posts: Vec<Post> = Vec::new();
+    let mut rejected: Vec<Rejection> = Vec::new();
+
+    for attempt in 1..=MAX_GENERATION_ATTEMPTS {
+        let reply = provider.complete(messages.clone(), Some(&format)).await?;
+        println!("\n\nContent (attempt {}): {:?}\n\n", attempt, reply);
+        let problems = match parse_posts(&reply) {
+            Ok(raw) => {
+                let (valid, invalid) = validate_posts(raw, platform_limit());
+                posts.extend(valid);
+                if invalid.is_empty() {
+                    break;
+                }
+                let problems = describe_rejections(&invalid);
+                rejected.extend(invalid);
+                problems
+            },
+            Err(e) => {
+                println!("Error parsing posts {}", e);
+                e
+            }
+        };
+        messages.push(ChatMessage::assistant(reply));
+        messages.push(ChatMessage::user(REASK_PROMPT
+            .replace("{problems}", &problems)
+            .replace("{max_length}", &platform_limit().to_string())));
+    }
+
+    if posts.is_empty() {
+        return Err(FailureResponse {
+            body: format!("No valid posts generated:\n{}", describe_rejections(&rejected))
+        });
+    }
+    Ok(Generation {
+        posts: Posts { posts },
+        rejected
+    })
+}
+
+fn normalize_post(post: &str) -> String {
+    post.to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/**
+ * Merges the candidates from every chunk, taking one post from each chunk
+ * in turn so no part of the article crowds out the rest, and drops posts
+ * that only differ by case, punctuation or spacing.
+ */
+fn dedupe_posts(candidates: Vec<Vec<Post>>) -> Vec<Post> {
+    let mut seen = std::collections::HashSet::new();
+    let mut merged = Vec::new();
+    let mut iters: Vec<std::vec::IntoIter<Post>> = candidates.into_iter().map(|c| c.into_iter()).collect();
+    loop {
+        let mut any = false;
+        for iter in iters.iter_mut() {
+            if let Some(post) = iter.next() {
+                any = true;
+                let key = normalize_post(&post.post);
+                if !key.is_empty() && seen.insert(key) {
+                    merged.push(post);
+                }
+            }
+        }
+        if !any {
+            break;
+        }
+    }
+    merged
+}
+
+/**
+ * Picks the posts at the given indexes, skipping repeats and anything out of
+ * range, then tops up from the front of the candidates if the model chose
+ * fewer than `count`.
+ */
+fn select_posts(candidates: Vec<Post>, selected: &[usize], count: usize) -> Vec<Post> {
+    let mut order: Vec<usize> = Vec::new();
+    for &i in selected {
+        if i < candidates.len() && !order.contains(&i) && order.len() < count {
+            order.push(i);
+        }
+    }
+    for i in 0..candidates.len() {
+        if order.len() >= count {
+            break;
+        }
+        if !order.contains(&i) {
+            order.push(i);
+        }
+    }
+    let mut slots: Vec<Option<Post>> = candidates.into_iter().map(Some).collect();
+    order.into_iter().filter_map(|i| slots[i].take()).collect()
+}
+
+/**
+ * Reduce step: dedupes the candidates from every chunk and has the model
+ * choose the best `count` of them
+ */
+async fn reduce_posts(provider: &dyn LlmProvider, candidates: Vec<Vec<Post>>, count: usize) -> Posts {
+    let candidates = dedupe_posts(candidates);
+    println!("Unique candidate posts: {}", candidates.len());
+    if candidates.len() <= count {
+        return Posts { posts: candidates };
+    }
+
+    let numbered = candidates.iter()
+        .enumerate()
+        .map(|(i, p)| format!("{}. {}", i, p.post))
+        .collect::<Vec<String>>()
+        .join("\n");
+    let prompt = REDUCE_PROMPT
+        .replace("{count}", &count.to_string())
+        .replace("{posts}", &numbered);
+    let format = ResponseFormat {
+        name: String::from("selection"),
+        schema: selection_schema(),
+    };
+    let selected: Vec<usize> = match request_json(provider, prompt, &format).await {
+        Ok(content) => match serde_json::from_str::<Selection>(&content) {
+            Ok(s) => s.selected,
+            Err(e) => {
+                println!("Error parsing selection JSON {:?}", e);
+                Vec::new()
+            }
+        },
+        Err(e) => {
+            println!("Error selecting posts, keeping the first {}: {}", count, e);
+            Vec::new()
+        }
+    };
+    Posts {
+        posts: select_posts(candidates, &selected, count)
+    }
+}
+
+async fn split_content(contents: &str) -> Vec<String> {
+    // Determine the number of tokens used in our request
+    // If they are more than our max capacity, then
+    // split the contents into chunks on paragraph and
+    // sentence boundaries, otherwise send the full contents
+    let bpe = cl100k_base().unwrap();
+    let tokens = bpe.encode_with_special_tokens(contents);
+    println!("Tokens: {:?}", tokens.len());
+    let config = ChunkConfig {
+        max_tokens: MAX_TOKENS,
+        overlap_tokens: get_chunk_overlap_tokens().await,
+    };
+    let content_chunks = chunk_content(&bpe, contents, config);
+    println!("Chunks: {}", content_chunks.len());
+    content_chunks
+}
+
+async fn generate_posts(provider: &dyn LlmProvider, prompt: &Prompt, contents: String) -> Result<Generation, FailureResponse> {
+    let content_chunks = split_content(&contents).await;
+
+    // Map: generate candidate posts for each chunk on its own
+    let results = join_all(content_chunks.into_iter()
+        .map(|chunk| generate_chunk_posts(provider, &prompt.text, chunk))).await;
+    let mut candidates: Vec<Vec<Post>> = Vec::new();
+    let mut rejected: Vec<Rejection> = Vec::new();
+    let mut last_error = None;
+    for result in results {
+        match result {
+            Ok(g) => {
+                candidates.push(g.posts.posts);
+                rejected.extend(g.rejected);
+            },
+            Err(e) => {
+                println!("Failed generating posts for chunk: {}", e);
+                last_error = Some(e);
+            }
+        }
+    }
+    if candidates.is_empty() {
+        return Err(last_error.unwrap_or(FailureResponse {
+            body: String::from("No content to generate posts from")
+        }));
+    }
+
+    // Reduce: pick the best posts across every chunk
+    Ok(Generation {
+        posts: reduce_posts(provider, candidates, prompt.post_count).await,
+        rejected
+    })
+}
+
+async fn summarize_chunk(provider: &dyn LlmProvider, content_chunk: String) -> Result<String, FailureResponse> {
+    provider.complete(vec![ChatMessage::user(format!("{} {}", SUMMARY_PROMPT, content_chunk))], None).await
+}
+
+/**
+ * Writes one thread summarizing the whole article. Long articles are
+ * summarized chunk by chunk first (map) so the thread can be written from
+ * all of it in a single request (reduce). Invalid threads are sent back
+ * with what was wrong, up to MAX_GENERATION_ATTEMPTS times.
+ */
+async fn generate_thread(provider: &dyn LlmProvider, prompt: &Prompt, contents: String) -> Result<Generation, FailureResponse> {
+    let content_chunks = split_content(&contents).await;
+    let article = if content_chunks.len() > 1 {
+        let summaries = join_all(content_chunks.into_iter()
+            .map(|chunk| summarize_chunk(provider, chunk))).await;
+        let mut parts = Vec::new();
+        for summary in summaries {
+            parts.push(summary?);
+        }
+        parts.join("\n\n")
+    } else {
+        content_chunks.into_iter().next().unwrap_or_default()
+    };
+    if article.trim().is_empty() {
+        return Err(FailureResponse {
+            body: String::from("No content to generate a thread from")
+        });
+    }
+
+    let format = thread_response_format();
+    let mut messages = vec![ChatMessage::user(format!("{} {}", prompt.text, article))];
+    let mut rejected: Vec<Rejection> = Vec::new();
+    for attempt in 1..=MAX_GENERATION_ATTEMPTS {
+        let reply = provider.complete(messages.clone(), Some(&format)).await?;
+        println!("\n\nThread (attempt {}): {:?}\n\n", attempt, reply);
+        let problems = match parse_thread(&reply) {
+            Ok(segments) => match validate_thread(segments, platform_limit()) {
+                Ok(segments) => return Ok(Generation {
+                    posts: Posts {
+                        posts: vec![Post::thread(segments)]
+                    },
+                    rejected
+                }),
+                Err(invalid) => {
+                    let problems = describe_rejections(&invalid);
+                    rejected.extend(invalid);
+                    problems
+                }
+            },
+            Err(e) => {
+                println!("Error parsing thread {}", e);
+                e
+            }
+        };
+        messages.push(ChatMessage::assistant(reply));
+        messages.push(ChatMessage::user(THREAD_REASK_PROMPT
+            .replace("{problems}", &problems)
+            .replace("{min_segments}", &MIN_THREAD_SEGMENTS.to_string())
+            .replace("{max_segments}", &MAX_THREAD_SEGMENTS.to_string())
+            .replace("{max_length}", &platform_limit().to_string())));
+    }
+    Err(FailureResponse {
+        body: format!("No valid thread generated:\n{}", describe_rejections(&rejected))
+    })
+}
+
+
+
+/**
+ * Rewrites each post's hashtags to follow the policy. A post that no
+ * longer fits once the required tags are added is rejected.
+ */
+fn apply_hashtag_policy(posts: Vec<Post>, policy: &HashtagPolicy, source: &str) -> (Vec<Post>, Vec<Rejection>) {
+    let mut kept = Vec::new();
+    let mut rejected = Vec::new();
+    for mut post in posts {
+        let result = match post.segments.take() {
+            Some(segments) => {
+                // Only a thread's last segment carries the required tags
+                let last = segments.len().saturating_sub(1);
+                let segments: Vec<String> = segments.iter().enumerate()
+                    .map(|(i, s)| policy.apply(s, if i == last { Some(source) } else { None }))
+                    .collect();
+                segments.iter()
+                    .try_for_each(|s| validate_post(s, platform_limit()))
+                    .map(|_| {
+                        post.post = segments.join("\n\n");
+                        post.segments = Some(segments);
+                    })
+            },
+            None => {
+                post.post = policy.apply(&post.post, Some(source));
+                validate_post(&post.post, platform_limit())
+            }
+        };
+        match result {
+            Ok(_) => kept.push(post),
+            Err(reason) => rejected.push(Rejection {
+                reason: format!("After applying the hashtag policy: {}", reason),
+                post: post.post,
+            }),
+        }
+    }
+    (kept, rejected)
+}
+
+async fn generate_uuid() -> String {
+    Uuid::new_v4().to_string()
+}
+
+
+/**
+ * Generated posts go in as drafts so nothing is published before someone
+ * has read it.
+ */
+fn to_records(posts: Posts, prompt_version: &str) -> Vec<PostRecord> {
+    posts.posts.into_iter().map(|p| {
+        let mut record = PostRecord::new(p.post, "draft");
+        record.content_type = Some(match p.content_type {
+            ContentType::POST => String::from("POST"),
+            ContentType::THREAD => String::from("THREAD"),
+        });
+        record.segments = p.segments;
+        record.prompt_version = Some(String::from(prompt_version));
+        record.unverified_quotes = p.unverified_quotes;
+        record
+    }).collect()
+}
+
+fn extract_json(json_string: &str) -> Option<String> {
+    // Find the positions of the first opening and closing curly braces
+    let start_pos = json_string.find('{');
+    let end_pos = json_string.rfind('}');
+
+    if let (Some(start), Some(end)) = (start_pos, end_pos) {
+        // Extract the content between the curly braces, including the braces themselves
+        let json_body = &json_string[start..=end];
+        return Some(json_body.trim().to_string());
+    }
+
+    // If no match was found, return None
+    None
+}
+pub async fn runtime_handler(event: LambdaEvent<Value>, db_client: &DbClient, s3_client: &S3Client) -> Result<(), Error> {
+    println!("Event: {:?}", event);
+    // Scheduled runs use the default source and template
+    worker(GenerateRequest::default(), db_client, s3_client).await?;
+    Ok(())
+}
+
+pub async fn http_handler(request: Request, db_client: &DbClient, s3_client: &S3Client) -> Result<Response<String>, Error> {
+    // Only the method and path, the headers carry credentials
+    println!("Request: {} {}", request.method(), request.uri().path());
+    let body = request.body();
+    let generate_request: GenerateRequest = if body.is_empty() {
+        GenerateRequest::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(r) => r,
+            Err(e) => return ApiError::from_body_error(&e).into_response(),
+        }
+    };
+    if let Err(e) = generate_request.validate() {
+        return e.into_response();
+    }
+    match worker(generate_request, db_client, s3_client).await {
+        Ok(s) => respond(200, s),
+        // The worker says what went wrong where it can, anything else is on us
+        Err(e) => match e.downcast::<ApiError>() {
+            Ok(api_error) => api_error.into_response(),
+            Err(e) => ApiError::internal(&format!("Failed: {}", e)).into_response(),
+        },
+    }
+}
+
+async fn worker(request: GenerateRequest, db_client: &DbClient, s3_client: &S3Client) -> Result<String, Error> {
+    // 1. First retrieve the current contents of our newsletters
+    let prompt_config = match PromptConfig::load() {
+        Ok(c) => c,
+        Err(e) => return Err(Box::new(ApiError::config(&e.to_string()))),
+    };
+    let source = match prompt_config.source(request.source.as_deref()) {
+        Ok(s) => s,
+        Err(e) => return Err(Box::new(ApiError::invalid("source", &e.to_string()))),
+    };
+    let article = match get_current_newsletter_content(&source.feed_url).await {
+        Ok(a) => a,
+        Err(e) => return Err(Box::new(ApiError::new(502, "feed_error", &format!("Failed getting content: {}", e)))),
+    };
+    let hashtag_policy = match HashtagPolicy::load() {
+        Ok(p) => p,
+        Err(e) => return Err(Box::new(ApiError::config(&e))),
+    };
+    let moderator = match Moderator::from_env() {
+        Ok(m) => m,
+        Err(e) => return Err(Box::new(ApiError::config(&e))),
+    };
+    // The template, post count and variables all come from the request
+    let prompt = match prompt_config.prompt(source, &request, &article) {
+        Ok(p) => p,
+        Err(e) => return Err(Box::new(ApiError::new(400, "invalid_request", &e.to_string()))),
+    };
+    println!("Prompt {}: {}", prompt.version_id(), prompt.text);
+    let clean_content = cleanup(article.content).await;
+    let provider = match OpenAiCompatibleProvider::from_env() {
+        Ok(p) => p,
+        Err(e) => return Err(Box::new(ApiError::config(&e.to_string()))),
+    };
+    let clean_content = match clean_content {
+        Ok(c) => c,
+        Err(e) => return Err(Box::new(ApiError::internal(&format!("Failed cleaning content: {}", e)))),
+    };
+    // Generate content
+    let generated = match prompt.content_type {
+        ContentType::POST => generate_posts(&provider, &prompt, clean_content.clone()).await,
+        ContentType::THREAD => generate_thread(&provider, &prompt, clean_content.clone()).await,
+    };
+    let mut generation = match generated {
+        Ok(g) => g,
+        Err(e) => return Err(Box::new(ApiError::new(502, "generation_error", &format!("Failed generating posts: {}", e)))),
+    };
+    // Keep the hashtags to the ones we want
+    let (posts, off_policy) = apply_hashtag_policy(generation.posts.posts, &hashtag_policy, &source.name);
+    generation.posts.posts = posts;
+    generation.rejected.extend(off_policy);
+    // Make sure every quote is actually from the article
+    let (posts, misquoted) = check_quotes(generation.posts.posts, &clean_content, QuoteCheckConfig::from_env());
+    generation.posts.posts = posts;
+    generation.rejected.extend(misquoted);
+    if !generation.rejected.is_empty() {
+        println!("Rejected posts:\n{}", describe_rejections(&generation.rejected));
+    }
+    let flagged = generation.posts.posts.iter().filter(|p| p.unverified_quotes.is_some()).count();
+    let records = to_records(generation.posts, &prompt.version_id());
+
+    // 2. Write the batch and its posts straight to the tables
+    let (table_name, batch_table_name) = match (get_table_name().await, get_batch_table_name().await) {
+        (Some(t), Some(b)) => (t, b),
+        _ => return Err(Box::new(ApiError::config("TABLE_NAME and BATCH_TABLE_NAME must be set"))),
+    };
+    let duplicate_config = DuplicateConfig::from_env();
+    let history_table = get_history_table_name().await;
+    let known = load_known_posts(db_client, &table_name, history_table.as_deref(), duplicate_config).await;
+    let (mut records, duplicates) = filter_duplicates(records, known, duplicate_config);
+    // Anything that fails moderation is stored quarantined rather than as a draft
+    let quarantined = moderator.moderate_records(&mut records).await;
+
+    let batch = GenerationBatch::new(source.name.clone(), source.feed_url.clone(), article.title.clone(), article.url.clone(), prompt.version_id());
+    // Illustrate the posts if we've been asked to
+    let image_config = ImageConfig::from_env();
+    let illustrated = match image_config.mode {
+        ImageMode::Off => 0,
+        _ => add_images(&provider, s3_client, &image_config, &batch, &clean_content, &mut records).await,
+    };
+    if let Err(e) = write_generation(db_client, &table_name, &batch_table_name, &batch, &mut records).await {
+        // Log what we generated so a failed write can be recovered by hand
+        println!("Unsaved posts: {}", serde_json::to_string(&records).unwrap());
+        return Err(Box::new(e));
+    }
+    Ok(json!({
+        "added": records.len(),
+        "batch_id": batch.uuid,
+        "flagged": flagged,
+        "quarantined": quarantined,
+        "illustrated": illustrated,
+        "prompt_version": prompt.version_id(),
+        "rejected": generation.rejected,
+        "duplicates": duplicates
+    }).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    #[test]
+    fn test_get_newsletter_content() {
+        let url = "https://davidjmeyer.substack.com/feed";
+
+        let response = aw!(get_current_newsletter_content(url)).unwrap().content;
+
+        // println!("Response: {:?}", response);
+
+        let cleanup = aw!(cleanup(response)).unwrap();
+        println!("Response: {:?}", cleanup);
+    }
+
+    #[test]
+    fn test_generate_posts() {
+        dotenv::from_filename("../../.env").ok();
+        let url = "https://davidjmeyer.substack.com/feed";
+        let article = aw!(get_current_newsletter_content(url)).unwrap();
+        let config = PromptConfig::load().unwrap();
+        let prompt = config.prompt(config.source(None).unwrap(), &GenerateRequest::default(), &article).unwrap();
+        let clean_content = aw!(cleanup(article.content)).unwrap();
+        let provider = OpenAiCompatibleProvider::from_env().unwrap();
+        let posts = aw!(generate_posts(&provider, &prompt, clean_content));
+        println!("Posts: {:?}", posts);
+    }
+
+    #[test]
+    fn test_generate_posts_with_fake_provider() {
+        let map = r#"Sure! {"posts": [{"post": "Run with joy!"}, {"post": "Rest is training too."}]}"#;
+        let provider = provider::FakeProvider::new(vec![map]);
+        let generation = aw!(generate_posts(&provider, &prompt(), String::from("Run with joy. Rest is training too."))).unwrap();
+        assert_eq!(generation.posts.posts, vec![post("Run with joy!"), post("Rest is training too.")]);
+        assert!(generation.rejected.is_empty());
+        let requests = provider.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0][0].content.starts_with(&prompt().text));
+    }
+
+    #[test]
+    fn test_generate_posts_reasks_for_invalid_posts() {
+        let first = r#"{"posts": [{"post": "Run with joy!"}, {"post": "Sign up at [insert link]"}]}"#;
+        let second = r#"{"posts": [{"post": "Rest is training too."}]}"#;
+        let provider = provider::FakeProvider::new(vec!["not json", first, second]);
+        let generation = aw!(generate_posts(&provider, &prompt(), String::from("Run with joy."))).unwrap();
+        assert_eq!(generation.posts.posts, vec![post("Run with joy!"), post("Rest is training too.")]);
+        assert_eq!(generation.rejected.len(), 1);
+        assert_eq!(generation.rejected[0].post, "Sign up at [insert link]");
+
+        // Each re-ask carries the conversation so far plus what was wrong
+        let requests = provider.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].len(), 5);
+        assert_eq!(requests[2][3].role, "assistant");
+        assert!(requests[2][4].content.contains("placeholder"));
+    }
+
+    #[test]
+    fn test_generate_posts_fails_without_valid_posts() {
+        let provider = provider::FakeProvider::new(vec![r#"{"posts": [{"post": "<str>"}]}"#]);
+        assert!(aw!(generate_posts(&provider, &prompt(), String::from("Run with joy."))).is_err());
+        assert_eq!(provider.requests.lock().unwrap().len(), MAX_GENERATION_ATTEMPTS);
+    }
+
+    fn post(s: &str) -> Post {
+        Post::new(String::from(s))
+    }
+
+    fn prompt() -> Prompt {
+        Prompt {
+            template: String::from("test"),
+            version: 1,
+            text: String::from("Write Tweets about this article."),
+            post_count: 12,
+            content_type: ContentType::POST
+        }
+    }
+
+    #[test]
+    fn test_generate_thread() {
+        let too_short = r#"{"segments": ["Run with joy.", "Rest."]}"#;
+        let thread = r#"{"segments": ["1. Run with joy.", "2. Rest is training too.", "3. Repeat tomorrow."]}"#;
+        let provider = provider::FakeProvider::new(vec![too_short, thread]);
+        let generation = aw!(generate_thread(&provider, &prompt(), String::from("Run with joy. Rest is training too."))).unwrap();
+        assert_eq!(generation.posts.posts.len(), 1);
+        let post = &generation.posts.posts[0];
+        assert_eq!(post.content_type, ContentType::THREAD);
+        assert_eq!(post.segments, Some(vec![
+            String::from("Run with joy."),
+            String::from("Rest is training too."),
+            String::from("Repeat tomorrow.")
+        ]));
+        assert_eq!(post.post, "Run with joy.\n\nRest is training too.\n\nRepeat tomorrow.");
+        assert_eq!(generation.rejected.len(), 1);
+        assert!(provider.requests.lock().unwrap()[1][2].content.contains("2 segments"));
+    }
+
+    #[test]
+    fn test_apply_hashtag_policy() {
+        let mut policy = HashtagPolicy::load().unwrap();
+        policy.required.insert(String::from("substack"), vec![String::from("#RunWithJoy")]);
+        let posts = vec![
+            post("A #HybridAthlete trains hard. #FitnessLife #Running"),
+            post(&format!("{} #Running", "a".repeat(275))),
+            Post::thread(vec![String::from("One #Running"), String::from("Two."), String::from("Three.")]),
+        ];
+        let (kept, rejected) = apply_hashtag_policy(posts, &policy, "substack");
+        assert_eq!(kept.len(), 2);
+        assert_eq!(kept[0].post, "A HybridAthlete trains hard. #RunWithJoy #HybridAthlete");
+        assert_eq!(kept[1].segments.as_ref().unwrap()[2], "Three. #RunWithJoy");
+        assert_eq!(kept[1].post, "One #Running\n\nTwo.\n\nThree. #RunWithJoy");
+        // Adding the required tag takes it over the limit
+        assert_eq!(rejected.len(), 1);
+        assert!(rejected[0].reason.contains("the limit is 280"));
+    }
+
+    #[test]
+    fn test_parse_newsletter() {
+        let xml = r#"<rss><channel><title>Newsletter</title>
+            <item>
+                <title><![CDATA[Run With Joy]]></title>
+                <link>https://davidjmeyer.substack.com/p/run-with-joy</link>
+                <content:encoded xmlns:content="http://purl.org/rss/1.0/modules/content/"><![CDATA[<p>Rest is training too.</p>]]></content:encoded>
+            </item>
+            <item><title><![CDATA[Older]]></title></item>
+        </channel></rss>"#;
+        let article = parse_newsletter(xml);
+        assert_eq!(article.title, "Run With Joy");
+        assert_eq!(article.url, "https://davidjmeyer.substack.com/p/run-with-joy");
+        assert_eq!(article.content, "Run With Joy<p>Rest is training too.</p>");
+    }
+
+    #[test]
+    fn test_dedupe_posts() {
+        let candidates = vec![
+            vec![post("Run with joy!"), post("Strength builds endurance.")],
+            vec![post("run with  JOY"), post("Rest is training too.")],
+            vec![post("Strength builds endurance")],
+        ];
+        let posts = dedupe_posts(candidates);
+        assert_eq!(posts, vec![post("Run with joy!"), post("Strength builds endurance"), post("Rest is training too.")]);
+    }
+
+    #[test]
+    fn test_select_posts() {
+        let candidates = vec![post("a"), post("b"), post("c"), post("d")];
+        // Repeats and out of range picks are ignored and the rest is filled in order
+        let posts = select_posts(candidates, &[2, 2, 9], 3);
+        assert_eq!(posts, vec![post("c"), post("a"), post("b")]);
+    }
+
+}
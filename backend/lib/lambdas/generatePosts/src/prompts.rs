@@ -0,0 +1,274 @@
+use regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::env;
+use shared::http::ApiError;
+
+use super::{Article, ContentType, FailureResponse};
+
+// Shipped with the lambda, PROMPT_CONFIG overrides it without a redeploy of the code
+const DEFAULT_PROMPT_CONFIG: &str = include_str!("../config/prompts.json");
+
+// How many posts we keep from each article unless the request asks for more or fewer
+pub const DEFAULT_POST_COUNT: usize = 12;
+
+// The most a request can ask for, more than this won't fit in one response
+pub const MAX_POST_COUNT: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PromptTemplate {
+    /// Bump whenever the template text changes so posts can be traced back to it.
+    pub version: u32,
+    pub template: String,
+    /// Default values for the template's variables.
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Source {
+    pub name: String,
+    pub feed_url: String,
+    /// Template to use for this source instead of the default.
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Template to use for this source's threads instead of the default.
+    #[serde(default)]
+    pub thread_template: Option<String>,
+    /// Overrides the template's variables for this source.
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+}
+
+fn default_thread_template() -> String {
+    String::from("thread")
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PromptConfig {
+    pub default_template: String,
+    #[serde(default = "default_thread_template")]
+    pub thread_template: String,
+    pub templates: HashMap<String, PromptTemplate>,
+    pub sources: Vec<Source>,
+}
+
+/**
+ * What the caller of /generate can choose. Everything is optional and
+ * falls back to the source and template defaults.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GenerateRequest {
+    #[serde(default)]
+    pub source: Option<String>,
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default)]
+    pub content_type: ContentType,
+    #[serde(default)]
+    pub post_count: Option<usize>,
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+}
+
+impl GenerateRequest {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        match self.post_count {
+            Some(count) if !(1..=MAX_POST_COUNT).contains(&count) => Err(ApiError::invalid("post_count", &format!("post_count has to be between 1 and {}", MAX_POST_COUNT))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/**
+ * A rendered prompt and where it came from
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prompt {
+    pub template: String,
+    pub version: u32,
+    pub text: String,
+    pub post_count: usize,
+    pub content_type: ContentType,
+}
+
+impl Prompt {
+    /// Recorded on every post so we know which prompt produced it, e.g. "tweets@v1".
+    pub fn version_id(&self) -> String {
+        format!("{}@v{}", self.template, self.version)
+    }
+}
+
+/**
+ * Replaces every {variable} in the template. Fails on variables we don't
+ * have a value for rather than sending the model a half-filled prompt.
+ */
+pub fn render(template: &str, variables: &HashMap<String, String>) -> Result<String, String> {
+    let re = Regex::new(r"\{([a-z_]+)\}").unwrap();
+    let mut missing = Vec::new();
+    let rendered = re.replace_all(template, |caps: &regex::Captures| {
+        match variables.get(&caps[1]) {
+            Some(v) => v.clone(),
+            None => {
+                missing.push(caps[1].to_string());
+                caps[0].to_string()
+            }
+        }
+    }).to_string();
+    if !missing.is_empty() {
+        return Err(format!("Missing prompt variables: {}", missing.join(", ")));
+    }
+    Ok(rendered)
+}
+
+impl PromptConfig {
+    pub fn load() -> Result<PromptConfig, FailureResponse> {
+        let raw = env::var("PROMPT_CONFIG").unwrap_or(String::from(DEFAULT_PROMPT_CONFIG));
+        match serde_json::from_str(&raw) {
+            Ok(c) => Ok(c),
+            Err(e) => Err(FailureResponse {
+                body: format!("Error parsing prompt config: {}", e)
+            }),
+        }
+    }
+
+    /// The named source, or the first one configured.
+    pub fn source(&self, name: Option<&str>) -> Result<&Source, FailureResponse> {
+        let source = match name {
+            Some(n) => self.sources.iter().find(|s| s.name == n),
+            None => self.sources.first(),
+        };
+        source.ok_or(FailureResponse {
+            body: format!("Unknown source: {}", name.unwrap_or("none configured"))
+        })
+    }
+
+    /**
+     * Picks the template (request, then source, then default, for the
+     * requested content type) and fills it
+     * in. Variables are layered the same way: template defaults, then the
+     * source's, then the request's, with the article's title and URL and
+     * the post count always set by us.
+     */
+    pub fn prompt(&self, source: &Source, request: &GenerateRequest, article: &Article) -> Result<Prompt, FailureResponse> {
+        let name = match (&request.template, request.content_type) {
+            (Some(t), _) => t.clone(),
+            (None, ContentType::POST) => source.template.clone().unwrap_or(self.default_template.clone()),
+            (None, ContentType::THREAD) => source.thread_template.clone().unwrap_or(self.thread_template.clone()),
+        };
+        let template = match self.templates.get(&name) {
+            Some(t) => t,
+            None => return Err(FailureResponse {
+                body: format!("Unknown prompt template: {}", name)
+            }),
+        };
+        let post_count = request.post_count.unwrap_or(DEFAULT_POST_COUNT);
+
+        let mut variables = template.variables.clone();
+        variables.extend(source.variables.clone());
+        variables.extend(request.variables.clone());
+        variables.insert(String::from("article_title"), article.title.clone());
+        variables.insert(String::from("article_url"), article.url.clone());
+        variables.insert(String::from("post_count"), post_count.to_string());
+
+        match render(&template.template, &variables) {
+            Ok(text) => Ok(Prompt {
+                template: name,
+                version: template.version,
+                text,
+                post_count,
+                content_type: request.content_type,
+            }),
+            Err(e) => Err(FailureResponse {
+                body: e
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn article() -> Article {
+        Article {
+            title: String::from("Run With Joy"),
+            url: String::from("https://davidjmeyer.substack.com/p/run-with-joy"),
+            content: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let mut variables = HashMap::new();
+        variables.insert(String::from("tone"), String::from("calm"));
+        let rendered = render("Be {tone}. {\"posts\": []}", &variables).unwrap();
+        assert_eq!(rendered, "Be calm. {\"posts\": []}");
+        assert_eq!(render("Be {tone} for {audience}", &variables).unwrap_err(), "Missing prompt variables: audience");
+    }
+
+    #[test]
+    fn test_default_config_renders() {
+        let config: PromptConfig = serde_json::from_str(DEFAULT_PROMPT_CONFIG).unwrap();
+        let source = config.source(None).unwrap();
+        let prompt = config.prompt(source, &GenerateRequest::default(), &article()).unwrap();
+        assert_eq!(prompt.version_id(), "tweets@v1");
+        assert_eq!(prompt.post_count, DEFAULT_POST_COUNT);
+        assert!(prompt.text.starts_with("Create 12 powerful short Tweets"));
+        assert!(prompt.text.contains("\"Run With Joy\""));
+
+        let request = GenerateRequest {
+            content_type: ContentType::THREAD,
+            ..GenerateRequest::default()
+        };
+        let prompt = config.prompt(source, &request, &article()).unwrap();
+        assert_eq!(prompt.version_id(), "thread@v1");
+        assert_eq!(prompt.content_type, ContentType::THREAD);
+    }
+
+    #[test]
+    fn test_request_overrides_source_and_template() {
+        let mut config: PromptConfig = serde_json::from_str(DEFAULT_PROMPT_CONFIG).unwrap();
+        config.templates.insert(String::from("short"), PromptTemplate {
+            version: 3,
+            template: String::from("{post_count} posts in a {tone} tone about {article_url}"),
+            variables: HashMap::from([(String::from("tone"), String::from("calm"))]),
+        });
+        config.sources[0].template = Some(String::from("short"));
+        config.sources[0].variables.insert(String::from("tone"), String::from("playful"));
+        let source = config.source(Some("substack")).unwrap();
+
+        let prompt = config.prompt(source, &GenerateRequest::default(), &article()).unwrap();
+        assert_eq!(prompt.text, "12 posts in a playful tone about https://davidjmeyer.substack.com/p/run-with-joy");
+        assert_eq!(prompt.version_id(), "short@v3");
+
+        let request = GenerateRequest {
+            post_count: Some(5),
+            variables: HashMap::from([(String::from("tone"), String::from("serious"))]),
+            ..GenerateRequest::default()
+        };
+        let prompt = config.prompt(source, &request, &article()).unwrap();
+        assert_eq!(prompt.text, "5 posts in a serious tone about https://davidjmeyer.substack.com/p/run-with-joy");
+
+        let request = GenerateRequest {
+            template: Some(String::from("missing")),
+            ..GenerateRequest::default()
+        };
+        assert!(config.prompt(source, &request, &article()).is_err());
+        assert!(config.source(Some("missing")).is_err());
+    }
+
+    #[test]
+    fn test_post_count_bounds() {
+        assert!(GenerateRequest::default().validate().is_ok());
+        let request = |count| GenerateRequest {
+            post_count: Some(count),
+            ..GenerateRequest::default()
+        };
+        assert!(request(1).validate().is_ok());
+        assert!(request(MAX_POST_COUNT).validate().is_ok());
+        assert_eq!(request(0).validate().unwrap_err().status, 400);
+        assert_eq!(request(MAX_POST_COUNT + 1).validate().unwrap_err().status, 400);
+    }
+}
@@ -0,0 +1,393 @@
+use async_trait::async_trait;
+use base64::Engine;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::env;
+
+use super::FailureResponse;
+
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+// The default json_schema response format needs structured outputs, which older models like gpt-4 reject
+const DEFAULT_MODEL: &str = "gpt-4o-2024-08-06";
+const DEFAULT_IMAGE_MODEL: &str = "dall-e-3";
+const DEFAULT_IMAGE_SIZE: &str = "1024x1024";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn user(content: String) -> ChatMessage {
+        ChatMessage {
+            role: String::from("user"),
+            content,
+        }
+    }
+
+    pub fn assistant(content: String) -> ChatMessage {
+        ChatMessage {
+            role: String::from("assistant"),
+            content,
+        }
+    }
+}
+
+/**
+ * A JSON schema the reply has to follow
+ */
+#[derive(Debug, Clone)]
+pub struct ResponseFormat {
+    pub name: String,
+    pub schema: Value,
+}
+
+/**
+ * How we ask an OpenAI-compatible server for JSON. Not every local server
+ * supports schemas, so they can fall back to plain JSON mode or nothing.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResponseFormatMode {
+    JsonSchema,
+    JsonObject,
+    Text,
+}
+
+impl std::str::FromStr for ResponseFormatMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ResponseFormatMode, String> {
+        match s {
+            "json_schema" => Ok(ResponseFormatMode::JsonSchema),
+            "json_object" => Ok(ResponseFormatMode::JsonObject),
+            "text" | "none" => Ok(ResponseFormatMode::Text),
+            _ => Err(format!("Unknown response format {}", s)),
+        }
+    }
+}
+
+/**
+ * Anything that can turn a conversation into a reply. Generation only
+ * talks to the model through this so we can swap in a local server or a
+ * fake without touching the rest of the lambda.
+ */
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    async fn complete(&self, messages: Vec<ChatMessage>, format: Option<&ResponseFormat>) -> Result<String, FailureResponse>;
+
+    /// Draws an image from a prompt, returning the PNG's bytes.
+    async fn generate_image(&self, prompt: &str) -> Result<Vec<u8>, FailureResponse>;
+}
+
+/**
+ * Talks to any server that implements the OpenAI chat completions API:
+ * OpenAI itself, a llama.cpp or Ollama server, or a mock.
+ */
+#[derive(Debug, Clone)]
+pub struct OpenAiCompatibleProvider {
+    pub base_url: String,
+    pub api_key: Option<String>,
+    pub model: String,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub response_format_mode: ResponseFormatMode,
+    pub image_model: String,
+    pub image_size: String,
+    client: reqwest::Client,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatCompletionResponse {
+    choices: Vec<ChatCompletionChoice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatCompletionChoice {
+    message: ChatCompletionMessage,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatCompletionMessage {
+    content: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ImageResponse {
+    data: Vec<ImageData>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ImageData {
+    b64_json: Option<String>,
+}
+
+fn parse_env<T: std::str::FromStr>(key: &str) -> Option<T> {
+    env::var(key).ok().and_then(|v| v.parse().ok())
+}
+
+impl OpenAiCompatibleProvider {
+    pub fn new(base_url: String, api_key: Option<String>, model: String) -> OpenAiCompatibleProvider {
+        OpenAiCompatibleProvider {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            model,
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
+            response_format_mode: ResponseFormatMode::JsonSchema,
+            image_model: String::from(DEFAULT_IMAGE_MODEL),
+            image_size: String::from(DEFAULT_IMAGE_SIZE),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /**
+     * Configuration:
+     * LLM_BASE_URL: defaults to OpenAI
+     * LLM_MODEL: defaults to gpt-4o-2024-08-06, set LLM_RESPONSE_FORMAT to match for models without structured outputs
+     * OPEN_AI_API_KEY: required for OpenAI, optional for local servers
+     * LLM_TEMPERATURE, LLM_TOP_P, LLM_MAX_TOKENS: optional
+     * LLM_RESPONSE_FORMAT: json_schema (default), json_object or text
+     * IMAGE_MODEL: defaults to dall-e-3
+     * IMAGE_SIZE: defaults to 1024x1024
+     */
+    pub fn from_env() -> Result<OpenAiCompatibleProvider, FailureResponse> {
+        let base_url = env::var("LLM_BASE_URL").unwrap_or(String::from(DEFAULT_BASE_URL));
+        let model = env::var("LLM_MODEL").unwrap_or(String::from(DEFAULT_MODEL));
+        let api_key = env::var("OPEN_AI_API_KEY").ok();
+        if api_key.is_none() && base_url.trim_end_matches('/') == DEFAULT_BASE_URL {
+            return Err(FailureResponse {
+                body: String::from("API Key Not Set")
+            });
+        }
+        let mut provider = OpenAiCompatibleProvider::new(base_url, api_key, model);
+        provider.temperature = parse_env("LLM_TEMPERATURE");
+        provider.top_p = parse_env("LLM_TOP_P");
+        provider.max_tokens = parse_env("LLM_MAX_TOKENS");
+        if let Some(mode) = parse_env("LLM_RESPONSE_FORMAT") {
+            provider.response_format_mode = mode;
+        }
+        if let Ok(model) = env::var("IMAGE_MODEL") {
+            provider.image_model = model;
+        }
+        if let Ok(size) = env::var("IMAGE_SIZE") {
+            provider.image_size = size;
+        }
+        Ok(provider)
+    }
+
+    fn request_body(&self, messages: &[ChatMessage], format: Option<&ResponseFormat>) -> Value {
+        let mut body = json!({
+            "model": self.model,
+            "messages": messages,
+        });
+        if let Some(t) = self.temperature {
+            body["temperature"] = json!(t);
+        }
+        if let Some(p) = self.top_p {
+            body["top_p"] = json!(p);
+        }
+        if let Some(m) = self.max_tokens {
+            body["max_tokens"] = json!(m);
+        }
+        if let Some(f) = format {
+            match self.response_format_mode {
+                ResponseFormatMode::JsonSchema => {
+                    body["response_format"] = json!({
+                        "type": "json_schema",
+                        "json_schema": {
+                            "name": f.name,
+                            "strict": true,
+                            "schema": f.schema,
+                        }
+                    });
+                },
+                ResponseFormatMode::JsonObject => {
+                    body["response_format"] = json!({ "type": "json_object" });
+                },
+                ResponseFormatMode::Text => {},
+            }
+        }
+        body
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiCompatibleProvider {
+    async fn complete(&self, messages: Vec<ChatMessage>, format: Option<&ResponseFormat>) -> Result<String, FailureResponse> {
+        let uri = format!("{}/chat/completions", self.base_url);
+        let mut request = self.client.post(&uri).json(&self.request_body(&messages, format));
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let response = match request.send().await {
+            Ok(r) => r,
+            Err(e) => {
+                println!("Error calling {}: {:?}", uri, e);
+                return Err(FailureResponse {
+                    body: format!("Error getting response from {}: {}", self.model, e)
+                });
+            }
+        };
+        let status = response.status();
+        let raw = response.text().await.unwrap_or_default();
+        if !status.is_success() {
+            println!("Error from {}: {} {}", uri, status, raw);
+            return Err(FailureResponse {
+                body: format!("Error getting response from {}: {} {}", self.model, status, raw)
+            });
+        }
+        let completion: ChatCompletionResponse = match serde_json::from_str(&raw) {
+            Ok(c) => c,
+            Err(e) => {
+                println!("Error parsing completion {:?}: {}", e, raw);
+                return Err(FailureResponse {
+                    body: format!("Error parsing completion: {}", e)
+                });
+            }
+        };
+        match completion.choices.into_iter().next().and_then(|c| c.message.content) {
+            Some(c) => Ok(c),
+            None => Err(FailureResponse {
+                body: String::from("Could not get message content")
+            }),
+        }
+    }
+
+    async fn generate_image(&self, prompt: &str) -> Result<Vec<u8>, FailureResponse> {
+        let uri = format!("{}/images/generations", self.base_url);
+        let mut request = self.client.post(&uri).json(&json!({
+            "model": self.image_model,
+            "prompt": prompt,
+            "n": 1,
+            "size": self.image_size,
+            "response_format": "b64_json",
+        }));
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let response = match request.send().await {
+            Ok(r) => r,
+            Err(e) => {
+                println!("Error calling {}: {:?}", uri, e);
+                return Err(FailureResponse {
+                    body: format!("Error getting image from {}: {}", self.image_model, e)
+                });
+            }
+        };
+        let status = response.status();
+        let raw = response.text().await.unwrap_or_default();
+        if !status.is_success() {
+            println!("Error from {}: {} {}", uri, status, raw);
+            return Err(FailureResponse {
+                body: format!("Error getting image from {}: {}", self.image_model, status)
+            });
+        }
+        let image: ImageResponse = match serde_json::from_str(&raw) {
+            Ok(i) => i,
+            Err(e) => return Err(FailureResponse {
+                body: format!("Error parsing image response: {}", e)
+            }),
+        };
+        let encoded = match image.data.into_iter().next().and_then(|d| d.b64_json) {
+            Some(e) => e,
+            None => return Err(FailureResponse {
+                body: String::from("Could not get image data")
+            }),
+        };
+        base64::engine::general_purpose::STANDARD.decode(encoded).map_err(|e| FailureResponse {
+            body: format!("Error decoding image: {}", e)
+        })
+    }
+}
+
+/**
+ * Replies with canned responses in order, repeating the last one once it
+ * runs out, and remembers every conversation it was sent.
+ */
+#[cfg(test)]
+pub struct FakeProvider {
+    responses: Vec<String>,
+    pub requests: std::sync::Mutex<Vec<Vec<ChatMessage>>>,
+}
+
+#[cfg(test)]
+impl FakeProvider {
+    pub fn new(responses: Vec<&str>) -> FakeProvider {
+        FakeProvider {
+            responses: responses.into_iter().map(String::from).collect(),
+            requests: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl LlmProvider for FakeProvider {
+    async fn complete(&self, messages: Vec<ChatMessage>, _format: Option<&ResponseFormat>) -> Result<String, FailureResponse> {
+        let mut requests = self.requests.lock().unwrap();
+        requests.push(messages);
+        let index = (requests.len() - 1).min(self.responses.len().saturating_sub(1));
+        match self.responses.get(index) {
+            Some(r) => Ok(r.clone()),
+            None => Err(FailureResponse {
+                body: String::from("FakeProvider has no responses")
+            }),
+        }
+    }
+
+    async fn generate_image(&self, prompt: &str) -> Result<Vec<u8>, FailureResponse> {
+        // The "image" is the prompt it was drawn from, so tests can check it
+        Ok(prompt.as_bytes().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_body() {
+        let mut provider = OpenAiCompatibleProvider::new(
+            String::from("http://localhost:11434/v1/"),
+            None,
+            String::from("llama3"),
+        );
+        provider.temperature = Some(0.5);
+        let body = provider.request_body(&[ChatMessage::user(String::from("hello"))], None);
+        assert_eq!(provider.base_url, "http://localhost:11434/v1");
+        assert_eq!(body["model"], "llama3");
+        assert_eq!(body["messages"][0]["role"], "user");
+        assert_eq!(body["temperature"], 0.5);
+        assert!(body.get("max_tokens").is_none());
+        assert!(body.get("response_format").is_none());
+    }
+
+    #[test]
+    fn test_request_body_response_format() {
+        let mut provider = OpenAiCompatibleProvider::new(
+            String::from(DEFAULT_BASE_URL),
+            Some(String::from("key")),
+            String::from(DEFAULT_MODEL),
+        );
+        let format = ResponseFormat {
+            name: String::from("posts"),
+            schema: json!({ "type": "object" }),
+        };
+        let messages = [ChatMessage::user(String::from("hello"))];
+        let body = provider.request_body(&messages, Some(&format));
+        assert_eq!(body["response_format"]["type"], "json_schema");
+        assert_eq!(body["response_format"]["json_schema"]["name"], "posts");
+        assert_eq!(body["response_format"]["json_schema"]["strict"], true);
+
+        provider.response_format_mode = ResponseFormatMode::JsonObject;
+        let body = provider.request_body(&messages, Some(&format));
+        assert_eq!(body["response_format"], json!({ "type": "json_object" }));
+
+        provider.response_format_mode = ResponseFormatMode::Text;
+        assert!(provider.request_body(&messages, Some(&format)).get("response_format").is_none());
+    }
+}
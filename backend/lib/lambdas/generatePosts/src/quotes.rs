@@ -0,0 +1,213 @@
+use regex::Regex;
+use std::env;
+
+use super::validation::Rejection;
+use super::Post;
+
+// Quotes shorter than this are usually scare quotes or titles, not quotes from the article
+const MIN_QUOTE_WORDS: usize = 3;
+
+// How much of a quote has to be found, in order, in the article to count as real
+const DEFAULT_MATCH_THRESHOLD: f64 = 0.85;
+
+/**
+ * What to do with a post whose quotes aren't in the article. Flagged posts
+ * are kept with their unverified quotes recorded so they can be reviewed.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuoteCheckMode {
+    Drop,
+    Flag,
+    Off,
+}
+
+impl std::str::FromStr for QuoteCheckMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<QuoteCheckMode, String> {
+        match s {
+            "drop" => Ok(QuoteCheckMode::Drop),
+            "flag" => Ok(QuoteCheckMode::Flag),
+            "off" | "none" => Ok(QuoteCheckMode::Off),
+            _ => Err(format!("Unknown quote check mode {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QuoteCheckConfig {
+    pub mode: QuoteCheckMode,
+    pub threshold: f64,
+}
+
+impl QuoteCheckConfig {
+    /**
+     * Configuration:
+     * QUOTE_CHECK: drop (default), flag or off
+     * QUOTE_MATCH_THRESHOLD: 0 to 1, defaults to 0.85
+     */
+    pub fn from_env() -> QuoteCheckConfig {
+        QuoteCheckConfig {
+            mode: env::var("QUOTE_CHECK").ok()
+                .and_then(|m| m.parse().ok())
+                .unwrap_or(QuoteCheckMode::Drop),
+            threshold: env::var("QUOTE_MATCH_THRESHOLD").ok()
+                .and_then(|t| t.parse().ok())
+                .unwrap_or(DEFAULT_MATCH_THRESHOLD),
+        }
+    }
+}
+
+/**
+ * Lowercases and splits text into words, ignoring punctuation so curly
+ * quotes, dashes and apostrophes don't stop a quote from matching.
+ */
+pub fn normalize_words(text: &str) -> Vec<String> {
+    text.chars()
+        .filter(|c| !matches!(c, '\'' | '’'))
+        .map(|c| if c.is_alphanumeric() { c.to_ascii_lowercase() } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .map(String::from)
+        .collect()
+}
+
+/// Every span in straight or curly double quotes long enough to be a real quote.
+pub fn extract_quotes(post: &str) -> Vec<String> {
+    let re = Regex::new(r#""([^"]+)"|“([^”]+)”"#).unwrap();
+    re.captures_iter(post)
+        .filter_map(|caps| caps.get(1).or(caps.get(2)))
+        .map(|m| m.as_str().trim().to_string())
+        .filter(|q| normalize_words(q).len() >= MIN_QUOTE_WORDS)
+        .collect()
+}
+
+fn common_subsequence(a: &[String], b: &[String]) -> usize {
+    let mut previous = vec![0; b.len() + 1];
+    for x in a {
+        let mut current = vec![0; b.len() + 1];
+        for (j, y) in b.iter().enumerate() {
+            current[j + 1] = if x == y {
+                previous[j] + 1
+            } else {
+                current[j].max(previous[j + 1])
+            };
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/**
+ * How much of the quote appears in the article: the share of its words
+ * found in order in the best matching passage. Passages are a little
+ * longer than the quote so a few inserted words don't break the match.
+ */
+pub fn match_score(quote: &[String], article: &[String]) -> f64 {
+    if quote.is_empty() {
+        return 1.0;
+    }
+    let window = quote.len() + quote.len() / 4 + 1;
+    let mut best = 0;
+    for start in 0..article.len() {
+        // A passage can only match well if it starts on one of the quote's words
+        if !quote.contains(&article[start]) {
+            continue;
+        }
+        let end = (start + window).min(article.len());
+        best = best.max(common_subsequence(quote, &article[start..end]));
+        if best == quote.len() {
+            break;
+        }
+    }
+    best as f64 / quote.len() as f64
+}
+
+/// The post's quotes that can't be found in the article.
+pub fn unverified_quotes(post: &str, article: &[String], threshold: f64) -> Vec<String> {
+    extract_quotes(post)
+        .into_iter()
+        .filter(|q| match_score(&normalize_words(q), article) < threshold)
+        .collect()
+}
+
+/**
+ * Checks every quote in every post against the article. Depending on the
+ * mode, posts with quotes we can't find are rejected or kept and flagged.
+ */
+pub fn check_quotes(posts: Vec<Post>, article: &str, config: QuoteCheckConfig) -> (Vec<Post>, Vec<Rejection>) {
+    if config.mode == QuoteCheckMode::Off {
+        return (posts, Vec::new());
+    }
+    let article_words = normalize_words(article);
+    let mut kept = Vec::new();
+    let mut rejected = Vec::new();
+    for mut post in posts {
+        let unverified = unverified_quotes(&post.post, &article_words, config.threshold);
+        if unverified.is_empty() {
+            kept.push(post);
+            continue;
+        }
+        println!("Unverified quotes in {:?}: {:?}", post.post, unverified);
+        match config.mode {
+            QuoteCheckMode::Drop => rejected.push(Rejection {
+                reason: format!("Quotes not found in the article: {:?}", unverified),
+                post: post.post,
+            }),
+            _ => {
+                post.unverified_quotes = Some(unverified);
+                kept.push(post);
+            }
+        }
+    }
+    (kept, rejected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARTICLE: &str = "Most runners skip strength work. “Strength is what keeps you running \
+        when your legs want to quit,” my coach told me.\n\nSo I lift twice a week, and I've never \
+        felt faster.";
+
+    #[test]
+    fn test_extract_quotes() {
+        let post = "My coach said “Strength keeps you running” and I \"believe\" it. \"Lift twice a week.\"";
+        assert_eq!(extract_quotes(post), vec!["Strength keeps you running", "Lift twice a week."]);
+        assert!(extract_quotes("No quotes here").is_empty());
+    }
+
+    #[test]
+    fn test_match_score() {
+        let article = normalize_words(ARTICLE);
+        let exact = normalize_words("strength is what keeps you running when your legs want to quit");
+        assert_eq!(match_score(&exact, &article), 1.0);
+        // Punctuation, case and a dropped word still match
+        let close = normalize_words("Strength is what keeps you running when legs want to quit!");
+        assert!(match_score(&close, &article) >= DEFAULT_MATCH_THRESHOLD);
+        let made_up = normalize_words("Speed work is the secret to every personal best");
+        assert!(match_score(&made_up, &article) < 0.5);
+    }
+
+    #[test]
+    fn test_check_quotes() {
+        let posts = vec![
+            Post::new(String::from("“Strength is what keeps you running when your legs want to quit.” #running")),
+            Post::new(String::from("\"Speed work is the secret to every personal best.\" Run with joy!")),
+            Post::new(String::from("Lift twice a week and run with joy.")),
+        ];
+        let drop = QuoteCheckConfig { mode: QuoteCheckMode::Drop, threshold: DEFAULT_MATCH_THRESHOLD };
+        let (kept, rejected) = check_quotes(posts.clone(), ARTICLE, drop);
+        assert_eq!(kept.len(), 2);
+        assert_eq!(rejected.len(), 1);
+        assert!(rejected[0].post.contains("Speed work"));
+
+        let flag = QuoteCheckConfig { mode: QuoteCheckMode::Flag, threshold: DEFAULT_MATCH_THRESHOLD };
+        let (kept, rejected) = check_quotes(posts, ARTICLE, flag);
+        assert_eq!(kept.len(), 3);
+        assert!(rejected.is_empty());
+        assert_eq!(kept[1].unverified_quotes, Some(vec![String::from("Speed work is the secret to every personal best.")]));
+        assert_eq!(kept[0].unverified_quotes, None);
+    }
+}
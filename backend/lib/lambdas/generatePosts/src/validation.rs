@@ -0,0 +1,315 @@
+use regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use super::provider::ResponseFormat;
+use super::{extract_json, Post};
+
+// Character limits of the platforms we publish to
+pub const PLATFORM_LIMITS: [(&str, usize); 2] = [("x", 280), ("deso", 10000)];
+
+pub const MIN_THREAD_SEGMENTS: usize = 3;
+pub const MAX_THREAD_SEGMENTS: usize = 10;
+
+// Template artifacts models leave behind when they copy the example instead
+// of filling it in. Words like TBD or placeholder show up in real posts, so
+// they don't count.
+const PLACEHOLDER_PATTERN: &str = r"(?i)(<str>|<post>|\[(insert|link|url|your|name)[^\]]*\]|\{[a-z_ ]*\}|lorem ipsum)";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Rejection {
+    pub post: String,
+    pub reason: String,
+}
+
+// Posts may come back as objects, like we ask for, or as bare strings
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum RawPost {
+    Object { post: String },
+    Text(String),
+}
+
+#[derive(Deserialize, Debug)]
+struct RawPosts {
+    posts: Vec<RawPost>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RawThread {
+    segments: Vec<RawPost>,
+}
+
+/**
+ * Everything we generate goes out on every platform, so it has to fit the
+ * strictest one.
+ */
+pub fn platform_limit() -> usize {
+    PLATFORM_LIMITS.iter().map(|(_, limit)| *limit).min().unwrap()
+}
+
+pub fn posts_response_format() -> ResponseFormat {
+    ResponseFormat {
+        name: String::from("posts"),
+        schema: json!({
+            "type": "object",
+            "properties": {
+                "posts": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "post": { "type": "string" }
+                        },
+                        "required": ["post"],
+                        "additionalProperties": false
+                    }
+                }
+            },
+            "required": ["posts"],
+            "additionalProperties": false
+        }),
+    }
+}
+
+pub fn thread_response_format() -> ResponseFormat {
+    ResponseFormat {
+        name: String::from("thread"),
+        schema: json!({
+            "type": "object",
+            "properties": {
+                "segments": {
+                    "type": "array",
+                    "items": { "type": "string" }
+                }
+            },
+            "required": ["segments"],
+            "additionalProperties": false
+        }),
+    }
+}
+
+fn raw_text(post: RawPost) -> String {
+    match post {
+        RawPost::Object { post } => post,
+        RawPost::Text(post) => post,
+    }
+}
+
+/**
+ * Parses the posts out of a model reply. We try the whole reply first
+ * since that's what structured output gives us, then fall back to the
+ * outermost braces for models that wrap their JSON in prose.
+ */
+pub fn parse_posts(reply: &str) -> Result<Vec<String>, String> {
+    let parsed: Result<RawPosts, serde_json::Error> = match serde_json::from_str(reply.trim()) {
+        Ok(p) => Ok(p),
+        Err(e) => match extract_json(reply) {
+            Some(json) => serde_json::from_str(&json),
+            None => Err(e),
+        },
+    };
+    match parsed {
+        Ok(p) => Ok(p.posts.into_iter().map(raw_text).collect()),
+        Err(e) => Err(format!("Response was not valid JSON in the expected format: {}", e)),
+    }
+}
+
+/**
+ * Parses a thread's segments, in order, out of a model reply the same way
+ * parse_posts does.
+ */
+pub fn parse_thread(reply: &str) -> Result<Vec<String>, String> {
+    let parsed: Result<RawThread, serde_json::Error> = match serde_json::from_str(reply.trim()) {
+        Ok(t) => Ok(t),
+        Err(e) => match extract_json(reply) {
+            Some(json) => serde_json::from_str(&json),
+            None => Err(e),
+        },
+    };
+    match parsed {
+        Ok(t) => Ok(t.segments.into_iter().map(raw_text).collect()),
+        Err(e) => Err(format!("Response was not valid JSON in the expected format: {}", e)),
+    }
+}
+
+/**
+ * Fixes the harmless things models do: surrounding whitespace, wrapping the
+ * whole post in quotes, and numbering or bulleting posts like a list.
+ */
+pub fn repair_post(post: &str) -> String {
+    let list_re = Regex::new(r"^\s*(\d+[.)]|[-*•])\s+").unwrap();
+    let mut repaired = list_re.replace(post.trim(), "").trim().to_string();
+    for (open, close) in [('"', '"'), ('“', '”')] {
+        if repaired.len() > 1 && repaired.starts_with(open) && repaired.ends_with(close) {
+            let inner = &repaired[open.len_utf8()..repaired.len() - close.len_utf8()];
+            // Only unwrap when the quotes belong to the whole post
+            if !inner.contains(open) && !inner.contains(close) {
+                repaired = inner.trim().to_string();
+            }
+        }
+    }
+    repaired
+}
+
+pub fn validate_post(post: &str, max_length: usize) -> Result<(), String> {
+    if post.trim().is_empty() {
+        return Err(String::from("Post is empty"));
+    }
+    let length = post.chars().count();
+    if length > max_length {
+        return Err(format!("Post is {} characters, the limit is {}", length, max_length));
+    }
+    let placeholder_re = Regex::new(PLACEHOLDER_PATTERN).unwrap();
+    if let Some(m) = placeholder_re.find(post) {
+        return Err(format!("Post contains placeholder text {:?}", m.as_str()));
+    }
+    Ok(())
+}
+
+/**
+ * Repairs every post and splits them into the ones we keep and the ones
+ * we reject, with the reason for each rejection.
+ */
+pub fn validate_posts(posts: Vec<String>, max_length: usize) -> (Vec<Post>, Vec<Rejection>) {
+    let mut valid = Vec::new();
+    let mut rejected = Vec::new();
+    for post in posts {
+        let repaired = repair_post(&post);
+        match validate_post(&repaired, max_length) {
+            Ok(_) => valid.push(Post::new(repaired)),
+            Err(reason) => rejected.push(Rejection { post, reason }),
+        }
+    }
+    (valid, rejected)
+}
+
+/**
+ * Repairs and validates every segment of a thread. A thread is only
+ * usable as a whole, so any invalid segment, or the wrong number of them,
+ * rejects it.
+ */
+pub fn validate_thread(segments: Vec<String>, max_length: usize) -> Result<Vec<String>, Vec<Rejection>> {
+    let mut rejected = Vec::new();
+    if segments.len() < MIN_THREAD_SEGMENTS || segments.len() > MAX_THREAD_SEGMENTS {
+        rejected.push(Rejection {
+            post: segments.join("\n\n"),
+            reason: format!("Thread has {} segments, it needs {} to {}", segments.len(), MIN_THREAD_SEGMENTS, MAX_THREAD_SEGMENTS),
+        });
+    }
+    let mut repaired = Vec::new();
+    for (i, segment) in segments.into_iter().enumerate() {
+        let fixed = repair_post(&segment);
+        match validate_post(&fixed, max_length) {
+            Ok(_) => repaired.push(fixed),
+            Err(reason) => rejected.push(Rejection {
+                post: segment,
+                reason: format!("Segment {}: {}", i + 1, reason),
+            }),
+        }
+    }
+    if rejected.is_empty() {
+        Ok(repaired)
+    } else {
+        Err(rejected)
+    }
+}
+
+pub fn describe_rejections(rejected: &[Rejection]) -> String {
+    rejected.iter()
+        .map(|r| format!("- {:?}: {}", r.post, r.reason))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+pub fn selection_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "selected": {
+                "type": "array",
+                "items": { "type": "integer" }
+            }
+        },
+        "required": ["selected"],
+        "additionalProperties": false
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_posts() {
+        let strict = r#"{"posts": [{"post": "Run with joy!"}]}"#;
+        assert_eq!(parse_posts(strict).unwrap(), vec!["Run with joy!"]);
+
+        let wrapped = "Here you go:\n{\"posts\": [\"Run with joy!\", {\"post\": \"Rest.\"}]}\nEnjoy!";
+        assert_eq!(parse_posts(wrapped).unwrap(), vec!["Run with joy!", "Rest."]);
+
+        // The shape the old prompt asked for isn't valid JSON
+        assert!(parse_posts(r#"{"posts": ["post": "Run with joy!"]}"#).is_err());
+        assert!(parse_posts("no json here").is_err());
+    }
+
+    #[test]
+    fn test_repair_post() {
+        assert_eq!(repair_post("  1. Run with joy!  "), "Run with joy!");
+        assert_eq!(repair_post("- \"Run with joy!\""), "Run with joy!");
+        assert_eq!(repair_post("“Run with joy!”"), "Run with joy!");
+        // Quotes inside the post are left alone
+        assert_eq!(repair_post("\"Run\" is what he said, \"with joy\""), "\"Run\" is what he said, \"with joy\"");
+    }
+
+    #[test]
+    fn test_validate_posts() {
+        let posts = vec![
+            String::from("1. Run with joy!"),
+            String::from("   "),
+            "a".repeat(platform_limit() + 1),
+            String::from("Read more at [insert link]"),
+            String::from("<str>"),
+        ];
+        let (valid, rejected) = validate_posts(posts, platform_limit());
+        assert_eq!(valid, vec![Post::new(String::from("Run with joy!"))]);
+        assert_eq!(rejected.len(), 4);
+        assert_eq!(rejected[0].reason, "Post is empty");
+        assert!(rejected[1].reason.contains("characters"));
+        assert!(rejected[2].reason.contains("placeholder"));
+        assert!(rejected[3].reason.contains("placeholder"));
+    }
+
+    #[test]
+    fn test_validate_post_placeholders() {
+        let limit = platform_limit();
+        assert!(validate_post("Race date TBD, training starts now.", limit).is_ok());
+        assert!(validate_post("Don't be a placeholder in your own plan.", limit).is_ok());
+        assert!(validate_post("Finished the XXX-mile week. Rest day!", limit).is_ok());
+        assert!(validate_post("Cross it off the TODO list: long run done.", limit).is_ok());
+        assert!(validate_post("Run with {name} today", limit).is_err());
+        assert!(validate_post("Lorem ipsum dolor sit amet", limit).is_err());
+    }
+
+    #[test]
+    fn test_parse_thread() {
+        let reply = r#"{"segments": ["1/3 Run with joy.", "2/3 Rest.", "3/3 Repeat."]}"#;
+        assert_eq!(parse_thread(reply).unwrap(), vec!["1/3 Run with joy.", "2/3 Rest.", "3/3 Repeat."]);
+        assert!(parse_thread(r#"{"posts": []}"#).is_err());
+    }
+
+    #[test]
+    fn test_validate_thread() {
+        let segments = |n: usize| (1..=n).map(|i| format!("{}. Segment {}", i, i)).collect::<Vec<String>>();
+        assert_eq!(validate_thread(segments(3), 280).unwrap(), vec!["Segment 1", "Segment 2", "Segment 3"]);
+        assert!(validate_thread(segments(2), 280).unwrap_err()[0].reason.contains("2 segments"));
+        assert!(validate_thread(segments(11), 280).is_err());
+
+        let mut long = segments(4);
+        long[2] = "a".repeat(281);
+        let rejected = validate_thread(long, 280).unwrap_err();
+        assert_eq!(rejected.len(), 1);
+        assert!(rejected[0].reason.starts_with("Segment 3:"));
+    }
+}
@@ -0,0 +1,559 @@
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use futures_util::future::join_all;
+use reqwest::get;
+use select::document::Document;
+use select::predicate::Name;
+use uuid::Uuid;
+use std::env;
+use openai_api_rs::v1::api;
+use openai_api_rs::v1::chat_completion::{self, ChatCompletionRequest};
+use openai_api_rs::v1::image::ImageGenerationRequest;
+use openai_api_rs::v1::error::APIError;
+use scraper::{Html, Selector};
+use lambda_http::{Response, Body, Error, Request, RequestExt};
+use shared::http::{respond_json, ApiError};
+use shared::storage::STATUS_INDEX;
+use tokio::fs::File;
+use tokio::time::Duration;
+use tokio::fs::File as AsyncFile;
+use tokio_util::codec::{BytesCodec, FramedRead};
+use std::path::Path;
+use std::io::prelude::*;
+use base64;
+use base64::Engine;
+use tokio::io::AsyncWriteExt;
+use dotenv::dotenv;
+use std::any::Any;
+use std::str::FromStr;
+use reqwest;
+use aws_sdk_sns::Client as SnsClient;
+use aws_config::{meta::region::RegionProviderChain, SdkConfig};
+use aws_sdk_dynamodb::{config::Region, meta::PKG_VERSION};
+use aws_sdk_dynamodb::Client as DbClient;
+use aws_sdk_dynamodb::types::{AttributeValue};
+use aws_sdk_dynamodb::operation::get_item::GetItemInput;
+use std::fmt;
+use std::error::Error as StdError;
+
+// Posts per page unless the request asks for more or fewer
+const DEFAULT_LIMIT: i32 = 50;
+const MAX_LIMIT: i32 = 100;
+
+
+#[derive(Debug)]
+pub struct Opt {
+    /// The AWS Region.
+    pub region: Option<String>,
+    /// Whether to display additional information.
+    pub verbose: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SuccessResponse {
+    pub body: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FailureResponse {
+    pub body: String,
+}
+
+type WorkerResponse = Result<SuccessResponse, FailureResponse>;
+
+// Error handling
+#[derive(Debug)]
+pub struct MyError {
+    message: String,
+}
+
+impl StdError for MyError {}
+
+impl MyError {
+    fn new(message: &str) -> MyError {
+        MyError {
+            message: String::from(message)
+        }
+    }
+}
+
+impl fmt::Display for MyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Post {
+    pub uuid: String,
+    pub post: String,
+    /// A thread's tweets, edit these rather than post
+    pub segments: Option<Vec<String>>,
+    /// Posts from before review existed have no status
+    pub status: Option<String>,
+    /// Sent back with an edit, posts from before versions are version 0
+    #[serde(default)]
+    pub version: u64,
+    pub created_at: Option<u64>,
+    /// Where a generated post came from, unset for posts added by hand
+    pub source: Option<String>,
+    pub source_feed: Option<String>,
+    pub article_title: Option<String>,
+    pub article_url: Option<String>,
+    pub generated_at: Option<u64>,
+    /// The post's image in the image bucket and its alt text
+    pub image_key: Option<String>,
+    pub image_alt: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Posts {
+    pub posts: Vec<Post>,
+    /// Pass back as `next` to get the following page, null on the last page
+    #[serde(default)]
+    pub next: Option<String>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    Asc,
+    Desc
+}
+
+/**
+ * What the caller asked for. Every query parameter is optional:
+ * limit, next, status, source, from and to (epoch seconds,
+ * compared with created_at) and order (asc or desc by created_at).
+ * Only a status is read in order, so order needs one.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostQuery {
+    pub limit: i32,
+    pub next: Option<HashMap<String, AttributeValue>>,
+    pub status: Option<String>,
+    pub source: Option<String>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub order: Option<Order>
+}
+
+impl Default for PostQuery {
+    fn default() -> PostQuery {
+        PostQuery {
+            limit: DEFAULT_LIMIT,
+            next: None,
+            status: None,
+            source: None,
+            from: None,
+            to: None,
+            order: None
+        }
+    }
+}
+
+fn parse_param<T: FromStr>(params: &HashMap<String, String>, key: &str) -> Result<Option<T>, MyError> {
+    match params.get(key) {
+        Some(v) => v.parse().map(Some).or_else(|_| Err(MyError {
+            message: format!("Invalid {}: {}", key, v)
+        })),
+        None => Ok(None)
+    }
+}
+
+impl PostQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<PostQuery, MyError> {
+        let limit = parse_param::<i32>(params, "limit")?.unwrap_or(DEFAULT_LIMIT);
+        if limit < 1 || limit > MAX_LIMIT {
+            return Err(MyError {
+                message: format!("limit has to be between 1 and {}", MAX_LIMIT)
+            });
+        }
+        let order = match params.get("order").map(|o| o.as_str()) {
+            Some("asc") => Some(Order::Asc),
+            Some("desc") => Some(Order::Desc),
+            Some(o) => return Err(MyError {
+                message: format!("Invalid order: {}", o)
+            }),
+            None => None
+        };
+        let status = params.get("status").cloned();
+        if order.is_some() && status.is_none() {
+            return Err(MyError::new("order needs a status, posts are only sorted within one"));
+        }
+        // Every post goes to every platform, so there's nothing to filter on yet
+        if params.contains_key("platform") {
+            return Err(MyError::new("platform isn't supported, every post is published to every platform"));
+        }
+        let from: Option<u64> = parse_param(params, "from")?;
+        let to: Option<u64> = parse_param(params, "to")?;
+        if let (Some(from), Some(to)) = (from, to) {
+            if from > to {
+                return Err(MyError::new("from has to be before to"));
+            }
+        }
+        Ok(PostQuery {
+            limit,
+            next: params.get("next").map(|t| decode_token(t)).transpose()?,
+            status,
+            source: params.get("source").cloned(),
+            from,
+            to,
+            order
+        })
+    }
+}
+
+/**
+ * The cursor is the scan's LastEvaluatedKey as base64 JSON, so callers
+ * don't depend on what's in it.
+ */
+pub fn encode_token(key: &HashMap<String, AttributeValue>) -> String {
+    let map: HashMap<String, Value> = key.iter()
+        .filter_map(|(k, v)| match v {
+            AttributeValue::S(s) => Some((k.clone(), json!({ "S": s }))),
+            AttributeValue::N(n) => Some((k.clone(), json!({ "N": n }))),
+            _ => None
+        })
+        .collect();
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_string(&map).unwrap())
+}
+
+pub fn decode_token(token: &str) -> Result<HashMap<String, AttributeValue>, MyError> {
+    let invalid = || MyError::new("Invalid next token");
+    let raw = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(token).or_else(|_| Err(invalid()))?;
+    let map: HashMap<String, HashMap<String, String>> = serde_json::from_slice(&raw).or_else(|_| Err(invalid()))?;
+    let mut key = HashMap::new();
+    for (name, value) in map {
+        let attribute = match (value.get("S"), value.get("N")) {
+            (Some(s), _) => AttributeValue::S(s.clone()),
+            (_, Some(n)) => AttributeValue::N(n.clone()),
+            _ => return Err(invalid())
+        };
+        key.insert(name, attribute);
+    }
+    Ok(key)
+}
+
+/**
+ * The expressions for a query. A status with a date range or an order
+ * reads the status index, where the range is part of the key condition and
+ * results come back sorted. Everything else scans and filters, the index
+ * leaves out posts without created_at and those still have a status.
+ */
+#[derive(Debug, Default, PartialEq)]
+pub struct Expressions {
+    pub key_condition: Option<String>,
+    pub filter: Option<String>,
+    pub names: HashMap<String, String>,
+    pub values: HashMap<String, AttributeValue>
+}
+
+pub fn build_expressions(query: &PostQuery) -> Expressions {
+    let mut expressions = Expressions::default();
+    let mut filters = Vec::new();
+    let mut range = Vec::new();
+    if let Some(from) = query.from {
+        range.push("created_at >= :from");
+        expressions.values.insert(String::from(":from"), AttributeValue::N(from.to_string()));
+    }
+    if let Some(to) = query.to {
+        range.push("created_at <= :to");
+        expressions.values.insert(String::from(":to"), AttributeValue::N(to.to_string()));
+    }
+    if let Some(status) = &query.status {
+        expressions.names.insert(String::from("#status"), String::from("status"));
+        expressions.values.insert(String::from(":status"), AttributeValue::S(status.clone()));
+    }
+    let use_index = query.status.is_some() && (!range.is_empty() || query.order.is_some());
+    if use_index {
+        let mut key_condition = vec!["#status = :status"];
+        // A key condition only takes one condition on the sort key
+        match (query.from, query.to) {
+            (Some(_), Some(_)) => key_condition.push("created_at BETWEEN :from AND :to"),
+            _ => key_condition.extend(range)
+        }
+        expressions.key_condition = Some(key_condition.join(" AND "));
+    } else {
+        if query.status.is_some() {
+            filters.push(String::from("#status = :status"));
+        }
+        filters.extend(range.into_iter().map(String::from));
+    }
+    if let Some(source) = &query.source {
+        filters.push(String::from("(#source = :source OR source_feed = :source)"));
+        expressions.names.insert(String::from("#source"), String::from("source"));
+        expressions.values.insert(String::from(":source"), AttributeValue::S(source.clone()));
+    }
+    if !filters.is_empty() {
+        expressions.filter = Some(filters.join(" AND "));
+    }
+    expressions
+}
+
+// Implement Display for the Failure response so that we can then implement Error.
+impl std::fmt::Display for FailureResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.body)
+    }
+}
+
+pub async fn make_config(opt: Opt) -> Result<SdkConfig, Error> {
+    let region_provider = make_region_provider(opt.region);
+
+    if opt.verbose {
+        println!("DynamoDB client version: {}", PKG_VERSION);
+        println!(
+            "Region: {}",
+            region_provider.region().await.unwrap().as_ref()
+        );
+        println!();
+    }
+
+    Ok(aws_config::from_env().region(region_provider).load().await)
+}
+
+pub fn make_region_provider(region: Option<String>) -> RegionProviderChain {
+    RegionProviderChain::first_try(region.map(Region::new))
+        .or_default_provider()
+        .or_else(Region::new("us-east-1"))
+}
+
+async fn get_table_name() -> Option<String> {
+    env::var("TABLE_NAME").ok()
+}
+
+fn get_optional_s(item: &HashMap<String, AttributeValue>, key: &str) -> Option<String> {
+    item.get(key).and_then(|v| v.as_s().ok()).cloned()
+}
+
+fn get_optional_n(item: &HashMap<String, AttributeValue>, key: &str) -> Option<u64> {
+    item.get(key).and_then(|n| n.as_n().ok()).and_then(|n| n.parse().ok())
+}
+
+fn get_optional_list(item: &HashMap<String, AttributeValue>, key: &str) -> Option<Vec<String>> {
+    item.get(key)
+        .and_then(|l| l.as_l().ok())
+        .map(|l| l.iter().filter_map(|v| v.as_s().ok().cloned()).collect())
+}
+
+fn item_to_post(item: &HashMap<String, AttributeValue>) -> Result<Post, Error> {
+    let post: String = item
+        .get("post")
+        .ok_or_else(|| MyError::new("Missing 'post' attribute"))?
+        .as_s()
+        .or_else(|_| Err(MyError::new("Error getting post S attribute")))?
+        .to_string();
+
+    let uuid: String = item
+        .get("uuid")
+        .ok_or_else(|| MyError::new("Missing 'uuid' attribute"))?
+        .as_s()
+        .or_else(|_| Err(MyError::new("Error getting uuid S attribute")))?
+        .to_string();
+    Ok(Post {
+        post,
+        uuid,
+        segments: get_optional_list(item, "segments"),
+        status: get_optional_s(item, "status"),
+        version: get_optional_n(item, "version").unwrap_or(0),
+        created_at: get_optional_n(item, "created_at"),
+        source: get_optional_s(item, "source"),
+        source_feed: get_optional_s(item, "source_feed"),
+        article_title: get_optional_s(item, "article_title"),
+        article_url: get_optional_s(item, "article_url"),
+        generated_at: get_optional_n(item, "generated_at"),
+        image_key: get_optional_s(item, "image_key"),
+        image_alt: get_optional_s(item, "image_alt")
+    })
+}
+
+/**
+ * Reads one page of posts. DynamoDB applies the limit before the filter,
+ * so we keep reading until the page is full or the table runs out, and
+ * the cursor is wherever we stopped.
+ */
+async fn get_posts_from_db(client: &DbClient, table_name: &str, query: &PostQuery) -> Result<Posts, Error> {
+    let expressions = build_expressions(query);
+    let names = if expressions.names.is_empty() { None } else { Some(expressions.names.clone()) };
+    let values = if expressions.values.is_empty() { None } else { Some(expressions.values.clone()) };
+    let mut posts = Vec::new();
+    let mut start_key = query.next.clone();
+    loop {
+        let remaining = query.limit - posts.len() as i32;
+        let (items, last_key) = match &expressions.key_condition {
+            Some(key_condition) => {
+                let response = client.query()
+                    .table_name(table_name)
+                    .index_name(STATUS_INDEX)
+                    .key_condition_expression(key_condition)
+                    .set_filter_expression(expressions.filter.clone())
+                    .set_expression_attribute_names(names.clone())
+                    .set_expression_attribute_values(values.clone())
+                    .scan_index_forward(query.order != Some(Order::Desc))
+                    .limit(remaining)
+                    .set_exclusive_start_key(start_key)
+                    .send().await?;
+                (response.items.unwrap_or_default(), response.last_evaluated_key)
+            },
+            None => {
+                let response = client.scan()
+                    .table_name(table_name)
+                    .set_filter_expression(expressions.filter.clone())
+                    .set_expression_attribute_names(names.clone())
+                    .set_expression_attribute_values(values.clone())
+                    .limit(remaining)
+                    .set_exclusive_start_key(start_key)
+                    .send().await?;
+                (response.items.unwrap_or_default(), response.last_evaluated_key)
+            }
+        };
+        for item in items {
+            posts.push(item_to_post(&item)?);
+        }
+        start_key = last_key;
+        if start_key.is_none() || posts.len() as i32 >= query.limit {
+            break;
+        }
+    }
+    Ok(Posts {
+        posts,
+        next: start_key.as_ref().map(encode_token)
+    })
+}
+
+pub async fn handler(request: Request, db_client: &DbClient) -> Result<Response<String>, Error> {
+    // 1. Read the query and get table name from env
+    let params: HashMap<String, String> = request.query_string_parameters()
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let query = match PostQuery::from_params(&params) {
+        Ok(q) => q,
+        Err(e) => return ApiError::new(400, "invalid_query", &e.to_string()).into_response()
+    };
+    let table_name = match get_table_name().await {
+        Some(t) => t,
+        None => return ApiError::config("No Table Name Set").into_response()
+    };
+    // 2. Get a page of posts from DB
+    let posts = match get_posts_from_db(db_client, &table_name, &query).await {
+        Ok(p) => p,
+        Err(e) => return ApiError::internal(&format!("Get Posts Internal Error: {}", e)).into_response()
+    };
+
+    respond_json(200, &posts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    #[test]
+    fn test_send_posts() {
+        dotenv::from_filename("../.env").ok();
+        for var in dotenv::vars() {
+            println!("{:?}", var);
+        }
+        let opt = Opt {
+            region: Some("us-east-1".to_string()),
+            verbose: true,
+        };
+        let config = aw!(make_config(opt)).unwrap();
+        let db_client = DbClient::new(&config);
+        let resp = aw!(handler(Request::new(Body::Empty), &db_client)).unwrap();
+        let posts: Posts = serde_json::from_str(resp.body()).unwrap();
+        println!("Total Posts: {}", posts.posts.len());
+        for post in posts.posts {
+            println!("* UUID: {} Post: {}", post.uuid, post.post);
+        }
+    }
+
+    #[test]
+    fn test_post_query() {
+        let mut params = HashMap::new();
+        assert_eq!(PostQuery::from_params(&params).unwrap(), PostQuery::default());
+
+        let mut key = HashMap::new();
+        key.insert(String::from("uuid"), AttributeValue::S(String::from("abc")));
+        key.insert(String::from("created_at"), AttributeValue::N(String::from("1718670000")));
+        params.insert(String::from("next"), encode_token(&key));
+        params.insert(String::from("limit"), String::from("10"));
+        params.insert(String::from("order"), String::from("desc"));
+        assert!(PostQuery::from_params(&params).is_err());
+        params.insert(String::from("status"), String::from("approved"));
+        let query = PostQuery::from_params(&params).unwrap();
+        assert_eq!(query.next, Some(key));
+        assert_eq!(query.limit, 10);
+        assert_eq!(query.order, Some(Order::Desc));
+
+        params.insert(String::from("platform"), String::from("x"));
+        assert!(PostQuery::from_params(&params).is_err());
+        params.remove("platform");
+
+        params.insert(String::from("from"), String::from("200"));
+        params.insert(String::from("to"), String::from("100"));
+        assert!(PostQuery::from_params(&params).is_err());
+        params.insert(String::from("to"), String::from("200"));
+        assert_eq!(PostQuery::from_params(&params).unwrap().to, Some(200));
+        params.remove("from");
+        params.remove("to");
+
+        params.insert(String::from("limit"), String::from("1000"));
+        assert!(PostQuery::from_params(&params).is_err());
+        params.insert(String::from("limit"), String::from("10"));
+        params.insert(String::from("next"), String::from("not a token"));
+        assert!(PostQuery::from_params(&params).is_err());
+    }
+
+    #[test]
+    fn test_build_expressions() {
+        let query = PostQuery {
+            from: Some(100),
+            to: Some(200),
+            ..PostQuery::default()
+        };
+        let expressions = build_expressions(&query);
+        assert_eq!(expressions.key_condition, None);
+        assert_eq!(expressions.filter, Some(String::from("created_at >= :from AND created_at <= :to")));
+
+        // Posts without created_at aren't in the index, so a status on its own scans
+        let query = PostQuery {
+            status: Some(String::from("draft")),
+            ..PostQuery::default()
+        };
+        let expressions = build_expressions(&query);
+        assert_eq!(expressions.key_condition, None);
+        assert_eq!(expressions.filter, Some(String::from("#status = :status")));
+
+        let query = PostQuery {
+            status: Some(String::from("draft")),
+            order: Some(Order::Desc),
+            ..PostQuery::default()
+        };
+        let expressions = build_expressions(&query);
+        assert_eq!(expressions.key_condition, Some(String::from("#status = :status")));
+        assert_eq!(expressions.filter, None);
+
+        let query = PostQuery {
+            status: Some(String::from("approved")),
+            source: Some(String::from("substack")),
+            from: Some(100),
+            to: Some(200),
+            ..PostQuery::default()
+        };
+        let expressions = build_expressions(&query);
+        assert_eq!(expressions.key_condition, Some(String::from("#status = :status AND created_at BETWEEN :from AND :to")));
+        assert_eq!(expressions.filter, Some(String::from("(#source = :source OR source_feed = :source)")));
+        assert_eq!(expressions.names.len(), 2);
+        assert_eq!(expressions.values.len(), 4);
+    }
+}
@@ -0,0 +1,190 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::env;
+use aws_config::{meta::region::RegionProviderChain, SdkConfig};
+use aws_sdk_dynamodb::{config::Region, meta::PKG_VERSION};
+use aws_sdk_dynamodb::Client as DbClient;
+use lambda_http::{Response, Error, Request};
+use chrono::{DateTime, Utc};
+use shared::http::{respond_json, ApiError};
+use shared::schedule::{ScheduledRecord, DEFAULT_TIMEZONE};
+
+
+#[derive(Debug)]
+pub struct Opt {
+    /// The AWS Region.
+    pub region: Option<String>,
+    /// Whether to display additional information.
+    pub verbose: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScheduledPost {
+    pub uuid: String,
+    pub post: String,
+    pub time: String,
+    pub timezone: String,
+    pub recurring: bool,
+    pub enabled: bool,
+    /// Epoch seconds, unset when the post won't go out again
+    pub next_occurrence: Option<i64>,
+    pub last_sent: Option<u64>,
+    pub status: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScheduledPosts {
+    pub posts: Vec<ScheduledPost>
+}
+
+// The API lambda serves posts too, so it names the scheduled table separately
+async fn get_table_name() -> Option<String> {
+    env::var("SCHEDULED_TABLE_NAME").ok()
+}
+
+pub async fn make_config(opt: Opt) -> Result<SdkConfig, Error> {
+    let region_provider = make_region_provider(opt.region);
+
+    println!();
+    if opt.verbose {
+        println!("DynamoDB client version: {}", PKG_VERSION);
+        println!(
+            "Region:                  {}",
+            region_provider.region().await.unwrap().as_ref()
+        );
+        println!();
+    }
+
+    Ok(aws_config::from_env().region(region_provider).load().await)
+}
+
+pub fn make_region_provider(region: Option<String>) -> RegionProviderChain {
+    RegionProviderChain::first_try(region.map(Region::new))
+        .or_default_provider()
+        .or_else(Region::new("us-east-1"))
+}
+
+/**
+ * The listing for one row. A row whose time or timezone doesn't parse is
+ * still listed, without a next occurrence, so someone can find and fix it.
+ */
+pub fn to_scheduled_post(record: ScheduledRecord, now: DateTime<Utc>) -> ScheduledPost {
+    let next_occurrence = match record.next_occurrence(now) {
+        Ok(next) => next.map(|n| n.timestamp()),
+        Err(e) => {
+            println!("Error scheduling {}: {}", record.uuid, e);
+            None
+        }
+    };
+    ScheduledPost {
+        uuid: record.uuid,
+        post: record.post,
+        time: record.time,
+        timezone: record.timezone.unwrap_or(String::from(DEFAULT_TIMEZONE)),
+        recurring: record.recurring,
+        enabled: record.enabled,
+        next_occurrence,
+        last_sent: record.last_sent,
+        status: record.status
+    }
+}
+
+/// Soonest first, posts that won't go out again at the end.
+pub fn sort_by_next_occurrence(posts: &mut [ScheduledPost]) {
+    posts.sort_by_key(|p| (p.next_occurrence.is_none(), p.next_occurrence));
+}
+
+/**
+ * Reads the whole scheduled posts table. It only holds a handful of posts
+ * a day, and sorting by next occurrence needs all of them anyway.
+ */
+pub async fn get_scheduled_posts(client: &DbClient, table: &str, now: DateTime<Utc>) -> Result<Vec<ScheduledPost>, Error> {
+    let mut posts = Vec::new();
+    let mut start_key = None;
+    loop {
+        let response = client.scan()
+            .table_name(table)
+            .set_exclusive_start_key(start_key)
+            .send().await?;
+        for item in response.items.unwrap_or_default() {
+            match ScheduledRecord::from_item(&item) {
+                Ok(record) => posts.push(to_scheduled_post(record, now)),
+                Err(e) => println!("Skipping scheduled post {:?}: {}", item.get("uuid"), e),
+            };
+        }
+        start_key = response.last_evaluated_key;
+        if start_key.is_none() {
+            break;
+        }
+    }
+    sort_by_next_occurrence(&mut posts);
+    Ok(posts)
+}
+
+pub async fn handler(_request: Request, db_client: &DbClient) -> Result<Response<String>, Error> {
+    // 1. Get table name from env
+    let table_name = match get_table_name().await {
+        Some(t) => t,
+        None => return ApiError::config("SCHEDULED_TABLE_NAME not set").into_response(),
+    };
+
+    // 2. Get every scheduled post, soonest first
+    let posts = match get_scheduled_posts(db_client, &table_name, Utc::now()).await {
+        Ok(p) => p,
+        Err(e) => return ApiError::internal(&format!("Error getting scheduled posts: {}", e)).into_response(),
+    };
+    respond_json(200, &ScheduledPosts { posts })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    fn record(uuid: &str, time: &str, timezone: Option<&str>) -> ScheduledRecord {
+        ScheduledRecord {
+            uuid: String::from(uuid),
+            post: String::from("Time for a run!"),
+            time: String::from(time),
+            timezone: timezone.map(String::from),
+            recurring: true,
+            enabled: true,
+            last_sent: None,
+            status: None
+        }
+    }
+
+    #[test]
+    fn test_sort_by_next_occurrence() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 18, 14, 20, 0).unwrap();
+        let mut posts = vec![
+            to_scheduled_post(record("broken", "noon", None), now),
+            to_scheduled_post(record("tomorrow", "09:00:00", None), now),
+            to_scheduled_post(record("tonight", "18:00:00", Some("Europe/Berlin")), now),
+            to_scheduled_post(record("later", "20:00:00", None), now),
+        ];
+        sort_by_next_occurrence(&mut posts);
+        let order: Vec<&str> = posts.iter().map(|p| p.uuid.as_str()).collect();
+        assert_eq!(order, vec!["tonight", "later", "tomorrow", "broken"]);
+        assert_eq!(posts[0].next_occurrence, Some(Utc.with_ymd_and_hms(2024, 6, 18, 16, 0, 0).unwrap().timestamp()));
+        assert_eq!(posts[1].timezone, "UTC");
+    }
+
+    #[test]
+    fn test_get_scheduled_posts() {
+        let opt = Opt {
+            region: Some("us-east-1".to_string()),
+            verbose: true,
+        };
+        let config = aw!(make_config(opt)).unwrap();
+        let db_client = DbClient::new(&config);
+        let resp = aw!(get_scheduled_posts(&db_client, "ScheduledPosts", Utc::now()));
+        println!("Response: {:?}", resp);
+    }
+}
@@ -7,6 +7,10 @@ use serde_json::json;
 use std::env;
 use aws_lambda_events::event::sqs::SqsEvent;
 use dotenv::dotenv;
+use shared::links::{rewrite_publication, with_article_link, ArticleLinkMode, LinkConfig};
+
+// DeSo posts can be much longer than tweets and links count in full
+const MAX_DESO_POST_LENGTH: usize = 10000;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MessageBody {
@@ -30,6 +34,8 @@ pub struct MessageBody {
 pub struct Post {
     pub uuid: String,
     pub post: String,
+    #[serde(default)]
+    pub article_url: Option<String>,
 }
 
 #[tokio::main]
@@ -54,9 +60,13 @@ async fn worker(body: &str) -> Result<String, Error> {
     let message_body: MessageBody = serde_json::from_str(&body).expect("Couldn't parse json raw body");
     let post: Post = serde_json::from_str(&message_body.message).expect("Couldn't parse json post");
 
-    let body = post.post;
+    let link_length = post.article_url.as_ref().map(|u| u.chars().count()).unwrap_or(0);
+    let publication = with_article_link(post.post, post.article_url.as_deref(), ArticleLinkMode::from_env(), MAX_DESO_POST_LENGTH, link_length);
+    // Tag and shorten every link on its way out
+    let publication = rewrite_publication(publication, &LinkConfig::from_env(), "deso", &post.uuid).await;
+    let body = publication.body;
+    let comment = publication.comment;
     println!("Body: {:?}", body);
-    let comment = String::from("If you like this kind of content, make sure to checkout my newsletter and remember, run with joy! https://davidjmeyer.substack.com");
     println!("{}", body);
     let deso_account = deso_sdk::DesoAccountBuilder::new()
         .public_key(get_deso_user().await.unwrap())
@@ -10,6 +10,9 @@ use std::collections::HashMap;
 use dotenv::dotenv;
 use oauth1_header::{Credentials};
 use oauth1_header::http::Method;
+use shared::links::{rewrite_links, with_article_link, ArticleLinkMode, LinkConfig, X_LINK_LENGTH};
+
+const MAX_TWEET_LENGTH: usize = 280;
 
 
 // Raw Body: {
@@ -46,6 +49,11 @@ pub struct MessageBody {
 pub struct Post {
     pub uuid: String,
     pub post: String,
+    /// Set for threads, each segment is posted as a reply to the one before
+    #[serde(default)]
+    pub segments: Option<Vec<String>>,
+    #[serde(default)]
+    pub article_url: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -111,13 +119,49 @@ async fn get_access_secret() -> Option<String> {
     env::var("ACCESS_TOKEN_SECRET").ok()
 }
 
+/// Posts one tweet and returns its id. X's error body comes back as the error.
+async fn send_tweet(client: &reqwest::Client, uri: &str, header_value: &str, json_body: &serde_json::Value) -> Result<String, Error> {
+    let response = client.post(uri)
+        .header("Authorization", header_value)
+        .header("Content-Type", "application/json")
+        .json(json_body)
+        .send().await?;
+    let status = response.status();
+    let raw_resp = response.text().await?;
+    if !status.is_success() {
+        return Err(format!("X answered {}: {}", status, raw_resp).into());
+    }
+    let tweet_data: TweetResponse = serde_json::from_str(&raw_resp)
+        .map_err(|e| format!("Error getting tweet data from {}: {}", raw_resp, e))?;
+    println!("Tweet Data: {:?}", tweet_data);
+    Ok(tweet_data.data.id)
+}
+
 async fn worker(body: &str) -> Result<String, Error> {
     println!("Raw Body: {}", body);
     let message_body: MessageBody = serde_json::from_str(&body).expect("Couldn't parse json raw body");
     let post: Post = serde_json::from_str(&message_body.message).expect("Couldn't parse json post");
 
-    let body = post.post;
-    println!("Body: {:?}", body);
+    // A thread is posted as a chain of replies, a post is a chain of one
+    let mut tweets = match post.segments {
+        Some(segments) if !segments.is_empty() => segments,
+        _ => vec![post.post],
+    };
+
+    // The article link goes on the last tweet or in the closing reply
+    let last = tweets.pop().expect("No tweets to post");
+    let publication = with_article_link(last, post.article_url.as_deref(), ArticleLinkMode::from_env(), MAX_TWEET_LENGTH, X_LINK_LENGTH);
+    tweets.push(publication.body);
+
+    // Tag and shorten every link on its way out
+    let link_config = LinkConfig::from_env();
+    let mut tagged_tweets = Vec::new();
+    for tweet in tweets {
+        tagged_tweets.push(rewrite_links(&tweet, &link_config, "x", &post.uuid).await);
+    }
+    let tweets = tagged_tweets;
+    let comment = rewrite_links(&publication.comment, &link_config, "x", &post.uuid).await;
+    println!("Tweets: {:?}", tweets);
 
     let uri = "https://api.twitter.com/2/tweets";
     let consumer_key = get_consumer_key().await.expect("Missing Consumer Key");
@@ -136,35 +180,31 @@ async fn worker(body: &str) -> Result<String, Error> {
     let header_value = credentials.auth(&Method::POST, uri, &params);
     let client = reqwest::Client::new();
 
-    // Construct JSON body
-    let json_body = json!({
-        "text": body,
-    });
+    // A failure part way leaves the tweets before it up, the error names
+    // them so a half posted thread can be found and finished or removed
+    let total = tweets.len();
+    let mut posted_ids: Vec<String> = Vec::new();
+    for text in tweets {
+        // Construct JSON body, replying to the previous tweet in the thread
+        let json_body = match posted_ids.last() {
+            Some(id) => json!(TweetComment::new(text, id.clone())),
+            None => json!({
+                "text": text,
+            }),
+        };
 
-    // Make the POST request with the authorization header
-    let response = client.post(uri)
-        .header("Authorization", &header_value)
-        .header("Content-Type", "application/json")
-        .json(&json_body)
-        .send().await;
+        match send_tweet(&client, uri, &header_value, &json_body).await {
+            Ok(id) => posted_ids.push(id),
+            Err(e) => return Err(format!("Post {} stopped after {} of {} tweets, already posted: {:?}: {}", post.uuid, posted_ids.len(), total, posted_ids, e).into()),
+        }
+    }
 
     // Make the comment
-    let comment = String::from("If you like this kind of content, make sure to checkout my newsletter and remember, run with joy! https://davidjmeyer.substack.com");
-
-    let raw_resp = response.unwrap().text().await.unwrap();
-    let tweet_data: TweetResponse = serde_json::from_str(&raw_resp).expect("Error getting tweet data");
-    println!("Tweet Data: {:?}", tweet_data);
-
-    let comment_body = TweetComment::new(comment, tweet_data.data.id);
-
-    let response = client.post(uri)
-        .header("Authorization", &header_value)
-        .header("Content-Type", "application/json")
-        .json(&comment_body)
-        .send().await;
-
-    let raw_resp = response.unwrap().text().await.unwrap();
-    println!("{}", raw_resp);
+    let last_id = posted_ids.last().cloned().ok_or("No tweets posted")?;
+    let comment_body = json!(TweetComment::new(comment, last_id));
+    if let Err(e) = send_tweet(&client, uri, &header_value, &comment_body).await {
+        return Err(format!("Post {} is up as {:?} but its comment failed: {}", post.uuid, posted_ids, e).into());
+    }
 
     Ok(String::from("Success"))
 
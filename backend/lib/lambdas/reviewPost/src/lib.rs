@@ -0,0 +1,264 @@
+use serde::Deserialize;
+use serde::Serialize;
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
+use std::collections::HashMap;
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+use aws_config::{meta::region::RegionProviderChain, SdkConfig};
+use aws_sdk_dynamodb::{config::Region, meta::PKG_VERSION};
+use aws_sdk_dynamodb::Client as DbClient;
+use lambda_http::{Response, Error, Request};
+use serde_json::json;
+use shared::http::{check_post_count, check_uuid, parse_body, respond_json, ApiError};
+
+
+#[derive(Debug)]
+pub struct Opt {
+    /// The AWS Region.
+    pub region: Option<String>,
+    /// Whether to display additional information.
+    pub verbose: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Draft,
+    Approved,
+    Rejected
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Draft => "draft",
+            Status::Approved => "approved",
+            Status::Rejected => "rejected",
+        }
+    }
+
+    /**
+     * The status a review endpoint sets, from the end of the request path
+     * so /approvePosts and /rejectPosts can share this lambda.
+     */
+    pub fn from_path(path: &str) -> Option<Status> {
+        let path = path.trim_end_matches('/');
+        if path.ends_with("approvePosts") {
+            Some(Status::Approved)
+        } else if path.ends_with("rejectPosts") {
+            Some(Status::Rejected)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PostId {
+    pub uuid: String,
+    /// The version the reviewer saw, the review is refused if the post has
+    /// been edited since. Posts from before versions are version 0.
+    pub version: u64
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Posts {
+    pub posts: Vec<PostId>
+}
+
+async fn get_table_name() -> Option<String> {
+    env::var("TABLE_NAME").ok()
+}
+
+pub async fn make_config(opt: Opt) -> Result<SdkConfig, Error> {
+    let region_provider = make_region_provider(opt.region);
+
+    println!();
+    if opt.verbose {
+        println!("DynamoDB client version: {}", PKG_VERSION);
+        println!(
+            "Region:                  {}",
+            region_provider.region().await.unwrap().as_ref()
+        );
+        println!();
+    }
+
+    Ok(aws_config::from_env().region(region_provider).load().await)
+}
+
+pub fn make_region_provider(region: Option<String>) -> RegionProviderChain {
+    RegionProviderChain::first_try(region.map(Region::new))
+        .or_default_provider()
+        .or_else(Region::new("us-east-1"))
+}
+
+/// How a review went for one post
+#[derive(Debug, PartialEq)]
+pub enum ReviewOutcome {
+    /// The post's new version
+    Updated(u64),
+    Missing,
+    /// The post was edited after the reviewer loaded it
+    Conflict
+}
+
+/**
+ * The condition a review is made under: the post is still in the table,
+ * so one that was already published or deleted isn't recreated with
+ * nothing but a status, and it's still the version the reviewer saw.
+ */
+pub fn review_condition(version: u64) -> (String, Option<AttributeValue>) {
+    match version {
+        0 => (String::from("attribute_exists(#uuid) AND attribute_not_exists(version)"), None),
+        v => (String::from("attribute_exists(#uuid) AND version = :version"), Some(AttributeValue::N(v.to_string()))),
+    }
+}
+
+/// Sets the status of one post and bumps its version.
+pub async fn set_status(client: &DbClient, table: &str, post: &PostId, status: Status) -> Result<ReviewOutcome, Error> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let (condition, version) = review_condition(post.version);
+    let result = client.update_item()
+        .table_name(table)
+        .key("uuid", AttributeValue::S(post.uuid.clone()))
+        .update_expression("SET #status = :status, reviewed_at = :reviewed_at, version = if_not_exists(version, :zero) + :one")
+        .condition_expression(condition)
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_names("#uuid", "uuid")
+        .expression_attribute_values(":status", AttributeValue::S(status.as_str().to_string()))
+        .expression_attribute_values(":reviewed_at", AttributeValue::N(now.to_string()))
+        .expression_attribute_values(":zero", AttributeValue::N(String::from("0")))
+        .expression_attribute_values(":one", AttributeValue::N(String::from("1")))
+        .return_values(ReturnValue::UpdatedNew);
+    let result = match version {
+        Some(v) => result.expression_attribute_values(":version", v),
+        None => result,
+    }.send().await;
+    match result {
+        Ok(output) => Ok(ReviewOutcome::Updated(output.attributes
+            .and_then(|a| a.get("version").and_then(|v| v.as_n().ok()).and_then(|v| v.parse().ok()))
+            .unwrap_or(0))),
+        Err(e) if e.as_service_error().map(|s| s.is_conditional_check_failed_exception()).unwrap_or(false) => {
+            // The condition doesn't say which part failed, so look
+            let existing = client.get_item()
+                .table_name(table)
+                .key("uuid", AttributeValue::S(post.uuid.clone()))
+                .projection_expression("#uuid")
+                .expression_attribute_names("#uuid", "uuid")
+                .send().await?;
+            match existing.item {
+                Some(_) => Ok(ReviewOutcome::Conflict),
+                None => Ok(ReviewOutcome::Missing)
+            }
+        },
+        Err(e) => Err(e.into())
+    }
+}
+
+pub async fn handler(request: Request, db_client: &DbClient) -> Result<Response<String>, Error> {
+    // 1. Work out whether we're approving or rejecting
+    let status = match Status::from_path(request.uri().path()) {
+        Some(s) => s,
+        None => return ApiError::not_found(&format!("Unknown review path: {}", request.uri().path())).into_response(),
+    };
+
+    // 2. Get table name from env
+    let table_name = match get_table_name().await {
+        Some(t) => t,
+        None => return ApiError::config("TABLE_NAME not set").into_response(),
+    };
+    let posts: Posts = match parse_body(request.body()) {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = check_post_count(posts.posts.len()) {
+        return e.into_response();
+    }
+    for (i, post) in posts.posts.iter().enumerate() {
+        if let Err(e) = check_uuid("uuid", &post.uuid) {
+            return e.within(&format!("posts[{}]", i)).into_response();
+        }
+    }
+    println!("Setting {:?} on {:?}", status, posts);
+
+    // 3. Update each post, keeping track of the ones we couldn't
+    let mut updated = Vec::new();
+    let mut versions = HashMap::new();
+    let mut missing = Vec::new();
+    let mut conflicts = Vec::new();
+    let mut failed = Vec::new();
+    for post in posts.posts {
+        match set_status(db_client, &table_name, &post, status).await {
+            Ok(ReviewOutcome::Updated(version)) => {
+                versions.insert(post.uuid.clone(), version);
+                updated.push(post.uuid);
+            },
+            Ok(ReviewOutcome::Missing) => missing.push(post.uuid),
+            Ok(ReviewOutcome::Conflict) => conflicts.push(post.uuid),
+            Err(e) => {
+                println!("Failed updating {} {:?}", post.uuid, e);
+                failed.push(post.uuid);
+            }
+        };
+    }
+    // An edit since the reviewer loaded the post means they haven't seen its text
+    let code = if !conflicts.is_empty() {
+        409
+    } else if !missing.is_empty() {
+        404
+    } else {
+        200
+    };
+    respond_json(code, &json!({
+        "status": status,
+        "updated": updated,
+        "versions": versions,
+        "missing": missing,
+        "conflicts": conflicts,
+        "failed": failed
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    #[test]
+    fn test_status_from_path() {
+        assert_eq!(Status::from_path("/prod/approvePosts"), Some(Status::Approved));
+        assert_eq!(Status::from_path("/rejectPosts/"), Some(Status::Rejected));
+        assert_eq!(Status::from_path("/editPosts"), None);
+        assert_eq!(serde_json::to_string(&Status::Approved).unwrap(), "\"approved\"");
+    }
+
+    #[test]
+    fn test_review_condition() {
+        assert_eq!(review_condition(0), (String::from("attribute_exists(#uuid) AND attribute_not_exists(version)"), None));
+        assert_eq!(review_condition(3), (String::from("attribute_exists(#uuid) AND version = :version"), Some(AttributeValue::N(String::from("3")))));
+        let posts: Result<Posts, ApiError> = parse_body(br#"{"posts": [{"uuid": "abc"}]}"#);
+        assert_eq!(posts.unwrap_err().field, Some(String::from("version")));
+    }
+
+    #[test]
+    fn test_set_status() {
+        let opt = Opt {
+            region: Some("us-east-1".to_string()),
+            verbose: true,
+        };
+        let config = aw!(make_config(opt)).unwrap();
+        let db_client = DbClient::new(&config);
+        let table_name = String::from("Posts");
+        let post = PostId {
+            uuid: String::from("df6381e1-1cd1-4c5e-8442-11745c43c7d7"),
+            version: 0
+        };
+        let resp = aw!(set_status(&db_client, &table_name, &post, Status::Approved));
+        println!("Response: {:?}", resp);
+    }
+}
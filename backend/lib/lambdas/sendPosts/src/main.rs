@@ -37,7 +37,13 @@ use aws_sdk_dynamodb::operation::get_item::GetItemInput;
 use lambda_runtime::{LambdaEvent};
 use std::fmt;
 use std::error::Error as StdError;
-use chrono::{Local, NaiveTime, DateTime, Timelike};
+use chrono::{Local, NaiveTime, DateTime, Timelike, Utc};
+use shared::duplicates::DuplicateConfig;
+use shared::moderation::{quarantine_post, Moderator, QUARANTINED};
+use shared::schedule::{mark_sent, ScheduledRecord};
+
+// How many approved posts we try before giving up when they keep failing moderation
+const MAX_MODERATION_ATTEMPTS: usize = 5;
 
 
 
@@ -96,7 +102,13 @@ pub trait SocialPost {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Post {
     pub uuid: String,
-    pub post: String
+    pub post: String,
+    /// A thread's posts in order, posted as a chain of replies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub segments: Option<Vec<String>>,
+    /// The article a generated post came from, publishers can link to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub article_url: Option<String>
 }
 
 impl SocialPost for Post {
@@ -161,6 +173,10 @@ async fn get_scheduled_table_name() -> Option<String> {
     env::var("SCHEDULED_TABLE_NAME").ok()
 }
 
+async fn get_history_table_name() -> Option<String> {
+    env::var("HISTORY_TABLE_NAME").ok()
+}
+
 async fn get_sns_arn() -> Option<String> {
     env::var("SNS_ARN").ok()
 }
@@ -176,76 +192,105 @@ async fn delete_post_from_db(client: &DbClient, table_name: &str, uuid: String)
     Ok(())
 }
 
-async fn check_scheduled_posts(client: &DbClient, table_name: &str) -> Result<Option<ScheduledPost>, Error> {
-    let response = match client.scan()
+/**
+ * Keeps a copy of every published post so new posts can be checked
+ * against what went out recently. published_at is in epoch seconds, the
+ * table's TTL removes the copy at expires_at, once it's past the window
+ * duplicates are checked in.
+ */
+async fn record_history(client: &DbClient, table_name: &str, post: &Post) -> Result<(), Error> {
+    let published_at = Local::now().timestamp() as u64;
+    client.put_item()
         .table_name(table_name)
-        .send().await {
-            Ok(r) => r,
-            Err(_) => return Ok(None)
-        };
+        .item("uuid", AttributeValue::S(Uuid::new_v4().to_string()))
+        .item("post_uuid", AttributeValue::S(post.uuid.clone()))
+        .item("post", AttributeValue::S(post.post.clone()))
+        .item("published_at", AttributeValue::N(published_at.to_string()))
+        .item("expires_at", AttributeValue::N(DuplicateConfig::from_env().history_expires_at(published_at).to_string()))
+        .send().await?;
 
-    let items = response.items.ok_or_else(|| MyError::new("No items found in response"))?;
-    let mut posts: Vec<ScheduledPost> = Vec::new();
+    Ok(())
+}
 
-    for item in items {
-        let post: String = item
-            .get("post")
-            .ok_or_else(|| MyError::new("Missing 'post' attribute"))?
-            .as_s()
-            .or_else(|_| Err(MyError::new("Error getting post S attribute")))?
-            .to_string();
-
-        let uuid: String = item
-            .get("uuid")
-            .ok_or_else(|| MyError::new("Missing 'uuid' attribute"))?
-            .as_s()
-            .or_else(|_| Err(MyError::new("Error getting uuid S attribute")))?
-            .to_string();
-
-        let time: String = item
-            .get("time")
-            .ok_or_else(|| MyError::new("Missing 'time' attribute"))?
-            .as_s()
-            .or_else(|_| Err(MyError::new("Error getting time S attribute")))?
-            .to_string();
-
-        let recurring: bool = *item
-            .get("recurring")
-            .ok_or_else(|| MyError::new("Missing 'recurring' attribute"))?
-            .as_bool()
-            .or_else(|_| Err(MyError::new("Error getting recurring Bool attribute")))?;
-
-        posts.push(ScheduledPost{post, uuid, time, recurring});
-    }
-    println!("Looking through all scheduled posts: {}", posts.len());
-    let now = Local::now();
-    let filtered_posts: Vec<ScheduledPost> = posts.into_iter()
-        .filter(|post| {
-            let scheduled_time: NaiveTime = match NaiveTime::from_str(&post.time) {
-                Ok(t) => t,
+/**
+ * Finds a scheduled post that's due. The scan is paged so posts past the
+ * first page still go out, and a row that can't be read is skipped rather
+ * than holding up every other scheduled post.
+ */
+async fn check_scheduled_posts(client: &DbClient, table_name: &str) -> Result<Option<ScheduledPost>, Error> {
+    let mut posts: Vec<ScheduledPost> = Vec::new();
+    let now = Utc::now();
+    let mut start_key = None;
+    loop {
+        let response = client.scan()
+            .table_name(table_name)
+            .set_exclusive_start_key(start_key)
+            .send().await?;
+
+        for item in response.items.unwrap_or_default() {
+            let record = match ScheduledRecord::from_item(&item) {
+                Ok(r) => r,
+                Err(e) => {
+                    let uuid = item.get("uuid").and_then(|u| u.as_s().ok());
+                    println!("Skipping scheduled post {:?}: {}", uuid, e);
+                    continue;
+                }
+            };
+            // Quarantined scheduled posts wait for someone to fix them
+            if record.status.as_deref() == Some(QUARANTINED) {
+                continue;
+            }
+            match record.is_due(now) {
+                Ok(true) => {},
+                Ok(false) => continue,
                 Err(e) => {
-                    println!("Error parsing: {} {}", post.time, e.to_string());
-                    return false;
+                    println!("Error checking {}: {}", record.uuid, e);
+                    continue;
                 }
             };
-            println!("Comparing scheduled hour: {} to now: {}", scheduled_time.hour(), now.hour());
-            scheduled_time.hour() == now.hour()
-        })
-        .collect::<Vec<ScheduledPost>>();
-    let item = filtered_posts.first().cloned().ok_or_else(|| MyError::new("No Scheduled Posts"))?;
-    
-    Ok(Some(item))
+            posts.push(ScheduledPost {
+                post: record.post,
+                uuid: record.uuid,
+                time: record.time,
+                recurring: record.recurring
+            });
+        }
+        match response.last_evaluated_key {
+            Some(k) => start_key = Some(k),
+            None => break,
+        }
+    }
+    println!("Scheduled posts due now: {}", posts.len());
+
+    Ok(posts.into_iter().next())
 }
 
+/**
+ * Finds an approved post. The status filter runs after each page is read,
+ * so we keep paging until a page has one. Posts added before review
+ * existed have no status and were already cleared to go out, so they count
+ * as approved.
+ */
 async fn get_new_post_from_db(client: &DbClient, table_name: &str) -> Result<Post, Error> {
-    let response = client.scan()
-        .table_name(table_name)
-        .limit(1)
-        .send().await?;
-
-    println!("DynamoDB Response: {:?}", response);
-    let items = response.items.ok_or_else(|| MyError::new("No items found in response"))?;
-    let item = items.first().ok_or_else(|| MyError::new("No items found in response"))?;
+    let mut start_key = None;
+    let item = loop {
+        let response = client.scan()
+            .table_name(table_name)
+            .filter_expression("#status = :approved OR attribute_not_exists(#status)")
+            .expression_attribute_names("#status", "status")
+            .expression_attribute_values(":approved", AttributeValue::S(String::from("approved")))
+            .set_exclusive_start_key(start_key)
+            .send().await?;
+
+        println!("DynamoDB Response: {:?}", response);
+        if let Some(item) = response.items.and_then(|items| items.into_iter().next()) {
+            break item;
+        }
+        match response.last_evaluated_key {
+            Some(k) => start_key = Some(k),
+            None => return Err(MyError::new("No approved posts found").into()),
+        }
+    };
 
     let content: String = item
         .get("post")
@@ -261,9 +306,27 @@ async fn get_new_post_from_db(client: &DbClient, table_name: &str) -> Result<Pos
         .or_else(|_| Err(MyError::new("Error getting uuid S attribute")))?
         .to_string();
 
+    // Only threads have segments
+    let segments: Option<Vec<String>> = match item.get("segments") {
+        Some(s) => Some(s
+            .as_l()
+            .or_else(|_| Err(MyError::new("Error getting segments L attribute")))?
+            .iter()
+            .filter_map(|v| v.as_s().ok().cloned())
+            .collect()),
+        None => None,
+    };
+
+    let article_url: Option<String> = item
+        .get("article_url")
+        .and_then(|u| u.as_s().ok())
+        .cloned();
+
     Ok(Post {
         uuid: uuid,
-        post: content
+        post: content,
+        segments,
+        article_url
     })
 }
 
@@ -302,32 +365,75 @@ async fn worker() -> Result<String, Error> {
         }
     };
 
+    let moderator = match Moderator::from_env() {
+        Ok(m) => m,
+        Err(e) => return Ok(format!("Failed: {}", e)),
+    };
+
     // 2. Check Scheduled Table First
-    let scheduled_post: Option<ScheduledPost> = match check_scheduled_posts(&db_client, &scheduled_table_name).await {
+    // A scan that fails stops the run, sending a queued post instead could skip a due one
+    let mut scheduled_post: Option<ScheduledPost> = match check_scheduled_posts(&db_client, &scheduled_table_name).await {
         Ok(s) => s,
-        Err(e) => None
+        Err(e) => return Ok(format!("Failed checking scheduled posts: {}", e)),
     };
+    if let Some(s_post) = &scheduled_post {
+        // Posts are checked again right before they go out, the rules may have changed
+        match moderator.moderate(&s_post.post).await {
+            Ok(reasons) if reasons.is_empty() => {},
+            Ok(reasons) => {
+                println!("Quarantining scheduled post {}: {:?}", s_post.uuid, reasons);
+                if let Err(e) = quarantine_post(&db_client, &scheduled_table_name, &s_post.uuid, &reasons).await {
+                    println!("{}", e);
+                }
+                scheduled_post = None;
+            },
+            Err(e) => return Ok(format!("Failed moderating: {}", e)),
+        }
+    }
 
     // 3. Get a new post from DB
     let mut message: Option<Post> = None;
     let mut uuid_to_delete: Option<String> = None;
+    let mut recurring_sent: Option<String> = None;
     let mut table_to_delete_from = &String::new();
 
     if let Some(s_post) = scheduled_post {
         println!("Sending a Scheduled Post");
         message = Some(Post {
             uuid: s_post.uuid.clone(),
-            post: s_post.post
+            post: s_post.post,
+            segments: None,
+            article_url: None
         });
-        uuid_to_delete = match s_post.recurring {
-            false => Some(s_post.uuid),
-            true => None,
+        match s_post.recurring {
+            false => uuid_to_delete = Some(s_post.uuid),
+            true => recurring_sent = Some(s_post.uuid),
         };
         table_to_delete_from = &scheduled_table_name;
     } else {
-        let post = match get_new_post_from_db(&db_client, &table_name).await {
-            Ok(p) => p,
-            Err(e) => return Ok(format!("Failed: {:?}", e)),
+        let mut attempts = 0;
+        let post = loop {
+            let post = match get_new_post_from_db(&db_client, &table_name).await {
+                Ok(p) => p,
+                Err(e) => return Ok(format!("Failed: {:?}", e)),
+            };
+            // A post we can't check isn't published, it's tried again next hour
+            let reasons = match moderator.moderate(&post.post).await {
+                Ok(r) => r,
+                Err(e) => return Ok(format!("Failed moderating: {}", e)),
+            };
+            if reasons.is_empty() {
+                break post;
+            }
+            println!("Quarantining {}: {:?}", post.uuid, reasons);
+            if let Err(e) = quarantine_post(&db_client, &table_name, &post.uuid, &reasons).await {
+                // Stop rather than pick the same post again
+                return Ok(format!("Failed: {}", e));
+            }
+            attempts += 1;
+            if attempts == MAX_MODERATION_ATTEMPTS {
+                return Ok(format!("Failed: {} posts in a row failed moderation", attempts));
+            }
         };
         println!("Sending a normal post");
         message = Some(post.clone());
@@ -345,18 +451,33 @@ async fn worker() -> Result<String, Error> {
         }
     };
 
+    let message = message.unwrap();
     let sns_client = SnsClient::new(&config);
     match sns_client.publish()
         .topic_arn(sns_arn)
         .message_group_id(Uuid::new_v4().to_string())
-        .message(serde_json::to_string(&message.unwrap()).unwrap())
+        .message(serde_json::to_string(&message).unwrap())
         .send().await {
             Ok(output) => println!("Successfully send! {:?}", output),
             Err(e) => return Ok(format!("Failed :/ {:?}", e)),
         };
     println!("Published!");
 
-    // 5. Delete post from DB
+    // 5. Remember what we published
+    if let Some(history_table) = get_history_table_name().await {
+        match record_history(&db_client, &history_table, &message).await {
+            Ok(_) => println!("Recorded in history"),
+            Err(e) => println!("Failed recording history {:?}", e),
+        };
+    }
+
+    // 6. Delete post from DB, recurring posts stay and remember when they were sent
+    if let Some(uuid) = recurring_sent {
+        match mark_sent(&db_client, &scheduled_table_name, &uuid).await {
+            Ok(_) => println!("Marked {} sent", uuid),
+            Err(e) => println!("{}", e),
+        };
+    }
     if let Some(uuid) = uuid_to_delete {
         match delete_post_from_db(&db_client, table_to_delete_from, uuid).await {
             Ok(s) => return Ok(format!("Success!")),
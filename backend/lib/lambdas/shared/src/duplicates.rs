@@ -0,0 +1,242 @@
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client as DbClient;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::env;
+
+use crate::similarity::{find_duplicate, KnownPost};
+use crate::storage::{now, PostRecord, StorageError, STATUSES, STATUS_INDEX};
+
+// Posts this recent still count when checking for duplicates
+const DEFAULT_HISTORY_DAYS: u64 = 30;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// How similar two posts have to be to count as duplicates
+const DEFAULT_DUPLICATE_THRESHOLD: f64 = 0.7;
+
+/**
+ * What to do with a post that's too similar to one we already have.
+ * Flagged posts are added with the uuid of the post they duplicate.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicateMode {
+    Drop,
+    Flag,
+    Off
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DuplicateConfig {
+    pub mode: DuplicateMode,
+    pub threshold: f64,
+    pub history_days: u64
+}
+
+impl DuplicateConfig {
+    /**
+     * Configuration:
+     * DUPLICATE_CHECK: drop (default), flag or off
+     * DUPLICATE_THRESHOLD: 0 to 1, defaults to 0.7
+     * HISTORY_DAYS: how far back posts are checked, defaults to 30. It's
+     * also how long PostHistory keeps a published post.
+     */
+    pub fn from_env() -> DuplicateConfig {
+        let mode = match env::var("DUPLICATE_CHECK").as_deref() {
+            Ok("flag") => DuplicateMode::Flag,
+            Ok("off") => DuplicateMode::Off,
+            _ => DuplicateMode::Drop
+        };
+        DuplicateConfig {
+            mode,
+            threshold: env::var("DUPLICATE_THRESHOLD").ok()
+                .and_then(|t| t.parse().ok())
+                .unwrap_or(DEFAULT_DUPLICATE_THRESHOLD),
+            history_days: env::var("HISTORY_DAYS").ok()
+                .and_then(|d| d.parse().ok())
+                .unwrap_or(DEFAULT_HISTORY_DAYS)
+        }
+    }
+
+    /// Epoch seconds from which posts are checked
+    pub fn since(&self) -> u64 {
+        now().saturating_sub(self.history_days * SECONDS_PER_DAY)
+    }
+
+    /// When DynamoDB's TTL can remove a post published at `published_at` from PostHistory
+    pub fn history_expires_at(&self, published_at: u64) -> u64 {
+        published_at + self.history_days * SECONDS_PER_DAY
+    }
+}
+
+fn known_post(item: &HashMap<String, AttributeValue>) -> Option<KnownPost> {
+    match (item.get("uuid"), item.get("post")) {
+        (Some(AttributeValue::S(uuid)), Some(AttributeValue::S(post))) => Some(KnownPost::new(uuid.clone(), post)),
+        _ => None
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DuplicatePost {
+    pub post: String,
+    pub duplicate_of: String,
+    pub similarity: f64
+}
+
+/**
+ * Reads every post in a table, following scan pages. With `since` (epoch
+ * seconds) only posts published at or after then are read.
+ */
+pub async fn scan_known_posts(client: &DbClient, table: &str, since: Option<u64>) -> Result<Vec<KnownPost>, StorageError> {
+    let mut known = Vec::new();
+    let mut start_key = None;
+    loop {
+        let mut scan = client.scan()
+            .table_name(table)
+            .set_exclusive_start_key(start_key);
+        if let Some(s) = since {
+            scan = scan.filter_expression("published_at >= :since")
+                .expression_attribute_values(":since", AttributeValue::N(s.to_string()));
+        }
+        let response = match scan.send().await {
+            Ok(r) => r,
+            Err(e) => return Err(StorageError {
+                message: format!("Failed reading {}: {}", table, e)
+            })
+        };
+        known.extend(response.items().unwrap_or_default().iter().filter_map(known_post));
+        match response.last_evaluated_key() {
+            Some(k) => start_key = Some(k.clone()),
+            None => break
+        }
+    }
+    Ok(known)
+}
+
+/**
+ * Reads the posts created at or after `since` (epoch seconds) through the
+ * status index, one status at a time, so only those posts are read rather
+ * than the whole table. Posts from before created_at aren't in the index.
+ */
+pub async fn query_recent_posts(client: &DbClient, table: &str, since: u64) -> Result<Vec<KnownPost>, StorageError> {
+    let mut known = Vec::new();
+    for status in STATUSES {
+        let mut start_key = None;
+        loop {
+            let response = match client.query()
+                .table_name(table)
+                .index_name(STATUS_INDEX)
+                .key_condition_expression("#status = :status AND created_at >= :since")
+                .expression_attribute_names("#status", "status")
+                .expression_attribute_values(":status", AttributeValue::S(String::from(status)))
+                .expression_attribute_values(":since", AttributeValue::N(since.to_string()))
+                .set_exclusive_start_key(start_key)
+                .send().await {
+                    Ok(r) => r,
+                    Err(e) => return Err(StorageError {
+                        message: format!("Failed reading {} posts from {}: {}", status, table, e)
+                    })
+                };
+            known.extend(response.items().unwrap_or_default().iter().filter_map(known_post));
+            match response.last_evaluated_key() {
+                Some(k) => start_key = Some(k.clone()),
+                None => break
+            }
+        }
+    }
+    Ok(known)
+}
+
+/**
+ * The posts created and the posts published in the last `history_days`.
+ * A table we can't read is skipped rather than blocking new posts.
+ */
+pub async fn load_known_posts(client: &DbClient, posts_table: &str, history_table: Option<&str>, config: DuplicateConfig) -> Vec<KnownPost> {
+    let mut known = Vec::new();
+    if config.mode == DuplicateMode::Off {
+        return known;
+    }
+    let since = config.since();
+    match query_recent_posts(client, posts_table, since).await {
+        Ok(k) => known.extend(k),
+        Err(e) => println!("{}", e)
+    };
+    if let Some(history_table) = history_table {
+        // The TTL keeps PostHistory to about this window, so the scan stays small
+        match scan_known_posts(client, history_table, Some(since)).await {
+            Ok(k) => known.extend(k),
+            Err(e) => println!("{}", e)
+        };
+    }
+    known
+}
+
+/**
+ * Checks each post against the posts we already have and the ones before
+ * it in the same request. Returns the posts to add and the duplicates that
+ * were dropped.
+ */
+pub fn filter_duplicates(posts: Vec<PostRecord>, mut known: Vec<KnownPost>, config: DuplicateConfig) -> (Vec<PostRecord>, Vec<DuplicatePost>) {
+    if config.mode == DuplicateMode::Off {
+        return (posts, Vec::new());
+    }
+    let mut kept = Vec::new();
+    let mut duplicates = Vec::new();
+    for mut post in posts {
+        match find_duplicate(&post.post, &known, config.threshold) {
+            Some(d) if config.mode == DuplicateMode::Drop => {
+                println!("Dropping duplicate of {}: {:?}", d.uuid, post.post);
+                duplicates.push(DuplicatePost {
+                    post: post.post,
+                    duplicate_of: d.uuid,
+                    similarity: d.similarity
+                });
+                continue;
+            },
+            Some(d) => post.duplicate_of = Some(d.uuid),
+            None => {}
+        }
+        // Later posts in the request are checked against this one too
+        known.push(KnownPost::new(post.uuid.clone(), &post.post));
+        kept.push(post);
+    }
+    (kept, duplicates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_duplicates() {
+        let known = vec![KnownPost::new(String::from("queued"), "Rest days are training days too. #HybridAthlete")];
+        let posts = vec![
+            PostRecord::new(String::from("Rest days are training days too! #HybridAthlete"), "approved"),
+            PostRecord::new(String::from("Strength keeps you running when your legs want to quit."), "approved"),
+            PostRecord::new(String::from("Strength keeps you running when your legs want to quit"), "approved"),
+        ];
+        let drop = DuplicateConfig { mode: DuplicateMode::Drop, threshold: DEFAULT_DUPLICATE_THRESHOLD, history_days: DEFAULT_HISTORY_DAYS };
+        let (kept, duplicates) = filter_duplicates(posts.clone(), known.clone(), drop);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(duplicates.len(), 2);
+        assert_eq!(duplicates[0].duplicate_of, "queued");
+        assert_eq!(duplicates[1].duplicate_of, posts[1].uuid);
+
+        let flag = DuplicateConfig { mode: DuplicateMode::Flag, ..drop };
+        let (kept, duplicates) = filter_duplicates(posts, known, flag);
+        assert_eq!(kept.len(), 3);
+        assert!(duplicates.is_empty());
+        assert_eq!(kept[0].duplicate_of, Some(String::from("queued")));
+        assert_eq!(kept[1].duplicate_of, None);
+    }
+
+    #[test]
+    fn test_history_window() {
+        let config = DuplicateConfig { mode: DuplicateMode::Drop, threshold: DEFAULT_DUPLICATE_THRESHOLD, history_days: 2 };
+        assert_eq!(config.history_expires_at(1_718_670_000), 1_718_670_000 + 2 * SECONDS_PER_DAY);
+        let since = config.since();
+        assert!(since <= now() - 2 * SECONDS_PER_DAY);
+        assert!(since >= now() - 2 * SECONDS_PER_DAY - 5);
+    }
+}
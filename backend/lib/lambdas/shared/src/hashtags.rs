@@ -0,0 +1,342 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::env;
+
+// Shipped with the lambdas, HASHTAG_POLICY overrides it without a redeploy of the code
+const DEFAULT_HASHTAG_POLICY: &str = include_str!("../config/hashtags.json");
+
+/**
+ * Where hashtags may go. End keeps them together after the text, Inline
+ * lets them appear anywhere.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Position {
+    End,
+    #[default]
+    Inline,
+}
+
+/// Extra rules for one platform, on top of the policy's own.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PlatformRules {
+    #[serde(default)]
+    pub max_count: Option<usize>,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+/**
+ * Which hashtags posts may use. An empty allow list allows every tag that
+ * isn't denied. Posts go out on every platform, so they have to follow the
+ * strictest of the platform rules.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HashtagPolicy {
+    #[serde(default)]
+    pub max_count: Option<usize>,
+    #[serde(default)]
+    pub position: Position,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Tags every post from a source has to carry, by source name
+    #[serde(default)]
+    pub required: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub platforms: HashMap<String, PlatformRules>,
+}
+
+// Tags are compared without the # and ignoring case
+fn tag_key(tag: &str) -> String {
+    tag.trim_start_matches('#').to_lowercase()
+}
+
+fn is_tag_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn is_hashtag(word: &str) -> bool {
+    match word.strip_prefix('#') {
+        Some(rest) => !rest.is_empty() && rest.chars().all(is_tag_char),
+        None => false,
+    }
+}
+
+fn listed(list: &[String], tag: &str) -> bool {
+    list.iter().any(|t| tag_key(t) == tag_key(tag))
+}
+
+fn permitted_by(allow: &[String], deny: &[String], tag: &str) -> bool {
+    (allow.is_empty() || listed(allow, tag)) && !listed(deny, tag)
+}
+
+/// Every hashtag in the text, in order.
+pub fn extract_hashtags(text: &str) -> Vec<String> {
+    let mut tags = Vec::new();
+    let mut previous = ' ';
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == '#' && (previous.is_whitespace() || previous == '(') {
+            let mut end = i + 1;
+            while let Some(&(j, next)) = chars.peek() {
+                if !is_tag_char(next) {
+                    break;
+                }
+                end = j + next.len_utf8();
+                chars.next();
+            }
+            if end > i + 1 {
+                tags.push(text[i..end].to_string());
+            }
+            previous = text[..end].chars().last().unwrap_or(c);
+            continue;
+        }
+        previous = c;
+    }
+    tags
+}
+
+/**
+ * Splits off the hashtags at the end of the text, returning the text
+ * before them and the tags.
+ */
+fn split_trailing(text: &str) -> (&str, Vec<String>) {
+    let mut end = text.trim_end().len();
+    let mut tags = Vec::new();
+    while end > 0 {
+        let head = &text[..end];
+        let start = head.char_indices().rev()
+            .find(|(_, c)| c.is_whitespace())
+            .map(|(i, c)| i + c.len_utf8())
+            .unwrap_or(0);
+        if !is_hashtag(&head[start..]) {
+            break;
+        }
+        tags.insert(0, head[start..].to_string());
+        end = head[..start].trim_end().len();
+    }
+    (&text[..end], tags)
+}
+
+/**
+ * Rewrites the hashtags in the text. `keep` decides, tag by tag, whether a
+ * tag stays a hashtag or becomes a plain word.
+ */
+fn rewrite_hashtags<F: FnMut(&str) -> bool>(text: &str, mut keep: F) -> String {
+    let tags = extract_hashtags(text);
+    let mut rewritten = String::with_capacity(text.len());
+    let mut rest = text;
+    for tag in tags {
+        // Tags were found in order, so each one is in what's left
+        let start = match rest.find(tag.as_str()) {
+            Some(s) => s,
+            None => continue,
+        };
+        rewritten.push_str(&rest[..start]);
+        if keep(&tag) {
+            rewritten.push_str(&tag);
+        } else {
+            rewritten.push_str(&tag[1..]);
+        }
+        rest = &rest[start + tag.len()..];
+    }
+    rewritten.push_str(rest);
+    rewritten
+}
+
+impl HashtagPolicy {
+    /// The shipped policy, or HASHTAG_POLICY if it's set.
+    pub fn load() -> Result<HashtagPolicy, String> {
+        let raw = env::var("HASHTAG_POLICY").unwrap_or(String::from(DEFAULT_HASHTAG_POLICY));
+        serde_json::from_str(&raw).map_err(|e| format!("Invalid hashtag policy: {}", e))
+    }
+
+    /// Whether the policy and every platform's rules allow the tag.
+    pub fn permits(&self, tag: &str) -> bool {
+        permitted_by(&self.allow, &self.deny, tag)
+            && self.platforms.values().all(|p| permitted_by(&p.allow, &p.deny, tag))
+    }
+
+    /// The lowest maximum of the policy and the platforms, if any has one.
+    pub fn max_count(&self) -> Option<usize> {
+        self.platforms.values()
+            .filter_map(|p| p.max_count)
+            .chain(self.max_count)
+            .min()
+    }
+
+    pub fn required_tags(&self, source: Option<&str>) -> Vec<String> {
+        source.and_then(|s| self.required.get(s)).cloned().unwrap_or_default()
+    }
+
+    /**
+     * Everything about the text's hashtags that breaks the policy. Required
+     * tags are only checked when the post has a source.
+     */
+    pub fn check(&self, text: &str, source: Option<&str>) -> Vec<String> {
+        let mut violations = Vec::new();
+        let tags = extract_hashtags(text);
+        for tag in tags.iter().filter(|t| !self.permits(t)) {
+            violations.push(format!("{} isn't allowed", tag));
+        }
+        if let Some(max) = self.max_count() {
+            if tags.len() > max {
+                violations.push(format!("{} hashtags, the limit is {}", tags.len(), max));
+            }
+        }
+        if self.position == Position::End {
+            let (body, _) = split_trailing(text);
+            if !extract_hashtags(body).is_empty() {
+                violations.push(String::from("Hashtags have to go at the end"));
+            }
+        }
+        for tag in self.required_tags(source) {
+            if !listed(&tags, &tag) {
+                violations.push(format!("Missing required hashtag {}", tag));
+            }
+        }
+        violations
+    }
+
+    /**
+     * Checks a thread. Each segment is posted on its own, so each has to
+     * follow the policy, but only the last one needs the required tags.
+     */
+    pub fn check_segments(&self, segments: &[String], source: Option<&str>) -> Vec<String> {
+        let last = segments.len().saturating_sub(1);
+        segments.iter().enumerate()
+            .flat_map(|(i, segment)| {
+                let segment_source = if i == last { source } else { None };
+                self.check(segment, segment_source).into_iter()
+                    .map(move |v| format!("Segment {}: {}", i + 1, v))
+            })
+            .collect()
+    }
+
+    /**
+     * Rewrites the text to follow the policy: tags that aren't allowed or
+     * are over the limit become plain words, with End inline tags move to
+     * the end, and missing required tags are added. Required tags always
+     * make the cut.
+     */
+    pub fn apply(&self, text: &str, source: Option<&str>) -> String {
+        let required = self.required_tags(source);
+        let mut budget = self.max_count()
+            .map(|max| max.saturating_sub(required.len()))
+            .unwrap_or(usize::MAX);
+        let mut kept: Vec<String> = Vec::new();
+        let mut moved: Vec<String> = Vec::new();
+        let (body, trailing) = split_trailing(text);
+
+        let body = rewrite_hashtags(body, |tag| {
+            if !self.permits(tag) || listed(&kept, tag) {
+                return false;
+            }
+            if listed(&required, tag) {
+                kept.push(tag.to_string());
+                return self.position == Position::Inline;
+            }
+            if budget == 0 {
+                return false;
+            }
+            budget -= 1;
+            kept.push(tag.to_string());
+            if self.position == Position::End {
+                moved.push(tag.to_string());
+                return false;
+            }
+            true
+        });
+
+        let mut ending: Vec<String> = Vec::new();
+        for tag in required.iter().filter(|t| self.position == Position::End || !listed(&kept, t)) {
+            let tag = format!("#{}", tag.trim_start_matches('#'));
+            if !listed(&ending, &tag) {
+                ending.push(tag);
+            }
+        }
+        for tag in moved.into_iter().chain(trailing) {
+            if listed(&ending, &tag) || listed(&required, &tag) || !self.permits(&tag) {
+                continue;
+            }
+            if listed(&kept, &tag) {
+                // Moved from the text, it already used its share of the budget
+                ending.push(tag);
+                continue;
+            }
+            if budget == 0 {
+                continue;
+            }
+            budget -= 1;
+            ending.push(tag);
+        }
+
+        let body = body.trim_end();
+        match (body.is_empty(), ending.is_empty()) {
+            (_, true) => body.to_string(),
+            (true, false) => ending.join(" "),
+            (false, false) => format!("{} {}", body, ending.join(" ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> HashtagPolicy {
+        let mut policy: HashtagPolicy = serde_json::from_str(DEFAULT_HASHTAG_POLICY).unwrap();
+        policy.required.insert(String::from("substack"), vec![String::from("#RunWithJoy")]);
+        policy
+    }
+
+    #[test]
+    fn test_extract_hashtags() {
+        assert_eq!(
+            extract_hashtags("Lift #twice a week (#strength). #HybridAthlete #Fitness_Life"),
+            vec!["#twice", "#strength", "#HybridAthlete", "#Fitness_Life"]
+        );
+        assert!(extract_hashtags("Runner#1 and a lone # sign").is_empty());
+    }
+
+    #[test]
+    fn test_check() {
+        let policy = policy();
+        assert_eq!(policy.max_count(), Some(2));
+        assert!(policy.check("Rest days count too. #RunWithJoy", Some("substack")).is_empty());
+        let violations = policy.check("A #HybridAthlete trains hard. #FitnessLife #Running #Strength", Some("substack"));
+        assert_eq!(violations, vec![
+            "#FitnessLife isn't allowed",
+            "4 hashtags, the limit is 2",
+            "Hashtags have to go at the end",
+            "Missing required hashtag #RunWithJoy",
+        ]);
+        // Posts without a source don't need the required tags
+        assert!(policy.check("Rest days count too.", None).is_empty());
+
+        let segments = vec![String::from("One #Running"), String::from("Two #RunWithJoy")];
+        assert!(policy.check_segments(&segments, Some("substack")).is_empty());
+    }
+
+    #[test]
+    fn test_apply() {
+        let policy = policy();
+        let applied = policy.apply("A #HybridAthlete trains hard. #FitnessLife #Running #Strength", Some("substack"));
+        assert_eq!(applied, "A HybridAthlete trains hard. #RunWithJoy #HybridAthlete");
+        assert!(policy.check(&applied, Some("substack")).is_empty());
+
+        // Nothing to change
+        assert_eq!(policy.apply("Run with joy. #RunWithJoy", Some("substack")), "Run with joy. #RunWithJoy");
+
+        let inline = HashtagPolicy { position: Position::Inline, ..policy };
+        assert_eq!(
+            inline.apply("Be a #HybridAthlete, not a #Fitness fanatic #Running #Strength", None),
+            "Be a #HybridAthlete, not a Fitness fanatic #Running"
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use lambda_http::{Error, Response};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::json;
+
+// Longest post we store, DeSo's limit. X posts are split into threads.
+pub const MAX_POST_CHARS: usize = 10000;
+
+// The most posts one request may add, edit or delete
+pub const MAX_POSTS_PER_REQUEST: usize = 100;
+
+/**
+ * An error an HTTP lambda returns. The body is always
+ * {"code": ..., "message": ..., "field": ...} with field set when one
+ * input was to blame, so the frontend can show it next to that input.
+ */
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ApiError {
+    #[serde(skip)]
+    pub status: u16,
+    pub code: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(status: u16, code: &str, message: &str) -> ApiError {
+        ApiError {
+            status,
+            code: String::from(code),
+            message: String::from(message),
+            field: None,
+        }
+    }
+
+    pub fn invalid(field: &str, message: &str) -> ApiError {
+        ApiError {
+            field: Some(String::from(field)),
+            ..ApiError::new(400, "invalid_field", message)
+        }
+    }
+
+    pub fn not_found(message: &str) -> ApiError {
+        ApiError::new(404, "not_found", message)
+    }
+
+    /// The server is missing configuration, nothing the caller can fix
+    pub fn config(message: &str) -> ApiError {
+        ApiError::new(500, "config_error", message)
+    }
+
+    pub fn internal(message: &str) -> ApiError {
+        ApiError::new(500, "internal_error", message)
+    }
+
+    /**
+     * A body that isn't the JSON we expect. serde names a missing or
+     * mistyped field in its message, so we pull it out for `field`.
+     */
+    pub fn from_body_error(e: &serde_json::Error) -> ApiError {
+        let message = e.to_string();
+        let field = message.split('`').nth(1)
+            .filter(|_| message.starts_with("missing field") || message.starts_with("unknown field"))
+            .map(String::from);
+        ApiError {
+            status: 400,
+            code: String::from(if field.is_some() { "invalid_field" } else { "invalid_body" }),
+            message: format!("Invalid request body: {}", message),
+            field,
+        }
+    }
+
+    /// Puts the field under a prefix, like posts[2], for errors in a list.
+    pub fn within(mut self, prefix: &str) -> ApiError {
+        self.field = Some(match self.field {
+            Some(field) => format!("{}.{}", prefix, field),
+            None => String::from(prefix),
+        });
+        self
+    }
+
+    pub fn to_body(&self) -> String {
+        serde_json::to_string(self).unwrap_or(json!({ "code": self.code, "message": self.message }).to_string())
+    }
+
+    pub fn into_response(self) -> Result<Response<String>, Error> {
+        respond(self.status, self.to_body())
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.field {
+            Some(field) => write!(f, "{}: {}", field, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// A JSON response the frontend can read from any origin.
+pub fn respond(status: u16, body: String) -> Result<Response<String>, Error> {
+    Ok(Response::builder()
+        .status(status)
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Content-Type", "application/json")
+        .body(body)?)
+}
+
+pub fn respond_json<T: Serialize>(status: u16, body: &T) -> Result<Response<String>, Error> {
+    respond(status, serde_json::to_string(body)?)
+}
+
+pub fn parse_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, ApiError> {
+    if body.is_empty() {
+        return Err(ApiError::new(400, "invalid_body", "Request body is empty"));
+    }
+    serde_json::from_slice(body).map_err(|e| ApiError::from_body_error(&e))
+}
+
+/// A request has to have at least one post, and not so many we'd time out.
+pub fn check_post_count(count: usize) -> Result<(), ApiError> {
+    if count == 0 {
+        return Err(ApiError::invalid("posts", "No posts in the request"));
+    }
+    if count > MAX_POSTS_PER_REQUEST {
+        return Err(ApiError::invalid("posts", &format!("At most {} posts per request", MAX_POSTS_PER_REQUEST)));
+    }
+    Ok(())
+}
+
+pub fn check_post_text(field: &str, text: &str) -> Result<(), ApiError> {
+    if text.trim().is_empty() {
+        return Err(ApiError::invalid(field, "Post is empty"));
+    }
+    let length = text.chars().count();
+    if length > MAX_POST_CHARS {
+        return Err(ApiError::invalid(field, &format!("Post is {} characters, the most is {}", length, MAX_POST_CHARS)));
+    }
+    Ok(())
+}
+
+pub fn check_uuid(field: &str, uuid: &str) -> Result<(), ApiError> {
+    if uuid.trim().is_empty() {
+        return Err(ApiError::invalid(field, "uuid is empty"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct Body {
+        post: String,
+    }
+
+    #[test]
+    fn test_parse_body() {
+        let error = parse_body::<Body>(br#"{"text": "hi"}"#).unwrap_err();
+        assert_eq!(error.status, 400);
+        assert_eq!(error.field, Some(String::from("post")));
+        assert_eq!(parse_body::<Body>(b"{not json").unwrap_err().code, "invalid_body");
+        assert_eq!(parse_body::<Body>(b"").unwrap_err().code, "invalid_body");
+        assert!(parse_body::<Body>(br#"{"post": "hi"}"#).is_ok());
+
+        let error = check_post_text("post", " ").unwrap_err().within("posts[2]");
+        assert_eq!(error.to_body(), r#"{"code":"invalid_field","message":"Post is empty","field":"posts[2].post"}"#);
+        assert!(check_post_text("post", &"a".repeat(MAX_POST_CHARS + 1)).is_err());
+        assert!(check_post_count(0).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client as DbClient;
+use lambda_http::{Error, Request, Response};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+
+use crate::auth::Principal;
+use crate::http::{respond, ApiError};
+use crate::storage::now;
+
+pub const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+pub const MAX_KEY_LENGTH: usize = 255;
+
+// How long a key is remembered, DynamoDB's TTL removes the record some time after
+pub const DEFAULT_TTL_SECONDS: u64 = 24 * 60 * 60;
+
+// A request that died part way leaves its key in progress. Past the
+// longest lambda timeout a retry is allowed to take the key over.
+const IN_PROGRESS_TIMEOUT_SECONDS: u64 = 15 * 60;
+
+const IN_PROGRESS: &str = "in_progress";
+const COMPLETE: &str = "complete";
+
+/// The response the first request with a key got
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replay {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Replay {
+    pub fn into_response(self) -> Result<Response<String>, Error> {
+        respond(self.status, self.body)
+    }
+}
+
+/// A key this request has claimed, `finish` it with the response
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pending {
+    pub table: String,
+    pub principal: String,
+    pub scope: String,
+    pub key: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Start {
+    /// Handle the request, finishing the key if it has one
+    Proceed(Option<Pending>),
+    /// The key was already used for this request, send back the first response
+    Replay(Replay),
+}
+
+pub fn get_table_name() -> Option<String> {
+    env::var("IDEMPOTENCY_TABLE_NAME").ok()
+}
+
+pub fn ttl_seconds() -> u64 {
+    env::var("IDEMPOTENCY_TTL_SECONDS").ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(DEFAULT_TTL_SECONDS)
+}
+
+/// The request's Idempotency-Key header, None when it doesn't send one.
+pub fn idempotency_key(request: &Request) -> Result<Option<String>, ApiError> {
+    let value = match request.headers().get(IDEMPOTENCY_HEADER) {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let key = value.to_str()
+        .map_err(|_| ApiError::invalid(IDEMPOTENCY_HEADER, "Idempotency-Key has to be printable ASCII"))?
+        .trim();
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+        return Err(ApiError::invalid(IDEMPOTENCY_HEADER, &format!("Idempotency-Key has to be 1 to {} characters", MAX_KEY_LENGTH)));
+    }
+    Ok(Some(String::from(key)))
+}
+
+/**
+ * Keys are per caller and per endpoint, so two callers picking the same key,
+ * or one caller using it on /add and /addSchedule, don't collide.
+ */
+pub fn record_key(principal: &str, scope: &str, key: &str) -> String {
+    format!("{}#{}#{}", principal, scope, key)
+}
+
+/// Identifies the request body, so a key reused for a different request is caught.
+pub fn body_hash(body: &[u8]) -> String {
+    Sha256::digest(body).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/**
+ * What an existing record means for a request with the same key: replay
+ * it if it's done, otherwise the first request is still running.
+ */
+pub fn resolve(record: &HashMap<String, AttributeValue>, hash: &str) -> Result<Replay, ApiError> {
+    let get_s = |key: &str| record.get(key).and_then(|v| v.as_s().ok()).map(|s| s.as_str());
+    if get_s("request_hash") != Some(hash) {
+        return Err(ApiError::new(422, "idempotency_key_reused", "This Idempotency-Key was already used for a different request"));
+    }
+    if get_s("state") != Some(COMPLETE) {
+        return Err(ApiError::new(409, "request_in_progress", "A request with this Idempotency-Key is still being handled, try again shortly"));
+    }
+    let status = record.get("response_status")
+        .and_then(|v| v.as_n().ok())
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(200);
+    Ok(Replay {
+        status,
+        body: get_s("response_body").unwrap_or_default().to_string(),
+    })
+}
+
+/**
+ * Records the key as in progress, unless a live record for it is already
+ * there, in which case that record decides what the request gets. None
+ * means the key is ours.
+ */
+pub async fn claim(client: &DbClient, table: &str, principal: &str, scope: &str, key: &str, hash: &str) -> Result<Option<Replay>, ApiError> {
+    let id = record_key(principal, scope, key);
+    let created_at = now();
+    let result = client.put_item()
+        .table_name(table)
+        .item("key", AttributeValue::S(id.clone()))
+        .item("state", AttributeValue::S(String::from(IN_PROGRESS)))
+        .item("request_hash", AttributeValue::S(String::from(hash)))
+        .item("created_at", AttributeValue::N(created_at.to_string()))
+        .item("expires_at", AttributeValue::N((created_at + ttl_seconds()).to_string()))
+        // DynamoDB's TTL is lazy, so an expired record may still be there
+        .condition_expression("attribute_not_exists(#key) OR expires_at < :now OR (#state = :in_progress AND created_at < :stale)")
+        .expression_attribute_names("#key", "key")
+        .expression_attribute_names("#state", "state")
+        .expression_attribute_values(":now", AttributeValue::N(created_at.to_string()))
+        .expression_attribute_values(":in_progress", AttributeValue::S(String::from(IN_PROGRESS)))
+        .expression_attribute_values(":stale", AttributeValue::N(created_at.saturating_sub(IN_PROGRESS_TIMEOUT_SECONDS).to_string()))
+        .send().await;
+    match result {
+        Ok(_) => return Ok(None),
+        Err(e) if e.as_service_error().map(|s| s.is_conditional_check_failed_exception()).unwrap_or(false) => {},
+        Err(e) => return Err(ApiError::internal(&format!("Failed recording Idempotency-Key: {}", e))),
+    };
+    let existing = client.get_item()
+        .table_name(table)
+        .key("key", AttributeValue::S(id))
+        .consistent_read(true)
+        .send().await
+        .map_err(|e| ApiError::internal(&format!("Failed reading Idempotency-Key: {}", e)))?;
+    match existing.item {
+        Some(record) => resolve(&record, hash).map(Some),
+        // Expired and removed since the put, the retry will get it
+        None => Err(ApiError::new(409, "request_in_progress", "Idempotency-Key is changing hands, try again shortly")),
+    }
+}
+
+/**
+ * Claims the request's Idempotency-Key for the caller the guard let in and
+ * `scope`, the endpoint. Requests without one are handled as they always were.
+ */
+pub async fn start(client: &DbClient, request: &Request, scope: &str) -> Result<Start, ApiError> {
+    let key = match idempotency_key(request)? {
+        Some(k) => k,
+        None => return Ok(Start::Proceed(None)),
+    };
+    let principal = request.extensions().get::<Principal>()
+        .map(|p| p.name.clone())
+        .ok_or_else(|| ApiError::internal("Idempotency-Key used without a caller, is the handler behind the guard?"))?;
+    let table = get_table_name().ok_or_else(|| ApiError::config("IDEMPOTENCY_TABLE_NAME not set"))?;
+    match claim(client, &table, &principal, scope, &key, &body_hash(request.body())).await? {
+        Some(replay) => Ok(Start::Replay(replay)),
+        None => Ok(Start::Proceed(Some(Pending {
+            table,
+            principal,
+            scope: String::from(scope),
+            key,
+        }))),
+    }
+}
+
+impl Pending {
+    /**
+     * Saves the response for replays. A failed request forgets its key
+     * instead, so the client can retry it with the same key.
+     */
+    pub async fn finish(&self, client: &DbClient, status: u16, body: &str) {
+        let id = AttributeValue::S(record_key(&self.principal, &self.scope, &self.key));
+        let result = if status >= 500 {
+            client.delete_item()
+                .table_name(&self.table)
+                .key("key", id)
+                .send().await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        } else {
+            client.update_item()
+                .table_name(&self.table)
+                .key("key", id)
+                .update_expression("SET #state = :complete, response_status = :status, response_body = :body")
+                .expression_attribute_names("#state", "state")
+                .expression_attribute_values(":complete", AttributeValue::S(String::from(COMPLETE)))
+                .expression_attribute_values(":status", AttributeValue::N(status.to_string()))
+                .expression_attribute_values(":body", AttributeValue::S(String::from(body)))
+                .send().await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        };
+        // The response still goes out, a retry just finds the key in progress until it goes stale
+        if let Err(e) = result {
+            println!("Failed finishing Idempotency-Key {}: {}", self.key, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(state: &str, hash: &str) -> HashMap<String, AttributeValue> {
+        HashMap::from([
+            (String::from("state"), AttributeValue::S(String::from(state))),
+            (String::from("request_hash"), AttributeValue::S(String::from(hash))),
+            (String::from("response_status"), AttributeValue::N(String::from("200"))),
+            (String::from("response_body"), AttributeValue::S(String::from(r#"{"created":["abc"]}"#))),
+        ])
+    }
+
+    #[test]
+    fn test_resolve() {
+        let hash = body_hash(br#"{"posts": [{"post": "Easy miles today"}]}"#);
+        assert_eq!(hash.len(), 64);
+        assert_ne!(hash, body_hash(br#"{"posts": [{"post": "Hard miles today"}]}"#));
+
+        assert_eq!(resolve(&record(COMPLETE, &hash), &hash), Ok(Replay {
+            status: 200,
+            body: String::from(r#"{"created":["abc"]}"#)
+        }));
+        assert_eq!(resolve(&record(IN_PROGRESS, &hash), &hash).unwrap_err().status, 409);
+        assert_eq!(resolve(&record(COMPLETE, "other"), &hash).unwrap_err().code, "idempotency_key_reused");
+
+        let request = lambda_http::http::Request::builder()
+            .header(IDEMPOTENCY_HEADER, "retry-1")
+            .body(lambda_http::Body::Empty)
+            .unwrap();
+        assert_eq!(idempotency_key(&request).unwrap(), Some(String::from("retry-1")));
+
+        assert_eq!(record_key("newsletter-sync", "add", "retry-1"), "newsletter-sync#add#retry-1");
+        assert_ne!(record_key("webapp", "add", "retry-1"), record_key("newsletter-sync", "add", "retry-1"));
+    }
+}
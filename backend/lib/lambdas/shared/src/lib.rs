@@ -0,0 +1,10 @@
+pub mod auth;
+pub mod duplicates;
+pub mod hashtags;
+pub mod http;
+pub mod idempotency;
+pub mod links;
+pub mod moderation;
+pub mod schedule;
+pub mod similarity;
+pub mod storage;
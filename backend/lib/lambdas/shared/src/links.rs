@@ -0,0 +1,339 @@
+use reqwest::Url;
+use std::env;
+
+pub const NEWSLETTER_URL: &str = "https://davidjmeyer.substack.com";
+
+const CALL_TO_ACTION: &str = "If you like this kind of content, make sure to checkout my newsletter and remember, run with joy!";
+
+const ARTICLE_CALL_TO_ACTION: &str = "Read the full article and remember, run with joy!";
+
+// X counts every link as this many characters, however long it is
+pub const X_LINK_LENGTH: usize = 23;
+
+/**
+ * Where a post's article link goes when it has one. Off keeps the generic
+ * newsletter link in the reply, Append adds the article link to the post
+ * itself and Reply swaps it in for the newsletter link.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArticleLinkMode {
+    Off,
+    Append,
+    Reply,
+}
+
+impl std::str::FromStr for ArticleLinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ArticleLinkMode, String> {
+        match s {
+            "off" | "none" => Ok(ArticleLinkMode::Off),
+            "append" => Ok(ArticleLinkMode::Append),
+            "reply" => Ok(ArticleLinkMode::Reply),
+            _ => Err(format!("Unknown article link mode {}", s)),
+        }
+    }
+}
+
+impl ArticleLinkMode {
+    /// ARTICLE_LINK_MODE: off (default), append or reply
+    pub fn from_env() -> ArticleLinkMode {
+        env::var("ARTICLE_LINK_MODE").ok()
+            .and_then(|m| m.parse().ok())
+            .unwrap_or(ArticleLinkMode::Off)
+    }
+}
+
+/**
+ * What a publisher posts: the post itself and the reply that follows it.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Publication {
+    pub body: String,
+    pub comment: String,
+}
+
+pub fn newsletter_comment() -> String {
+    format!("{} {}", CALL_TO_ACTION, NEWSLETTER_URL)
+}
+
+/**
+ * Adds the article link to a post. When appending would take the post
+ * over `max_length` (with links counted as `link_length` characters) the
+ * link goes in the reply instead. A blank url counts as no article.
+ */
+pub fn with_article_link(body: String, article_url: Option<&str>, mode: ArticleLinkMode, max_length: usize, link_length: usize) -> Publication {
+    let url = match article_url.map(str::trim) {
+        Some(u) if !u.is_empty() && mode != ArticleLinkMode::Off => u,
+        _ => return Publication {
+            body,
+            comment: newsletter_comment(),
+        },
+    };
+    let article_comment = format!("{} {}", ARTICLE_CALL_TO_ACTION, url);
+    if mode == ArticleLinkMode::Append && body.chars().count() + 2 + link_length <= max_length {
+        return Publication {
+            body: format!("{}\n\n{}", body, url),
+            comment: newsletter_comment(),
+        };
+    }
+    Publication {
+        body,
+        comment: article_comment,
+    }
+}
+
+/**
+ * UTM parameters added to links to our own sites, so newsletter analytics
+ * can tell which platform and post the traffic came from. utm_source is the
+ * platform and utm_content the post's uuid.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct UtmConfig {
+    pub medium: String,
+    pub campaign: String,
+    /// Only links to these hosts are tagged
+    pub domains: Vec<String>,
+}
+
+/**
+ * How outbound links are shortened. Http calls SHORTENER_URL with the
+ * link as its url parameter and uses the response body as the short link, so
+ * any service with a plain text API can be plugged in. Stub makes links
+ * up locally, for tests and trying things out.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shortener {
+    Off,
+    Stub,
+    Http { endpoint: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkConfig {
+    pub utm: Option<UtmConfig>,
+    pub shortener: Shortener,
+}
+
+impl LinkConfig {
+    /**
+     * Configuration:
+     * UTM_TAGGING: on (default) or off
+     * UTM_MEDIUM: defaults to social
+     * UTM_CAMPAIGN: defaults to autoposter
+     * UTM_DOMAINS: comma separated hosts to tag, defaults to the newsletter's
+     * SHORTENER: off (default), stub or http
+     * SHORTENER_URL: for http, e.g. https://is.gd/create.php?format=simple
+     */
+    pub fn from_env() -> LinkConfig {
+        let utm = match env::var("UTM_TAGGING").as_deref() {
+            Ok("off") => None,
+            _ => Some(UtmConfig {
+                medium: env::var("UTM_MEDIUM").unwrap_or(String::from("social")),
+                campaign: env::var("UTM_CAMPAIGN").unwrap_or(String::from("autoposter")),
+                domains: match env::var("UTM_DOMAINS") {
+                    Ok(d) => d.split(',').map(|h| h.trim().to_string()).filter(|h| !h.is_empty()).collect(),
+                    Err(_) => vec![newsletter_host()],
+                },
+            }),
+        };
+        let shortener = match (env::var("SHORTENER").as_deref(), env::var("SHORTENER_URL")) {
+            (Ok("stub"), _) => Shortener::Stub,
+            (Ok("http"), Ok(endpoint)) => Shortener::Http { endpoint },
+            _ => Shortener::Off,
+        };
+        LinkConfig { utm, shortener }
+    }
+}
+
+fn newsletter_host() -> String {
+    Url::parse(NEWSLETTER_URL).ok()
+        .and_then(|u| u.host_str().map(String::from))
+        .unwrap_or_default()
+}
+
+/**
+ * Adds the UTM parameters to a link to one of our domains. Other links,
+ * and links that already have a utm_source, are left alone.
+ */
+pub fn tag_url(url: &str, utm: &UtmConfig, platform: &str, post_uuid: &str) -> String {
+    let mut parsed = match Url::parse(url) {
+        Ok(u) => u,
+        Err(_) => return url.to_string(),
+    };
+    let ours = parsed.host_str().map(|h| utm.domains.iter().any(|d| d == h)).unwrap_or(false);
+    if !ours || parsed.query_pairs().any(|(k, _)| k == "utm_source") {
+        return url.to_string();
+    }
+    parsed.query_pairs_mut()
+        .append_pair("utm_source", platform)
+        .append_pair("utm_medium", &utm.medium)
+        .append_pair("utm_campaign", &utm.campaign)
+        .append_pair("utm_content", post_uuid);
+    parsed.to_string()
+}
+
+fn stub_short_url(url: &str) -> String {
+    // FNV-1a, stable across runs so the same link always gets the same stub
+    let hash = url.bytes().fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+    format!("https://short.test/{:x}", hash & 0xffffffff)
+}
+
+/**
+ * Shortens a link. If the shortener fails the long link is used, a
+ * missing short link shouldn't stop a post going out.
+ */
+pub async fn shorten_url(url: &str, shortener: &Shortener) -> String {
+    match shortener {
+        Shortener::Off => url.to_string(),
+        Shortener::Stub => stub_short_url(url),
+        Shortener::Http { endpoint } => {
+            let request = match Url::parse_with_params(endpoint, &[("url", url)]) {
+                Ok(r) => r,
+                Err(e) => {
+                    println!("Bad shortener url {}: {:?}", endpoint, e);
+                    return url.to_string();
+                }
+            };
+            let response = match reqwest::get(request).await {
+                Ok(r) if r.status().is_success() => r.text().await,
+                Ok(r) => {
+                    println!("Shortener returned {} for {}", r.status(), url);
+                    return url.to_string();
+                },
+                Err(e) => Err(e),
+            };
+            match response {
+                Ok(short) if short.trim().starts_with("http") => short.trim().to_string(),
+                Ok(short) => {
+                    println!("Shortener returned {:?} for {}", short, url);
+                    url.to_string()
+                },
+                Err(e) => {
+                    println!("Failed shortening {}: {:?}", url, e);
+                    url.to_string()
+                }
+            }
+        }
+    }
+}
+
+/// Splits a link from the punctuation that ends the sentence it's in.
+fn split_trailing_punctuation(word: &str) -> (&str, &str) {
+    let end = word.trim_end_matches(['.', ',', '!', '?', ')', ':', ';']).len();
+    word.split_at(end)
+}
+
+/**
+ * The publish path's link rewriting stage: tags and shortens every link in
+ * the text for the platform it's being posted to.
+ */
+pub async fn rewrite_links(text: &str, config: &LinkConfig, platform: &str, post_uuid: &str) -> String {
+    let mut rewritten = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("http://").into_iter().chain(rest.find("https://")).min() {
+        rewritten.push_str(&rest[..start]);
+        let end = rest[start..].find(char::is_whitespace).map(|e| start + e).unwrap_or(rest.len());
+        let (link, punctuation) = split_trailing_punctuation(&rest[start..end]);
+        let tagged = match &config.utm {
+            Some(utm) => tag_url(link, utm, platform, post_uuid),
+            None => link.to_string(),
+        };
+        rewritten.push_str(&shorten_url(&tagged, &config.shortener).await);
+        rewritten.push_str(punctuation);
+        rest = &rest[end..];
+    }
+    rewritten.push_str(rest);
+    rewritten
+}
+
+/// Rewrites the links in both the post and its reply.
+pub async fn rewrite_publication(publication: Publication, config: &LinkConfig, platform: &str, post_uuid: &str) -> Publication {
+    Publication {
+        body: rewrite_links(&publication.body, config, platform, post_uuid).await,
+        comment: rewrite_links(&publication.comment, config, platform, post_uuid).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    const URL: &str = "https://davidjmeyer.substack.com/p/run-with-joy";
+
+    #[test]
+    fn test_with_article_link() {
+        let off = with_article_link(String::from("Run with joy!"), Some(URL), ArticleLinkMode::Off, 280, X_LINK_LENGTH);
+        assert_eq!(off.body, "Run with joy!");
+        assert_eq!(off.comment, newsletter_comment());
+
+        let append = with_article_link(String::from("Run with joy!"), Some(URL), ArticleLinkMode::Append, 280, X_LINK_LENGTH);
+        assert_eq!(append.body, format!("Run with joy!\n\n{}", URL));
+        assert_eq!(append.comment, newsletter_comment());
+
+        let reply = with_article_link(String::from("Run with joy!"), Some(URL), ArticleLinkMode::Reply, 280, X_LINK_LENGTH);
+        assert_eq!(reply.body, "Run with joy!");
+        assert!(reply.comment.ends_with(URL));
+
+        // Too long to append, so the link goes in the reply
+        let long = "a".repeat(270);
+        let too_long = with_article_link(long.clone(), Some(URL), ArticleLinkMode::Append, 280, X_LINK_LENGTH);
+        assert_eq!(too_long.body, long);
+        assert!(too_long.comment.ends_with(URL));
+
+        // Posts without an article keep the newsletter link
+        let no_article = with_article_link(String::from("Gm!"), None, ArticleLinkMode::Reply, 280, X_LINK_LENGTH);
+        assert_eq!(no_article.comment, newsletter_comment());
+        let blank = with_article_link(String::from("Gm!"), Some(""), ArticleLinkMode::Append, 280, X_LINK_LENGTH);
+        assert_eq!(blank.body, "Gm!");
+        assert_eq!(blank.comment, newsletter_comment());
+    }
+
+    #[test]
+    fn test_tag_url() {
+        let utm = UtmConfig {
+            medium: String::from("social"),
+            campaign: String::from("autoposter"),
+            domains: vec![newsletter_host()],
+        };
+        assert_eq!(
+            tag_url(URL, &utm, "x", "abc"),
+            format!("{}?utm_source=x&utm_medium=social&utm_campaign=autoposter&utm_content=abc", URL)
+        );
+        // Other sites and links that are already tagged are left alone
+        assert_eq!(tag_url("https://example.com/a", &utm, "x", "abc"), "https://example.com/a");
+        let tagged = format!("{}?utm_source=deso", URL);
+        assert_eq!(tag_url(&tagged, &utm, "x", "abc"), tagged);
+    }
+
+    #[test]
+    fn test_rewrite_links() {
+        let config = LinkConfig {
+            utm: Some(UtmConfig {
+                medium: String::from("social"),
+                campaign: String::from("autoposter"),
+                domains: vec![newsletter_host()],
+            }),
+            shortener: Shortener::Off,
+        };
+        let text = format!("Read it here: {}. And here https://example.com", NEWSLETTER_URL);
+        let rewritten = aw!(rewrite_links(&text, &config, "deso", "abc"));
+        assert_eq!(
+            rewritten,
+            format!("Read it here: {}/?utm_source=deso&utm_medium=social&utm_campaign=autoposter&utm_content=abc. And here https://example.com", NEWSLETTER_URL)
+        );
+
+        let stub = LinkConfig { utm: None, shortener: Shortener::Stub };
+        let short = aw!(rewrite_links(&text, &stub, "x", "abc"));
+        assert!(short.starts_with("Read it here: https://short.test/"));
+        assert!(!short.contains("example.com"));
+        assert_eq!(short, aw!(rewrite_links(&text, &stub, "x", "abc")));
+        assert_eq!(aw!(rewrite_links("No links", &stub, "x", "abc")), "No links");
+    }
+}
@@ -0,0 +1,289 @@
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client as DbClient;
+use regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::env;
+
+use crate::storage::{now, PostRecord, StorageError};
+
+// Shipped with the lambdas, MODERATION_POLICY overrides it without a redeploy of the code
+const DEFAULT_MODERATION_POLICY: &str = include_str!("../config/moderation.json");
+
+/// Posts that fail moderation are kept with this status instead of being published
+pub const QUARANTINED: &str = "quarantined";
+
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+const DEFAULT_MODEL: &str = "gpt-4o-2024-08-06";
+
+const CLASSIFIER_PROMPT: &str = "You review posts for an encouraging running and fitness account before they are published. A post is unsafe if it is offensive, hateful, sexual, harassing, gives medical, legal or financial advice, makes claims we could be sued for, or is off-brand for the account. Respond in JSON format like this: {\"safe\": true, \"reason\": \"\"} with the reason set when the post is unsafe.";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    pub pattern: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ModerationConfig {
+    /// Words and phrases no post may contain, matched as whole words ignoring case
+    #[serde(default)]
+    pub banned_terms: Vec<String>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+/// The config with its terms and rules compiled.
+#[derive(Debug, Clone)]
+pub struct ModerationPolicy {
+    banned_terms: Vec<(String, Regex)>,
+    rules: Vec<(String, Regex)>,
+}
+
+impl ModerationPolicy {
+    pub fn new(config: ModerationConfig) -> Result<ModerationPolicy, String> {
+        let mut banned_terms = Vec::new();
+        for term in config.banned_terms {
+            let re = Regex::new(&format!(r"(?i)\b{}\b", regex::escape(&term)))
+                .map_err(|e| format!("Invalid banned term {:?}: {}", term, e))?;
+            banned_terms.push((term, re));
+        }
+        let mut rules = Vec::new();
+        for rule in config.rules {
+            let re = Regex::new(&rule.pattern)
+                .map_err(|e| format!("Invalid moderation rule {:?}: {}", rule.name, e))?;
+            rules.push((rule.name, re));
+        }
+        Ok(ModerationPolicy { banned_terms, rules })
+    }
+
+    /// The shipped policy, or MODERATION_POLICY if it's set.
+    pub fn load() -> Result<ModerationPolicy, String> {
+        let raw = env::var("MODERATION_POLICY").unwrap_or(String::from(DEFAULT_MODERATION_POLICY));
+        let config: ModerationConfig = serde_json::from_str(&raw)
+            .map_err(|e| format!("Invalid moderation policy: {}", e))?;
+        ModerationPolicy::new(config)
+    }
+
+    /// Why the text breaks the policy, empty when it doesn't.
+    pub fn check(&self, text: &str) -> Vec<String> {
+        let mut reasons = Vec::new();
+        for (term, re) in &self.banned_terms {
+            if re.is_match(text) {
+                reasons.push(format!("Contains banned term {:?}", term));
+            }
+        }
+        for (name, re) in &self.rules {
+            if let Some(m) = re.find(text) {
+                reasons.push(format!("Matches rule {:?}: {:?}", name, m.as_str()));
+            }
+        }
+        reasons
+    }
+}
+
+/**
+ * An optional LLM that reads each post and says whether it's safe to
+ * publish. It talks to any server with the OpenAI chat completions API.
+ */
+#[derive(Debug, Clone)]
+pub enum Classifier {
+    Off,
+    Llm {
+        base_url: String,
+        api_key: Option<String>,
+        model: String,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+struct Verdict {
+    safe: bool,
+    #[serde(default)]
+    reason: String,
+}
+
+impl Classifier {
+    /**
+     * Configuration:
+     * MODERATION_CLASSIFIER: off (default) or llm
+     * LLM_BASE_URL, OPEN_AI_API_KEY: the same server generatePosts uses
+     * MODERATION_MODEL: defaults to LLM_MODEL, then gpt-4o-2024-08-06
+     */
+    pub fn from_env() -> Classifier {
+        match env::var("MODERATION_CLASSIFIER").as_deref() {
+            Ok("llm") | Ok("on") => Classifier::Llm {
+                base_url: env::var("LLM_BASE_URL").unwrap_or(String::from(DEFAULT_BASE_URL)).trim_end_matches('/').to_string(),
+                api_key: env::var("OPEN_AI_API_KEY").ok(),
+                model: env::var("MODERATION_MODEL")
+                    .or_else(|_| env::var("LLM_MODEL"))
+                    .unwrap_or(String::from(DEFAULT_MODEL)),
+            },
+            _ => Classifier::Off,
+        }
+    }
+
+    /**
+     * Some(reason) when the classifier says the post isn't safe. An error
+     * means we couldn't ask, which isn't the same as the post being safe.
+     */
+    pub async fn classify(&self, text: &str) -> Result<Option<String>, String> {
+        let (base_url, api_key, model) = match self {
+            Classifier::Off => return Ok(None),
+            Classifier::Llm { base_url, api_key, model } => (base_url, api_key, model),
+        };
+        let uri = format!("{}/chat/completions", base_url);
+        let body = json!({
+            "model": model,
+            "messages": [
+                { "role": "system", "content": CLASSIFIER_PROMPT },
+                { "role": "user", "content": text },
+            ],
+        });
+        let mut request = reqwest::Client::new().post(&uri).json(&body);
+        if let Some(key) = api_key {
+            request = request.bearer_auth(key);
+        }
+        let response = request.send().await
+            .map_err(|e| format!("Error calling the moderation classifier: {}", e))?;
+        let status = response.status();
+        let raw: Value = response.json().await
+            .map_err(|e| format!("Error reading the moderation classifier's response: {}", e))?;
+        if !status.is_success() {
+            return Err(format!("Moderation classifier returned {}: {}", status, raw));
+        }
+        let content = raw["choices"][0]["message"]["content"].as_str().unwrap_or_default();
+        // No response_format, not every model has a JSON mode, so take the object out of the reply
+        let start = content.find('{').unwrap_or(0);
+        let end = content.rfind('}').map(|e| e + 1).unwrap_or(content.len());
+        let verdict: Verdict = serde_json::from_str(&content[start..end])
+            .map_err(|e| format!("Error parsing the moderation classifier's verdict {:?}: {}", content, e))?;
+        if verdict.safe {
+            Ok(None)
+        } else {
+            Ok(Some(verdict.reason))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Moderator {
+    pub policy: ModerationPolicy,
+    pub classifier: Classifier,
+}
+
+impl Moderator {
+    pub fn from_env() -> Result<Moderator, String> {
+        Ok(Moderator {
+            policy: ModerationPolicy::load()?,
+            classifier: Classifier::from_env(),
+        })
+    }
+
+    /**
+     * Every reason the post shouldn't be published. The classifier is only
+     * asked about posts that pass the term and rule checks.
+     */
+    pub async fn moderate(&self, text: &str) -> Result<Vec<String>, String> {
+        let mut reasons = self.policy.check(text);
+        if reasons.is_empty() {
+            if let Some(reason) = self.classifier.classify(text).await? {
+                reasons.push(format!("Classifier: {}", reason));
+            }
+        }
+        Ok(reasons)
+    }
+
+    /**
+     * Quarantines every record that fails moderation. A post we couldn't
+     * check is quarantined too, so it's read by a person before it goes
+     * out. Returns how many were quarantined.
+     */
+    pub async fn moderate_records(&self, records: &mut [PostRecord]) -> usize {
+        let mut quarantined = 0;
+        for record in records.iter_mut() {
+            let reasons = match self.moderate(&record.post).await {
+                Ok(r) => r,
+                Err(e) => vec![e],
+            };
+            if reasons.is_empty() {
+                continue;
+            }
+            println!("Quarantining {:?}: {:?}", record.post, reasons);
+            record.status = String::from(QUARANTINED);
+            record.moderation_reasons = Some(reasons);
+            quarantined += 1;
+        }
+        quarantined
+    }
+}
+
+/**
+ * Takes a post out of the publishing queue, recording why, without
+ * deleting it. A post deleted in the meantime isn't written back as a
+ * quarantined stub.
+ */
+pub async fn quarantine_post(client: &DbClient, table: &str, uuid: &str, reasons: &[String]) -> Result<(), StorageError> {
+    let result = client.update_item()
+        .table_name(table)
+        .key("uuid", AttributeValue::S(String::from(uuid)))
+        .update_expression("SET #status = :status, moderation_reasons = :reasons, quarantined_at = :now, version = if_not_exists(version, :zero) + :one")
+        .condition_expression("attribute_exists(#uuid)")
+        .expression_attribute_names("#uuid", "uuid")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":status", AttributeValue::S(String::from(QUARANTINED)))
+        .expression_attribute_values(":reasons", AttributeValue::L(reasons.iter().cloned().map(AttributeValue::S).collect()))
+        .expression_attribute_values(":now", AttributeValue::N(now().to_string()))
+        .expression_attribute_values(":zero", AttributeValue::N(String::from("0")))
+        .expression_attribute_values(":one", AttributeValue::N(String::from("1")))
+        .send().await;
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => Err(StorageError {
+            message: format!("Failed quarantining {}: {}", uuid, e)
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! aw {
+        ($e:expr) => {
+            tokio_test::block_on($e)
+        };
+    }
+
+    #[test]
+    fn test_check() {
+        let policy = ModerationPolicy::load().unwrap();
+        assert!(policy.check("Rest days are training days too. #RunWithJoy").is_empty());
+        assert_eq!(policy.check("This plan has GUARANTEED RESULTS!").len(), 1);
+        // Banned terms only match whole words
+        assert!(policy.check("Steroidsfree is not a word").is_empty());
+        let reasons = policy.check("Running cures depression, email coach@example.com");
+        assert_eq!(reasons.len(), 2);
+        assert!(reasons[0].contains("medical advice"));
+
+        let bad = ModerationConfig { banned_terms: vec![], rules: vec![Rule { name: String::from("bad"), pattern: String::from("(") }] };
+        assert!(ModerationPolicy::new(bad).is_err());
+    }
+
+    #[test]
+    fn test_moderate_records() {
+        let moderator = Moderator {
+            policy: ModerationPolicy::load().unwrap(),
+            classifier: Classifier::Off,
+        };
+        let mut records = vec![
+            PostRecord::new(String::from("Run with joy!"), "draft"),
+            PostRecord::new(String::from("A miracle cure for slow legs"), "draft"),
+        ];
+        assert_eq!(aw!(moderator.moderate_records(&mut records)), 1);
+        assert_eq!(records[0].status, "draft");
+        assert_eq!(records[1].status, QUARANTINED);
+        assert_eq!(records[1].moderation_reasons.as_ref().unwrap().len(), 1);
+    }
+}
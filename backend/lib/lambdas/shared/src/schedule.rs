@@ -0,0 +1,197 @@
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client as DbClient;
+use chrono::{DateTime, Duration, NaiveTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::http::ApiError;
+use crate::storage::{now, StorageError};
+
+/// Scheduled posts without a timezone go out on UTC, which is what the lambdas run on
+pub const DEFAULT_TIMEZONE: &str = "UTC";
+
+pub fn parse_timezone(name: &str) -> Result<Tz, String> {
+    Tz::from_str(name).map_err(|_| format!("Unknown timezone: {}", name))
+}
+
+pub fn parse_time(time: &str) -> Result<NaiveTime, String> {
+    NaiveTime::from_str(time).map_err(|e| format!("Invalid time {:?}: {}", time, e))
+}
+
+/// The checks a scheduled post's time and timezone have to pass, whether it's being added or edited.
+pub fn validate_schedule(time: Option<&str>, timezone: Option<&str>) -> Result<(), ApiError> {
+    if let Some(time) = time {
+        parse_time(time).map_err(|e| ApiError::invalid("time", &e))?;
+    }
+    if let Some(timezone) = timezone {
+        parse_timezone(timezone).map_err(|e| ApiError::invalid("timezone", &e))?;
+    }
+    Ok(())
+}
+
+/**
+ * A row of the scheduled posts table. SendPost runs once an hour and
+ * sends a scheduled post in the run during its hour, in its timezone.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledRecord {
+    pub uuid: String,
+    pub post: String,
+    pub time: String,
+    pub timezone: Option<String>,
+    pub recurring: bool,
+    /// Paused posts stay in the table but aren't sent
+    pub enabled: bool,
+    /// Epoch seconds, only kept for recurring posts since the others are deleted once sent
+    pub last_sent: Option<u64>,
+    pub status: Option<String>,
+}
+
+fn get_s(item: &HashMap<String, AttributeValue>, key: &str) -> Result<String, String> {
+    item.get(key)
+        .ok_or_else(|| format!("Missing '{}' attribute", key))?
+        .as_s()
+        .map(|s| s.to_string())
+        .map_err(|_| format!("Error getting {} S attribute", key))
+}
+
+impl ScheduledRecord {
+    pub fn from_item(item: &HashMap<String, AttributeValue>) -> Result<ScheduledRecord, String> {
+        let recurring = *item
+            .get("recurring")
+            .ok_or_else(|| String::from("Missing 'recurring' attribute"))?
+            .as_bool()
+            .map_err(|_| String::from("Error getting recurring Bool attribute"))?;
+        Ok(ScheduledRecord {
+            uuid: get_s(item, "uuid")?,
+            post: get_s(item, "post")?,
+            time: get_s(item, "time")?,
+            timezone: get_s(item, "timezone").ok(),
+            recurring,
+            enabled: item.get("enabled")
+                .and_then(|e| e.as_bool().ok())
+                .copied()
+                .unwrap_or(true),
+            last_sent: item.get("last_sent")
+                .and_then(|n| n.as_n().ok())
+                .and_then(|n| n.parse().ok()),
+            status: get_s(item, "status").ok(),
+        })
+    }
+
+    pub fn tz(&self) -> Result<Tz, String> {
+        parse_timezone(self.timezone.as_deref().unwrap_or(DEFAULT_TIMEZONE))
+    }
+
+    fn sent_since(&self, timestamp: i64) -> bool {
+        match self.last_sent {
+            Some(sent) => sent as i64 >= timestamp,
+            None => false,
+        }
+    }
+
+    /// Whether the run at `now` should send it: it's the post's hour and it hasn't gone out this hour.
+    pub fn is_due(&self, now: DateTime<Utc>) -> Result<bool, String> {
+        let time = parse_time(&self.time)?;
+        if !self.enabled {
+            return Ok(false);
+        }
+        let local = now.with_timezone(&self.tz()?);
+        if local.hour() != time.hour() {
+            return Ok(false);
+        }
+        let hour_start = now.timestamp() - (local.minute() * 60 + local.second()) as i64;
+        Ok(!self.sent_since(hour_start))
+    }
+
+    /**
+     * When the post goes out next. A post that's due but hasn't been sent
+     * yet this hour returns this hour's time, even if it's a few minutes
+     * ago. None for a paused post or a one off post that was already sent.
+     */
+    pub fn next_occurrence(&self, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>, String> {
+        if !self.enabled || (!self.recurring && self.last_sent.is_some()) {
+            return Ok(None);
+        }
+        let time = parse_time(&self.time)?;
+        let tz = self.tz()?;
+        let today = now.with_timezone(&tz).date_naive();
+        // Two days covers a time skipped by a daylight saving change
+        for days in 0..3 {
+            let date = today + Duration::days(days);
+            let hour = match date.and_hms_opt(time.hour(), 0, 0).and_then(|h| tz.from_local_datetime(&h).earliest()) {
+                Some(h) => h,
+                None => continue,
+            };
+            let hour_start = hour.timestamp();
+            if hour_start + 3600 <= now.timestamp() || self.sent_since(hour_start) {
+                continue;
+            }
+            let at = tz.from_local_datetime(&date.and_time(time)).earliest().unwrap_or(hour);
+            return Ok(Some(at.with_timezone(&Utc)));
+        }
+        Ok(None)
+    }
+}
+
+/// Records that a recurring post went out so it isn't sent twice in one hour.
+pub async fn mark_sent(client: &DbClient, table: &str, uuid: &str) -> Result<(), StorageError> {
+    let result = client.update_item()
+        .table_name(table)
+        .key("uuid", AttributeValue::S(String::from(uuid)))
+        .update_expression("SET last_sent = :now")
+        .condition_expression("attribute_exists(#uuid)")
+        .expression_attribute_names("#uuid", "uuid")
+        .expression_attribute_values(":now", AttributeValue::N(now().to_string()))
+        .send().await;
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => Err(StorageError {
+            message: format!("Failed marking {} sent: {}", uuid, e)
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(time: &str, timezone: Option<&str>, recurring: bool, last_sent: Option<u64>) -> ScheduledRecord {
+        ScheduledRecord {
+            uuid: String::from("abc"),
+            post: String::from("Good morning runners!"),
+            time: String::from(time),
+            timezone: timezone.map(String::from),
+            recurring,
+            enabled: true,
+            last_sent,
+            status: None,
+        }
+    }
+
+    #[test]
+    fn test_next_occurrence() {
+        // 2024-06-18 14:20 UTC, 10:20 in New York
+        let now = Utc.with_ymd_and_hms(2024, 6, 18, 14, 20, 0).unwrap();
+        let morning = record("08:00:00", Some("America/New_York"), true, None);
+        assert!(!morning.is_due(now).unwrap());
+        assert_eq!(morning.next_occurrence(now).unwrap(), Some(Utc.with_ymd_and_hms(2024, 6, 19, 12, 0, 0).unwrap()));
+
+        let due = record("14:00:00", None, true, None);
+        assert!(due.is_due(now).unwrap());
+        assert_eq!(due.next_occurrence(now).unwrap(), Some(Utc.with_ymd_and_hms(2024, 6, 18, 14, 0, 0).unwrap()));
+
+        // Sent at 14:05, so not again until tomorrow
+        let sent = record("14:00:00", None, true, Some(now.timestamp() as u64 - 900));
+        assert!(!sent.is_due(now).unwrap());
+        assert_eq!(sent.next_occurrence(now).unwrap(), Some(Utc.with_ymd_and_hms(2024, 6, 19, 14, 0, 0).unwrap()));
+
+        assert_eq!(record("14:00:00", None, false, Some(0)).next_occurrence(now).unwrap(), None);
+        assert!(record("14:00:00", Some("Mars/Olympus"), true, None).is_due(now).is_err());
+
+        let paused = ScheduledRecord { enabled: false, ..due };
+        assert!(!paused.is_due(now).unwrap());
+        assert_eq!(paused.next_occurrence(now).unwrap(), None);
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+
+// Words per shingle. Posts are short, so pairs of words keep enough overlap
+// between rewordings of the same sentence.
+const SHINGLE_SIZE: usize = 2;
+
+// Number of hash functions in a signature, more is more accurate and slower
+const SIGNATURE_SIZE: usize = 128;
+
+/**
+ * A MinHash signature of a post. The share of slots two signatures agree on
+ * estimates the Jaccard similarity of the posts' shingles.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature(Vec<u64>);
+
+fn normalize_words(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        // Links change between otherwise identical posts
+        .filter(|w| !w.starts_with("http"))
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .chars()
+        .filter(|c| !matches!(c, '\'' | '’'))
+        .map(|c| if c.is_alphanumeric() || c == '#' { c.to_ascii_lowercase() } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .map(String::from)
+        .collect()
+}
+
+pub fn shingles(text: &str) -> HashSet<String> {
+    let words = normalize_words(text);
+    if words.len() < SHINGLE_SIZE {
+        return words.into_iter().collect();
+    }
+    words.windows(SHINGLE_SIZE).map(|w| w.join(" ")).collect()
+}
+
+fn hash_with_seed(shingle: &str, seed: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    shingle.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub fn signature(text: &str) -> Signature {
+    let shingles = shingles(text);
+    Signature((0..SIGNATURE_SIZE as u64)
+        .map(|seed| shingles.iter()
+            .map(|s| hash_with_seed(s, seed))
+            .min()
+            .unwrap_or(u64::MAX))
+        .collect())
+}
+
+impl Signature {
+    pub fn similarity(&self, other: &Signature) -> f64 {
+        let matching = self.0.iter().zip(other.0.iter()).filter(|(a, b)| a == b).count();
+        matching as f64 / SIGNATURE_SIZE as f64
+    }
+}
+
+/**
+ * A post we already have, either waiting in the queue or recently
+ * published.
+ */
+#[derive(Debug, Clone)]
+pub struct KnownPost {
+    pub uuid: String,
+    pub signature: Signature,
+}
+
+impl KnownPost {
+    pub fn new(uuid: String, post: &str) -> KnownPost {
+        KnownPost {
+            uuid,
+            signature: signature(post),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Duplicate {
+    pub uuid: String,
+    pub similarity: f64,
+}
+
+/// The most similar known post, if it's at least `threshold` similar.
+pub fn find_duplicate(post: &str, known: &[KnownPost], threshold: f64) -> Option<Duplicate> {
+    let sig = signature(post);
+    known.iter()
+        .map(|k| Duplicate {
+            uuid: k.uuid.clone(),
+            similarity: sig.similarity(&k.signature),
+        })
+        .filter(|d| d.similarity >= threshold)
+        .max_by(|a, b| a.similarity.partial_cmp(&b.similarity).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_similarity() {
+        let a = signature("Strength training keeps you running when your legs want to quit. #HybridAthlete");
+        let b = signature("strength training keeps you running when your legs want to quit! #hybridathlete https://t.co/abc");
+        let c = signature("Strength training keeps you running strong when your tired legs want to quit. #HybridAthlete");
+        let d = signature("Rest days are training days too. Recovery is where you get faster.");
+        assert_eq!(a.similarity(&b), 1.0);
+        assert!(a.similarity(&c) > 0.4);
+        assert!(a.similarity(&d) < 0.1);
+    }
+
+    #[test]
+    fn test_find_duplicate() {
+        let known = vec![
+            KnownPost::new(String::from("1"), "Rest days are training days too."),
+            KnownPost::new(String::from("2"), "Strength training keeps you running when your legs want to quit."),
+        ];
+        let duplicate = find_duplicate("Strength training keeps you running when your legs want to quit!", &known, 0.7).unwrap();
+        assert_eq!(duplicate.uuid, "2");
+        assert!(find_duplicate("Run with joy every single day.", &known, 0.7).is_none());
+    }
+}
@@ -0,0 +1,367 @@
+use aws_sdk_dynamodb::types::{AttributeValue, Put, PutRequest, TransactWriteItem, WriteRequest};
+use aws_sdk_dynamodb::Client as DbClient;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+// Review states, sendPosts only publishes approved posts. Quarantined
+// posts failed moderation.
+pub const STATUSES: [&str; 4] = ["draft", "approved", "rejected", "quarantined"];
+
+// The Posts table's index on status sorted by created_at, so a status can be read in order
+pub const STATUS_INDEX: &str = "status-created_at";
+
+// A DynamoDB transaction holds at most 100 items, one of them is the batch
+pub const MAX_TRANSACTION_POSTS: usize = 99;
+
+// A batch write holds at most 25 items
+pub const MAX_BATCH_WRITE: usize = 25;
+
+// Unprocessed items are retried this many times, waiting twice as long each time
+const MAX_BATCH_ATTEMPTS: u32 = 5;
+const BATCH_BACKOFF_MS: u64 = 100;
+
+#[derive(Debug)]
+pub struct StorageError {
+    pub message: String,
+}
+
+impl StdError for StorageError {}
+
+impl StorageError {
+    pub fn new(message: &str) -> StorageError {
+        StorageError {
+            message: String::from(message)
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Seconds since the epoch, what every timestamp in our tables is stored as.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/**
+ * A post as it's stored in the Posts table. Optional attributes are only
+ * written when they're set.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct PostRecord {
+    pub uuid: String,
+    pub post: String,
+    pub status: String,
+    pub created_at: Option<u64>,
+    pub content_type: Option<String>,
+    pub segments: Option<Vec<String>>,
+    pub prompt_version: Option<String>,
+    pub unverified_quotes: Option<Vec<String>>,
+    pub duplicate_of: Option<String>,
+    /// The generation batch the post came from
+    pub batch_id: Option<String>,
+    /// Where a generated post came from
+    pub source: Option<String>,
+    pub source_feed: Option<String>,
+    pub article_title: Option<String>,
+    pub article_url: Option<String>,
+    pub generated_at: Option<u64>,
+    /// Why the post failed moderation, set when it's quarantined
+    pub moderation_reasons: Option<Vec<String>>,
+    /// Where the post's image is in the image bucket, and its alt text
+    pub image_key: Option<String>,
+    pub image_alt: Option<String>,
+}
+
+fn string_list(values: &[String]) -> AttributeValue {
+    AttributeValue::L(values.iter().cloned().map(AttributeValue::S).collect())
+}
+
+impl PostRecord {
+    pub fn new(post: String, status: &str) -> PostRecord {
+        PostRecord {
+            uuid: Uuid::new_v4().to_string(),
+            post,
+            status: String::from(status),
+            created_at: Some(now()),
+            ..PostRecord::default()
+        }
+    }
+
+    pub fn to_item(&self) -> HashMap<String, AttributeValue> {
+        let mut item = HashMap::new();
+        item.insert("uuid".to_string(), AttributeValue::S(self.uuid.clone()));
+        item.insert("post".to_string(), AttributeValue::S(self.post.clone()));
+        item.insert("status".to_string(), AttributeValue::S(self.status.clone()));
+        if let Some(created_at) = &self.created_at {
+            item.insert("created_at".to_string(), AttributeValue::N(created_at.to_string()));
+        }
+        if let Some(content_type) = &self.content_type {
+            item.insert("content_type".to_string(), AttributeValue::S(content_type.clone()));
+        }
+        if let Some(segments) = &self.segments {
+            item.insert("segments".to_string(), string_list(segments));
+        }
+        if let Some(prompt_version) = &self.prompt_version {
+            item.insert("prompt_version".to_string(), AttributeValue::S(prompt_version.clone()));
+        }
+        if let Some(quotes) = &self.unverified_quotes {
+            item.insert("unverified_quotes".to_string(), string_list(quotes));
+        }
+        if let Some(duplicate_of) = &self.duplicate_of {
+            item.insert("duplicate_of".to_string(), AttributeValue::S(duplicate_of.clone()));
+        }
+        if let Some(batch_id) = &self.batch_id {
+            item.insert("batch_id".to_string(), AttributeValue::S(batch_id.clone()));
+        }
+        if let Some(source) = &self.source {
+            item.insert("source".to_string(), AttributeValue::S(source.clone()));
+        }
+        if let Some(source_feed) = &self.source_feed {
+            item.insert("source_feed".to_string(), AttributeValue::S(source_feed.clone()));
+        }
+        if let Some(article_title) = &self.article_title {
+            item.insert("article_title".to_string(), AttributeValue::S(article_title.clone()));
+        }
+        if let Some(article_url) = &self.article_url {
+            item.insert("article_url".to_string(), AttributeValue::S(article_url.clone()));
+        }
+        if let Some(generated_at) = &self.generated_at {
+            item.insert("generated_at".to_string(), AttributeValue::N(generated_at.to_string()));
+        }
+        if let Some(reasons) = &self.moderation_reasons {
+            item.insert("moderation_reasons".to_string(), string_list(reasons));
+        }
+        if let Some(image_key) = &self.image_key {
+            item.insert("image_key".to_string(), AttributeValue::S(image_key.clone()));
+        }
+        if let Some(image_alt) = &self.image_alt {
+            item.insert("image_alt".to_string(), AttributeValue::S(image_alt.clone()));
+        }
+        item
+    }
+}
+
+/**
+ * One run of generatePosts: where the posts came from and which prompt
+ * wrote them.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GenerationBatch {
+    pub uuid: String,
+    pub source: String,
+    pub feed_url: String,
+    pub article_title: String,
+    pub article_url: String,
+    pub prompt_version: String,
+    pub created_at: u64,
+}
+
+impl GenerationBatch {
+    pub fn new(source: String, feed_url: String, article_title: String, article_url: String, prompt_version: String) -> GenerationBatch {
+        GenerationBatch {
+            uuid: Uuid::new_v4().to_string(),
+            source,
+            feed_url,
+            article_title,
+            article_url,
+            prompt_version,
+            created_at: now(),
+        }
+    }
+
+    /// The article's url, None for feed items without a link
+    pub fn article_link(&self) -> Option<String> {
+        Some(self.article_url.trim()).filter(|u| !u.is_empty()).map(String::from)
+    }
+
+    pub fn to_item(&self, post_uuids: &[String]) -> HashMap<String, AttributeValue> {
+        let mut item = HashMap::new();
+        item.insert("uuid".to_string(), AttributeValue::S(self.uuid.clone()));
+        item.insert("source".to_string(), AttributeValue::S(self.source.clone()));
+        item.insert("feed_url".to_string(), AttributeValue::S(self.feed_url.clone()));
+        item.insert("article_title".to_string(), AttributeValue::S(self.article_title.clone()));
+        if let Some(article_url) = self.article_link() {
+            item.insert("article_url".to_string(), AttributeValue::S(article_url));
+        }
+        item.insert("prompt_version".to_string(), AttributeValue::S(self.prompt_version.clone()));
+        item.insert("created_at".to_string(), AttributeValue::N(self.created_at.to_string()));
+        item.insert("post_uuids".to_string(), string_list(post_uuids));
+        item
+    }
+}
+
+fn put(table: &str, item: HashMap<String, AttributeValue>) -> TransactWriteItem {
+    let put = Put::builder()
+        .table_name(table)
+        .set_item(Some(item))
+        // Never overwrite an existing row with a new one
+        .condition_expression("attribute_not_exists(#uuid)")
+        .expression_attribute_names("#uuid", "uuid")
+        .build();
+    TransactWriteItem::builder().put(put).build()
+}
+
+/**
+ * Writes a generation batch and all of its posts in one transaction, so
+ * either the batch and every post are stored or none of them are. Each
+ * post is stamped with the batch's uuid and the article it came from.
+ */
+pub async fn write_generation(
+    client: &DbClient,
+    posts_table: &str,
+    batches_table: &str,
+    batch: &GenerationBatch,
+    posts: &mut [PostRecord],
+) -> Result<(), StorageError> {
+    if posts.len() > MAX_TRANSACTION_POSTS {
+        return Err(StorageError {
+            message: format!("Can't write {} posts in one batch, the limit is {}", posts.len(), MAX_TRANSACTION_POSTS)
+        });
+    }
+    for post in posts.iter_mut() {
+        post.batch_id = Some(batch.uuid.clone());
+        post.source = Some(batch.source.clone());
+        post.source_feed = Some(batch.feed_url.clone());
+        post.article_title = Some(batch.article_title.clone());
+        post.article_url = batch.article_link();
+        post.generated_at = Some(batch.created_at);
+    }
+    let post_uuids: Vec<String> = posts.iter().map(|p| p.uuid.clone()).collect();
+
+    let mut items = vec![put(batches_table, batch.to_item(&post_uuids))];
+    items.extend(posts.iter().map(|p| put(posts_table, p.to_item())));
+
+    match client.transact_write_items().set_transact_items(Some(items)).send().await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("Failed writing batch {} {:?}", batch.uuid, e);
+            Err(StorageError {
+                message: format!("Failed writing batch {}: {}", batch.uuid, e)
+            })
+        }
+    }
+}
+
+/// Which items of a batch write were written, by uuid
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct BatchWriteResult {
+    pub written: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+/// How long to wait before retrying a batch for the nth time.
+pub fn batch_backoff(attempt: u32) -> Duration {
+    Duration::from_millis(BATCH_BACKOFF_MS * 2u64.pow(attempt))
+}
+
+fn item_uuid(item: &HashMap<String, AttributeValue>) -> String {
+    item.get("uuid")
+        .and_then(|u| u.as_s().ok())
+        .cloned()
+        .unwrap_or_default()
+}
+
+/**
+ * Puts items with BatchWriteItem, 25 at a time. DynamoDB can leave some of
+ * a batch unprocessed when it's throttled, those and batches that fail
+ * outright are retried with backoff. Whatever is still left after the last
+ * attempt is reported as failed.
+ */
+pub async fn batch_put(client: &DbClient, table: &str, items: Vec<HashMap<String, AttributeValue>>) -> BatchWriteResult {
+    let mut result = BatchWriteResult::default();
+    for chunk in items.chunks(MAX_BATCH_WRITE) {
+        let mut pending: Vec<WriteRequest> = chunk.iter()
+            .map(|item| WriteRequest::builder()
+                .put_request(PutRequest::builder().set_item(Some(item.clone())).build())
+                .build())
+            .collect();
+        let mut attempt = 0;
+        while !pending.is_empty() && attempt < MAX_BATCH_ATTEMPTS {
+            if attempt > 0 {
+                tokio::time::sleep(batch_backoff(attempt)).await;
+            }
+            attempt += 1;
+            let uuids: Vec<String> = pending.iter()
+                .filter_map(|w| w.put_request().and_then(|p| p.item()).map(item_uuid))
+                .collect();
+            match client.batch_write_item().request_items(table, pending.clone()).send().await {
+                Ok(output) => {
+                    let unprocessed = output.unprocessed_items
+                        .and_then(|mut u| u.remove(table))
+                        .unwrap_or_default();
+                    let unprocessed_uuids: Vec<String> = unprocessed.iter()
+                        .filter_map(|w| w.put_request().and_then(|p| p.item()).map(item_uuid))
+                        .collect();
+                    if !unprocessed.is_empty() {
+                        println!("{} items unprocessed, attempt {}", unprocessed.len(), attempt);
+                    }
+                    result.written.extend(uuids.into_iter().filter(|u| !unprocessed_uuids.contains(u)));
+                    pending = unprocessed;
+                },
+                Err(e) => println!("Batch write failed, attempt {}: {:?}", attempt, e),
+            };
+        }
+        result.failed.extend(pending.iter()
+            .filter_map(|w| w.put_request().and_then(|p| p.item()).map(item_uuid)));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_post_record_item() {
+        let mut record = PostRecord::new(String::from("Run with joy!"), "draft");
+        let item = record.to_item();
+        assert_eq!(item.len(), 4);
+        assert!(item.contains_key("created_at"));
+        assert_eq!(item.get("status"), Some(&AttributeValue::S(String::from("draft"))));
+
+        record.segments = Some(vec![String::from("One"), String::from("Two")]);
+        record.batch_id = Some(String::from("batch"));
+        record.generated_at = Some(1718670000);
+        let item = record.to_item();
+        assert_eq!(item.get("segments"), Some(&string_list(&[String::from("One"), String::from("Two")])));
+        assert_eq!(item.get("batch_id"), Some(&AttributeValue::S(String::from("batch"))));
+        assert_eq!(item.get("generated_at"), Some(&AttributeValue::N(String::from("1718670000"))));
+        assert!(!item.contains_key("prompt_version"));
+    }
+
+    #[test]
+    fn test_generation_batch_item() {
+        let batch = GenerationBatch::new(
+            String::from("substack"),
+            String::from("https://davidjmeyer.substack.com/feed"),
+            String::from("Run With Joy"),
+            String::from("https://davidjmeyer.substack.com/p/run-with-joy"),
+            String::from("tweets@v1"),
+        );
+        let item = batch.to_item(&[String::from("a"), String::from("b")]);
+        assert_eq!(item.get("prompt_version"), Some(&AttributeValue::S(String::from("tweets@v1"))));
+        assert_eq!(item.get("post_uuids"), Some(&string_list(&[String::from("a"), String::from("b")])));
+        assert_eq!(item.get("created_at"), Some(&AttributeValue::N(batch.created_at.to_string())));
+        assert_eq!(batch.article_link(), Some(String::from("https://davidjmeyer.substack.com/p/run-with-joy")));
+
+        // A feed item without a link has no article url rather than an empty one
+        let no_link = GenerationBatch { article_url: String::from(" "), ..batch };
+        assert_eq!(no_link.article_link(), None);
+        assert!(!no_link.to_item(&[]).contains_key("article_url"));
+    }
+
+    #[test]
+    fn test_batch_backoff() {
+        assert_eq!(batch_backoff(1), Duration::from_millis(200));
+        assert_eq!(batch_backoff(4), Duration::from_millis(1600));
+    }
+}
@@ -0,0 +1,89 @@
+// src/auth.js
+// Signs the user in with the identity provider (OAuth authorization code with PKCE)
+// and keeps their access token for API calls. The API checks the token against the
+// provider's JWKS, so no key ships in the bundle.
+const authorizeUrl = process.env.REACT_APP_AUTH_AUTHORIZE_URL;
+const tokenUrl = process.env.REACT_APP_AUTH_TOKEN_URL;
+const clientId = process.env.REACT_APP_AUTH_CLIENT_ID;
+const audience = process.env.REACT_APP_AUTH_AUDIENCE;
+const scope = process.env.REACT_APP_AUTH_SCOPE || 'openid read write generate publish';
+
+const TOKEN_KEY = 'auth.accessToken';
+const EXPIRES_KEY = 'auth.expiresAt';
+const VERIFIER_KEY = 'auth.verifier';
+const STATE_KEY = 'auth.state';
+
+const base64Url = (bytes) => btoa(String.fromCharCode(...new Uint8Array(bytes)))
+  .replace(/\+/g, '-')
+  .replace(/\//g, '_')
+  .replace(/=+$/, '');
+
+const randomString = () => base64Url(crypto.getRandomValues(new Uint8Array(32)));
+
+const redirectUri = () => window.location.origin + window.location.pathname;
+
+export const accessToken = () => {
+  const token = sessionStorage.getItem(TOKEN_KEY);
+  const expiresAt = Number(sessionStorage.getItem(EXPIRES_KEY) || 0);
+  return token && Date.now() < expiresAt ? token : null;
+};
+
+export const signIn = async () => {
+  const verifier = randomString();
+  const state = randomString();
+  sessionStorage.setItem(VERIFIER_KEY, verifier);
+  sessionStorage.setItem(STATE_KEY, state);
+  const challenge = base64Url(await crypto.subtle.digest('SHA-256', new TextEncoder().encode(verifier)));
+  const params = new URLSearchParams({
+    response_type: 'code',
+    client_id: clientId,
+    redirect_uri: redirectUri(),
+    scope,
+    state,
+    code_challenge: challenge,
+    code_challenge_method: 'S256',
+  });
+  if (audience) {
+    params.set('audience', audience);
+  }
+  window.location.assign(`${authorizeUrl}?${params}`);
+};
+
+export const signOut = () => {
+  sessionStorage.removeItem(TOKEN_KEY);
+  sessionStorage.removeItem(EXPIRES_KEY);
+};
+
+// Swaps the code the provider redirected back with for a token, if there is one
+export const completeSignIn = async () => {
+  const params = new URLSearchParams(window.location.search);
+  const code = params.get('code');
+  if (!code) {
+    return;
+  }
+  const verifier = sessionStorage.getItem(VERIFIER_KEY);
+  const state = sessionStorage.getItem(STATE_KEY);
+  sessionStorage.removeItem(VERIFIER_KEY);
+  sessionStorage.removeItem(STATE_KEY);
+  window.history.replaceState({}, document.title, redirectUri());
+  if (!verifier || params.get('state') !== state) {
+    throw new Error('Sign in response did not match the request');
+  }
+  const response = await fetch(tokenUrl, {
+    method: 'POST',
+    headers: { 'Content-Type': 'application/x-www-form-urlencoded' },
+    body: new URLSearchParams({
+      grant_type: 'authorization_code',
+      client_id: clientId,
+      code,
+      redirect_uri: redirectUri(),
+      code_verifier: verifier,
+    }),
+  });
+  if (!response.ok) {
+    throw new Error(`Sign in failed: ${response.status}`);
+  }
+  const tokens = await response.json();
+  sessionStorage.setItem(TOKEN_KEY, tokens.access_token);
+  sessionStorage.setItem(EXPIRES_KEY, String(Date.now() + (tokens.expires_in || 3600) * 1000));
+};
@@ -8,23 +8,32 @@ const EditablePosts = () => {
   const [loading, setLoading] = useState(true);
   const [error, setError] = useState(null);
 
-  const fetchPosts = () => {
-    axios.get('https://4zkgjocsu8.execute-api.us-east-1.amazonaws.com/prod/getPosts')
-      .then(response => {
+  // getPosts returns a page at a time, follow the cursor until the last one
+  const fetchPosts = async () => {
+    try {
+      let allPosts = [];
+      let next = null;
+      do {
+        const response = await axios.get('https://4zkgjocsu8.execute-api.us-east-1.amazonaws.com/prod/getPosts', {
+          params: next ? { next } : {}
+        });
         console.log(response.data);
         // Ensure the data has a posts array
-        if (response.data && Array.isArray(response.data.posts)) {
-          setPosts(response.data.posts);
-        } else {
+        if (!response.data || !Array.isArray(response.data.posts)) {
           console.error('Fetched data does not contain a posts array:', response.data);
           setError('Unexpected data format');
+          setLoading(false);
+          return;
         }
-        setLoading(false);
-      })
-      .catch(error => {
-        setError(error);
-        setLoading(false);
-      });
+        allPosts = allPosts.concat(response.data.posts);
+        next = response.data.next;
+      } while (next);
+      setPosts(allPosts);
+      setLoading(false);
+    } catch (error) {
+      setError(error);
+      setLoading(false);
+    }
   };
 
   useEffect(() => {
@@ -35,13 +44,52 @@ const EditablePosts = () => {
     setPosts(posts.map(post => post.uuid === uuid ? { ...post, post: value } : post));
   };
 
-  const handleSave = (uuid, value) => {
-    axios.post('https://4zkgjocsu8.execute-api.us-east-1.amazonaws.com/prod/editPosts', { posts: [{ uuid, post: value }] })
+  // The version makes the save fail if someone else changed the post first.
+  // A thread is shown with a blank line between tweets and saved as its segments
+  // No status goes with the text, so the server sends the edited post back to
+  // draft and it's only published once someone approves the new text
+  const handleSave = (uuid, value, version, isThread) => {
+    const edit = isThread
+      ? { uuid, segments: value.split(/\n\s*\n/).map(s => s.trim()).filter(s => s), version }
+      : { uuid, post: value, version };
+    axios.post('https://4zkgjocsu8.execute-api.us-east-1.amazonaws.com/prod/editPosts', { posts: [edit] })
       .then(response => {
         console.log(`Post ${uuid} saved`);
+        const [saved] = response.data.updated;
+        setPosts(posts.map(post => post.uuid === uuid ? { ...post, version: saved.version, status: saved.status } : post));
       })
       .catch(error => {
-        console.error(`Error saving post ${uuid}`, error);
+        if (error.response && error.response.status === 409) {
+          alert('This post was changed since you loaded it, reloading the latest version.');
+          fetchPosts();
+        } else if (error.response && error.response.status === 404) {
+          alert('This post was already published or deleted.');
+          fetchPosts();
+        } else {
+          console.error(`Error saving post ${uuid}`, error);
+        }
+      });
+  };
+
+  // action is either approvePosts or rejectPosts. The version makes the review
+  // fail if the post was edited after it was loaded, so unseen text isn't approved
+  const handleReview = (uuid, action, status, version) => {
+    axios.post(`https://4zkgjocsu8.execute-api.us-east-1.amazonaws.com/prod/${action}`, { posts: [{ uuid, version }] })
+      .then(response => {
+        console.log(`Post ${uuid} ${status}`);
+        const newVersion = response.data.versions[uuid];
+        setPosts(posts.map(post => post.uuid === uuid ? { ...post, status, version: newVersion } : post));
+      })
+      .catch(error => {
+        if (error.response && error.response.status === 409) {
+          alert('This post was changed since you loaded it, reloading it so you can review the latest text.');
+          fetchPosts();
+        } else if (error.response && error.response.status === 404) {
+          alert('This post was already published or deleted.');
+          fetchPosts();
+        } else {
+          console.error(`Error reviewing post ${uuid}`, error);
+        }
       });
   };
 
@@ -90,7 +138,10 @@ const EditablePosts = () => {
             }}
             className="post-textarea"
           />
-          <button onClick={() => handleSave(post.uuid, post.post)}>Save</button>
+          <span className="post-status">{post.status || 'draft'}</span>
+          <button onClick={() => handleSave(post.uuid, post.post, post.version, Array.isArray(post.segments))}>Save</button>
+          <button onClick={() => handleReview(post.uuid, 'approvePosts', 'approved', post.version)}>Approve</button>
+          <button onClick={() => handleReview(post.uuid, 'rejectPosts', 'rejected', post.version)}>Reject</button>
         </div>
       ))}
       <button onClick={handleGenerate}>Generate New Posts</button>
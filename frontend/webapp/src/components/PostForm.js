@@ -14,6 +14,10 @@ const PostForm = () => {
       })
       .catch(error => {
         console.error('Error adding post', error);
+        // Rejected input comes back as {code, message, field}
+        if (error.response && error.response.data && error.response.data.message) {
+          alert(error.response.data.message);
+        }
       });
   };
 
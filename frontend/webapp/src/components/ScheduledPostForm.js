@@ -18,6 +18,10 @@ const ScheduledPostForm = () => {
       })
       .catch(error => {
         console.error('Error adding scheduled post', error);
+        // Rejected input comes back as {code, message, field}
+        if (error.response && error.response.data && error.response.data.message) {
+          alert(error.response.data.message);
+        }
       });
   };
 
@@ -1,10 +1,43 @@
 import React from 'react';
 import ReactDOM from 'react-dom/client';
+import axios from 'axios';
 import App from './App';
+import { accessToken, completeSignIn, signIn, signOut } from './auth';
+
+// Every API call carries the signed in user's token, the API reads their scopes from it
+axios.interceptors.request.use((config) => {
+  const token = accessToken();
+  if (token) {
+    config.headers.Authorization = `Bearer ${token}`;
+  }
+  return config;
+});
+
+// An expired or revoked token means signing in again
+axios.interceptors.response.use(undefined, (error) => {
+  if (error.response && error.response.status === 401) {
+    signOut();
+    signIn();
+  }
+  return Promise.reject(error);
+});
 
 const root = ReactDOM.createRoot(document.getElementById('root'));
-root.render(
-  <React.StrictMode>
-    <App />
-  </React.StrictMode>
-);
+
+completeSignIn()
+  .then(() => {
+    if (!accessToken()) {
+      signIn();
+      return;
+    }
+    root.render(
+      <React.StrictMode>
+        <App />
+      </React.StrictMode>
+    );
+  })
+  .catch((error) => {
+    // Redirecting again could loop, so say what went wrong instead
+    console.error('Error signing in', error);
+    root.render(<p>Couldn't sign in: {error.message}. Reload the page to try again.</p>);
+  });